@@ -0,0 +1,362 @@
+use crate::{
+    dotfile::{lift_io_err, symlink_atomic, write_atomic, ApplyError, ExistingFile, Ownership},
+    state::state_dir,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    cell::RefCell,
+    collections::HashSet,
+    fs, io,
+    os::unix::fs::{DirBuilderExt, PermissionsExt},
+    path::{Path, PathBuf},
+    rc::Rc,
+    time::{SystemTime, UNIX_EPOCH},
+};
+use thiserror::Error;
+
+const MANIFEST: &str = "manifest.json";
+
+/// Keeps the previous content of every file `apply_dotfiles` overwrites, grouped in one directory
+/// per run: `<root>/<run id>/manifest.json` lists the paths, and `<root>/<run id>/files/<n>` holds
+/// their bytes.
+#[derive(Debug, Clone)]
+pub struct BackupStore {
+    root: PathBuf,
+}
+
+/// The backups of a single run. Its directory is only created once the first file is saved. Clones
+/// share the entries saved so far.
+#[derive(Debug, Clone)]
+pub struct BackupRun {
+    id: String,
+    dir: PathBuf,
+    manifest: Rc<RefCell<Manifest>>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+struct Manifest {
+    entries: Vec<BackupEntry>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BackupEntry {
+    pub path: PathBuf,
+    /// `None` if the file didn't exist before the run, so restoring it means removing it.
+    pub mode: Option<u32>,
+    #[serde(default)]
+    pub uid: Option<u32>,
+    #[serde(default)]
+    pub gid: Option<u32>,
+    /// Set if the path was a symlink, pointing here.
+    #[serde(default)]
+    pub symlink: Option<PathBuf>,
+    blob: Option<String>,
+}
+
+#[derive(Debug, Error)]
+pub enum RestoreError {
+    #[error("no backup run found")]
+    NoRuns,
+    #[error("backup run {0:?} not found")]
+    RunNotFound(String),
+    #[error("backup run {0:?} has saved files but no manifest")]
+    MissingManifest(String),
+    #[error("{path:?} is not in backup run {run:?}")]
+    PathNotInRun { run: String, path: PathBuf },
+    #[error("failed to read backups: {0}")]
+    IO(#[from] io::Error),
+    #[error("corrupted backup manifest: {0}")]
+    Manifest(#[from] serde_json::Error),
+    #[error("failed to restore: {0}")]
+    Apply(#[from] ApplyError),
+}
+
+impl BackupStore {
+    pub fn new(root: PathBuf) -> Self {
+        Self { root }
+    }
+
+    pub fn in_home(home_dir: &Path) -> Self {
+        Self::new(state_dir(home_dir).join("backups"))
+    }
+
+    /// Starts a new run, identified by the current Unix time in seconds, and a `-<n>` suffix if
+    /// another run started within the same second.
+    pub fn begin_run(&self) -> BackupRun {
+        let secs = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default();
+        let mut id = secs.to_string();
+        let mut n = 1;
+        while self.root.join(&id).exists() {
+            id = format!("{}-{}", secs, n);
+            n += 1;
+        }
+        BackupRun {
+            dir: self.root.join(&id),
+            id,
+            manifest: Rc::default(),
+        }
+    }
+
+    /// Ids of all runs, oldest first.
+    pub fn runs(&self) -> io::Result<Vec<String>> {
+        let mut runs = Vec::new();
+        match fs::read_dir(&self.root) {
+            Ok(entries) => {
+                for entry in entries {
+                    let entry = entry?;
+                    if entry.file_type()?.is_dir() {
+                        runs.push(entry.file_name().to_string_lossy().into_owned());
+                    }
+                }
+            }
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+            Err(err) => return Err(err),
+        }
+        runs.sort_by_cached_key(|id| run_order(id));
+        Ok(runs)
+    }
+
+    pub fn run(&self, id: &str) -> Result<BackupRun, RestoreError> {
+        let dir = self.root.join(id);
+        if id.contains('/') || !dir.is_dir() {
+            return Err(RestoreError::RunNotFound(id.to_string()));
+        }
+        let manifest = match fs::read(dir.join(MANIFEST)) {
+            Ok(bytes) => serde_json::from_slice(&bytes)?,
+            // the manifest is written with the first file, so files without it are a broken run
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                if fs::read_dir(dir.join("files")).is_ok_and(|mut files| files.next().is_some()) {
+                    return Err(RestoreError::MissingManifest(id.to_string()));
+                }
+                Manifest::default()
+            }
+            Err(err) => return Err(err.into()),
+        };
+        Ok(BackupRun {
+            id: id.to_string(),
+            dir,
+            manifest: Rc::new(RefCell::new(manifest)),
+        })
+    }
+
+    pub fn latest_run(&self) -> Result<BackupRun, RestoreError> {
+        match self.runs()?.last() {
+            Some(id) => self.run(id),
+            None => Err(RestoreError::NoRuns),
+        }
+    }
+
+    /// Retention policy: deletes all but the newest `keep` runs, returning the ids removed. Runs
+    /// in `protected` are never deleted.
+    pub fn prune(&self, keep: usize, protected: &HashSet<String>) -> io::Result<Vec<String>> {
+        let runs = self.runs()?;
+        let remove = runs.len().saturating_sub(keep);
+        let removed: Vec<String> = runs
+            .into_iter()
+            .take(remove)
+            .filter(|id| !protected.contains(id))
+            .collect();
+        for id in removed.iter() {
+            fs::remove_dir_all(self.root.join(id))?;
+        }
+        Ok(removed)
+    }
+}
+
+impl BackupRun {
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    pub fn entries(&self) -> Vec<BackupEntry> {
+        self.manifest.borrow().entries.clone()
+    }
+
+    /// Records what `path` looked like before it gets changed. Only the first call for a path
+    /// within a run is kept, so the backup always holds the state from before the run. The
+    /// manifest is rewritten before this returns, so that a run cut short can still be restored.
+    pub(crate) fn save(
+        &self,
+        path: &Path,
+        previous: Option<&ExistingFile>,
+    ) -> Result<(), ApplyError> {
+        let lift_err = |operation: &str| lift_io_err(&self.dir, operation);
+        let mut manifest = self.manifest.borrow_mut();
+        if manifest.entries.iter().any(|entry| entry.path == path) {
+            return Ok(());
+        }
+        let files_dir = self.dir.join("files");
+        fs::DirBuilder::new()
+            .recursive(true)
+            .mode(0o700)
+            .create(&files_dir)
+            .map_err(lift_err("fs::create_dir_all"))?;
+        let blob = match previous {
+            Some(previous) if previous.link_target.is_none() => {
+                let blob = manifest.entries.len().to_string();
+                write_atomic(
+                    &files_dir.join(&blob),
+                    &previous.content,
+                    fs::Permissions::from_mode(0o600),
+                    Ownership::default(),
+                )?;
+                Some(blob)
+            }
+            _ => None,
+        };
+        manifest.entries.push(BackupEntry {
+            path: path.to_path_buf(),
+            mode: previous.map(|previous| previous.mode),
+            uid: previous.map(|previous| previous.uid),
+            gid: previous.map(|previous| previous.gid),
+            symlink: previous.and_then(|previous| previous.link_target.clone()),
+            blob,
+        });
+        if let Err(err) = self.write_manifest(&manifest) {
+            manifest.entries.pop();
+            return Err(err);
+        }
+        Ok(())
+    }
+
+    fn write_manifest(&self, manifest: &Manifest) -> Result<(), ApplyError> {
+        let manifest =
+            serde_json::to_vec_pretty(manifest).map_err(|err| ApplyError::Other(Box::new(err)))?;
+        write_atomic(
+            &self.dir.join(MANIFEST),
+            &manifest,
+            fs::Permissions::from_mode(0o600),
+            Ownership::default(),
+        )
+    }
+
+    /// Puts `entry` back the way it was before the run.
+    pub fn restore(&self, entry: &BackupEntry) -> Result<(), RestoreError> {
+        if entry.mode.is_some() {
+            if let Some(parent) = entry.path.parent() {
+                fs::create_dir_all(parent)?;
+            }
+        }
+        match (&entry.symlink, &entry.blob, entry.mode) {
+            (Some(target), _, _) => symlink_atomic(&entry.path, target)?,
+            (None, Some(blob), Some(mode)) => {
+                let content = fs::read(self.dir.join("files").join(blob))?;
+                let ownership = Ownership {
+                    uid: entry.uid,
+                    gid: entry.gid,
+                };
+                write_atomic(
+                    &entry.path,
+                    &content,
+                    fs::Permissions::from_mode(mode),
+                    ownership,
+                )?;
+            }
+            _ => match fs::remove_file(&entry.path) {
+                Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err.into()),
+                _ => {}
+            },
+        }
+        Ok(())
+    }
+}
+
+/// Runs sort by their start time, then by their suffix. Ids of another form, like the timestamps
+/// of older versions, go first.
+fn run_order(id: &str) -> (Option<u64>, u64, String) {
+    let (secs, n) = id.split_once('-').unwrap_or((id, "0"));
+    (
+        secs.parse().ok(),
+        n.parse().unwrap_or_default(),
+        id.to_string(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::MetadataExt;
+
+    #[test]
+    fn runs_sort_numerically() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = BackupStore::new(dir.path().to_path_buf());
+        for id in [
+            "1700000000-10",
+            "999999999",
+            "1700000000",
+            "20231114T221320Z",
+            "1700000000-2",
+        ] {
+            fs::create_dir(dir.path().join(id)).unwrap();
+        }
+        assert_eq!(
+            store.runs().unwrap(),
+            vec![
+                "20231114T221320Z",
+                "999999999",
+                "1700000000",
+                "1700000000-2",
+                "1700000000-10"
+            ]
+        );
+    }
+
+    #[test]
+    fn files_without_manifest() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = BackupStore::new(dir.path().to_path_buf());
+        fs::create_dir_all(dir.path().join("1/files")).unwrap();
+        fs::write(dir.path().join("1/files/0"), "by hand").unwrap();
+        assert!(matches!(
+            store.latest_run(),
+            Err(RestoreError::MissingManifest(_))
+        ));
+    }
+
+    #[test]
+    fn save_restore_and_prune() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = BackupStore::new(dir.path().join("backups"));
+        let edited = dir.path().join("edited");
+        let created = dir.path().join("created");
+        fs::write(&edited, "by hand").unwrap();
+        fs::set_permissions(&edited, fs::Permissions::from_mode(0o640)).unwrap();
+
+        let run = store.begin_run();
+        let previous = ExistingFile {
+            content: b"by hand".to_vec(),
+            mode: 0o640,
+            uid: fs::metadata(&edited).unwrap().uid(),
+            gid: fs::metadata(&edited).unwrap().gid(),
+            link_target: None,
+        };
+        run.save(&edited, Some(&previous)).unwrap();
+        run.save(&created, None).unwrap();
+        fs::write(&edited, "managed").unwrap();
+        fs::write(&created, "managed").unwrap();
+
+        let run = store.latest_run().unwrap();
+        for entry in run.entries() {
+            run.restore(&entry).unwrap();
+        }
+        assert_eq!(fs::read_to_string(&edited).unwrap(), "by hand");
+        assert_eq!(
+            fs::metadata(&edited).unwrap().permissions().mode() & 0o777,
+            0o640
+        );
+        assert!(!created.exists());
+
+        fs::create_dir_all(dir.path().join("backups/0")).unwrap();
+        fs::create_dir_all(dir.path().join("backups/1")).unwrap();
+        let protected = HashSet::from(["1".to_string()]);
+        assert_eq!(store.prune(1, &protected).unwrap(), vec!["0"]);
+        assert_eq!(
+            store.runs().unwrap(),
+            vec!["1".to_string(), run.id().to_string()]
+        );
+    }
+}
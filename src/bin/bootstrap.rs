@@ -1,17 +1,68 @@
-use clap::{builder::ValueParser, Parser};
-use dotfiles::{bootstrap, Config, Region};
+use clap::{builder::ValueParser, Parser, Subcommand};
+use dotfiles::{
+    bootstrap, flush_uploads, plan, post_build_hook, restore, status, store_url::StoreUrl,
+    upload_queue::DEFAULT_SPOOL_DIR, BootstrapOptions, Config, Region,
+};
+use std::path::PathBuf;
 use thiserror::Error;
 
 /// Bootstrap my dotfiles
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
+#[command(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
 struct Args {
+    #[command(subcommand)]
+    command: Option<Command>,
     /// The list of targets to bootstrap. If not specified, will bootstrap everything.
     targets: Vec<dotfiles::Target>,
-    #[arg(long, value_parser=region_parser())]
-    region: Region,
+    #[arg(long, value_parser=region_parser(), required = true)]
+    region: Option<Region>,
+    #[arg(long, required = true)]
+    ssh_private_key: Option<String>,
+    /// Show a diff of every file that would change, without writing anything.
     #[arg(long)]
-    ssh_private_key: String,
+    dry_run: bool,
+    /// If any file fails to apply, restore every file that was already replaced.
+    #[arg(long)]
+    transactional: bool,
+    /// How many runs of backups of overwritten files to keep.
+    #[arg(long, default_value_t = 10)]
+    keep_backups: usize,
+    /// Clean up files that a previous run wrote but no target produces anymore.
+    #[arg(long)]
+    prune: bool,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Restore files overwritten by a previous bootstrap from their backups
+    Restore {
+        /// The backup run to restore from. Defaults to the latest one.
+        #[arg(long)]
+        run: Option<String>,
+        /// Only restore these paths. If not specified, will restore every file in the run.
+        paths: Vec<PathBuf>,
+    },
+    /// Report which managed files drifted since the last bootstrap
+    Status,
+    /// Queue the store paths in OUT_PATHS for upload. Called by the Nix post-build hook.
+    PostBuildHook {
+        #[arg(long, default_value = DEFAULT_SPOOL_DIR)]
+        spool_dir: PathBuf,
+    },
+    /// Sign and upload the store paths queued by post-build-hook
+    FlushUploads {
+        #[arg(long, default_value = DEFAULT_SPOOL_DIR)]
+        spool_dir: PathBuf,
+        /// The binary cache to copy to.
+        #[arg(long)]
+        to: StoreUrl,
+        #[arg(long, default_value = "/etc/nix/secret-key")]
+        key_file: PathBuf,
+        /// How many times to try again if the upload fails.
+        #[arg(long, default_value_t = 3)]
+        retries: usize,
+    },
 }
 
 fn region_parser() -> ValueParser {
@@ -34,18 +85,39 @@ fn region_parser() -> ValueParser {
 struct RegionParserError(String);
 
 fn main() {
-    let args = Args::parse();
-    match Config::new(args.region, args.ssh_private_key) {
-        Ok(config) => match bootstrap(config, expand_default_targets(args.targets)) {
-            Ok(()) => {}
-            Err(e) => {
-                eprintln!("{}", e);
-                std::process::exit(1);
+    if let Err(e) = run(Args::parse()) {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
+}
+
+fn run(args: Args) -> anyhow::Result<()> {
+    match args.command {
+        Some(Command::Restore { run, paths }) => restore(run, paths),
+        Some(Command::Status) => status(),
+        Some(Command::PostBuildHook { spool_dir }) => post_build_hook(spool_dir),
+        Some(Command::FlushUploads {
+            spool_dir,
+            to,
+            key_file,
+            retries,
+        }) => flush_uploads(spool_dir, key_file, to, retries),
+        None => {
+            let targets = expand_default_targets(args.targets);
+            // clap enforces both when there is no subcommand
+            let config = Config::new(
+                args.region.expect("--region is required"),
+                args.ssh_private_key.expect("--ssh-private-key is required"),
+            )?;
+            if args.dry_run {
+                plan(config, targets)
+            } else {
+                let options = BootstrapOptions::new()
+                    .with_transaction(args.transactional)
+                    .with_keep_backups(args.keep_backups)
+                    .with_prune(args.prune);
+                bootstrap(config, targets, &options)
             }
-        },
-        Err(e) => {
-            eprintln!("{}", e);
-            std::process::exit(1);
         }
     }
 }
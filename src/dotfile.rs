@@ -2,19 +2,132 @@ use std::{
     any::Any,
     collections::{HashMap, HashSet},
     error::Error,
-    fs, io,
+    fmt, fs,
+    io::{self, Write},
+    os::unix::fs::{DirBuilderExt, MetadataExt, OpenOptionsExt, PermissionsExt},
     path::{Path, PathBuf},
 };
 use thiserror::Error;
 
+use crate::backup::BackupRun;
+use parts::Parts;
+use symlink::ExistingFilePolicy;
+
+pub mod directory_tree;
+pub mod ini;
+pub mod json;
+pub mod key_set;
+pub mod key_value;
+pub mod line_set;
+pub mod managed_block;
 pub mod nix_conf;
+pub mod parts;
+pub mod ssh_config;
+pub mod structured;
+pub mod symlink;
+pub mod toml;
 pub mod unstructured;
+pub mod yaml;
+
+/// Dotfiles by the path they are applied to.
+pub type Dotfiles = HashMap<PathBuf, Box<dyn Dotfile>>;
 
 pub trait Dotfile: Any {
     fn apply(&self, old_content: &[u8]) -> Result<Option<Vec<u8>>, ApplyError>;
     fn file_permission(&self) -> fs::Permissions;
+    fn ownership(&self) -> Ownership {
+        Ownership::default()
+    }
     fn merge(&mut self, y: Box<dyn Dotfile>) -> Result<(), MergeError>;
     fn as_any(self: Box<Self>) -> Box<dyn Any>;
+
+    /// Whether the content contains secrets (e.g. anything from a `Decrpytor`) that must never be
+    /// printed, such as in the diff of a plan.
+    fn is_secret(&self) -> bool {
+        false
+    }
+
+    fn layout(&self) -> Layout<'_> {
+        Layout::File
+    }
+
+    /// The mode the directory containing the file must have, like 0700 for `~/.ssh`. `None`
+    /// leaves an existing directory alone.
+    fn directory_permission(&self) -> Option<fs::Permissions> {
+        None
+    }
+
+    /// Dotfiles that stand for several files (like a `DirectoryTree` at `path`) return those
+    /// files here. They are expanded after merging and before anything is planned or applied.
+    fn expand(&self, _path: &Path) -> Result<Option<Dotfiles>, ApplyError> {
+        Ok(None)
+    }
+
+    /// What this dotfile puts in a file it shares with others, like the settings it adds to
+    /// nix.conf, so that pruning it takes out only those. `None` if it owns the whole file.
+    fn parts(&self) -> Option<Parts> {
+        None
+    }
+}
+
+/// What a dotfile puts at its path.
+pub enum Layout<'a> {
+    /// A regular file with the content from `Dotfile::apply`. If the path is a symlink, the file
+    /// it points to is written.
+    File,
+    Symlink {
+        target: &'a Path,
+        existing_file: symlink::ExistingFilePolicy,
+    },
+    /// Nothing; an existing file or symlink is removed.
+    Absent,
+}
+
+/// Desired owner and group of a file. `None` leaves the existing one alone, or whatever a newly
+/// created file gets from the running process.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Ownership {
+    pub uid: Option<u32>,
+    pub gid: Option<u32>,
+}
+
+impl Ownership {
+    pub fn new(uid: u32, gid: u32) -> Self {
+        Self {
+            uid: Some(uid),
+            gid: Some(gid),
+        }
+    }
+
+    pub fn root() -> Self {
+        Self::new(0, 0)
+    }
+
+    /// Owner and group of an existing path.
+    pub fn of(path: &Path) -> io::Result<Self> {
+        let metadata = fs::metadata(path)?;
+        Ok(Self::new(metadata.uid(), metadata.gid()))
+    }
+
+    /// Fills in what isn't set here from `other`.
+    pub fn or(self, other: Ownership) -> Self {
+        Self {
+            uid: self.uid.or(other.uid),
+            gid: self.gid.or(other.gid),
+        }
+    }
+
+    /// Whether a file owned by `uid`:`gid` needs to be chowned.
+    fn differs_from(&self, uid: u32, gid: u32) -> bool {
+        self.uid.is_some_and(|x| x != uid) || self.gid.is_some_and(|x| x != gid)
+    }
+}
+
+impl fmt::Display for Ownership {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let show = |id: Option<u32>| id.map_or("-".to_string(), |id| id.to_string());
+        write!(f, "{}:{}", show(self.uid), show(self.gid))
+    }
 }
 
 #[derive(Debug, PartialEq, Eq, Error)]
@@ -32,6 +145,10 @@ pub enum ApplyError {
     },
     #[error("other error: {0}")]
     Other(Box<dyn Error + Send + Sync>),
+    #[error("refusing to write a secret readable by group or others (mode {mode:o})")]
+    InsecureSecretPermissions { mode: u32 },
+    #[error("refusing to replace the {kind} at {path:?} with a symlink")]
+    PathOccupied { path: PathBuf, kind: &'static str },
     #[error("path: {path:?}, operation: {operation:?}, io error: {err:?}")]
     IO {
         path: PathBuf,
@@ -40,7 +157,7 @@ pub enum ApplyError {
     },
 }
 
-fn apply_utf8<D: Dotfile, F>(old_content: &[u8], consume: F) -> Result<Option<Vec<u8>>, ApplyError>
+fn apply_utf8<F>(old_content: &[u8], consume: F) -> Result<Option<Vec<u8>>, ApplyError>
 where
     F: FnOnce(String) -> Result<Option<String>, Box<dyn Error + Send + Sync>>,
 {
@@ -49,7 +166,7 @@ where
             expected_encoding: "utf8",
             message: err.to_string(),
         })?;
-    let new_content = consume(old_content).map_err(|err| ApplyError::Other(err))?;
+    let new_content = consume(old_content).map_err(ApplyError::Other)?;
     Ok(new_content.map(|new_content| new_content.into_bytes()))
 }
 
@@ -73,6 +190,18 @@ where
     }
 }
 
+/// Two dotfiles for the same path agree on its owner if they don't set different ones.
+fn merge_ownership(x: Ownership, y: Ownership) -> Result<Ownership, MergeError> {
+    let conflict = |x: Option<u32>, y: Option<u32>| matches!((x, y), (Some(x), Some(y)) if x != y);
+    if conflict(x.uid, y.uid) || conflict(x.gid, y.gid) {
+        Err(MergeError::MergeConflict {
+            reason: format!("conflicting ownership {:?} and {:?}", x, y),
+        })
+    } else {
+        Ok(x.or(y))
+    }
+}
+
 pub fn merge_dotfiles(
     dotfiles: Vec<HashMap<PathBuf, Box<dyn Dotfile>>>,
 ) -> Result<HashMap<PathBuf, Box<dyn Dotfile>>, MergeError> {
@@ -93,50 +222,846 @@ pub fn merge_dotfiles(
     Ok(result)
 }
 
+#[derive(Debug, Error)]
+pub enum ExpandError {
+    #[error("failed to expand {path:?}: {err}")]
+    Apply { path: PathBuf, err: ApplyError },
+    #[error(transparent)]
+    Merge(#[from] MergeError),
+}
+
+/// Replaces every dotfile that stands for several files with those files, merging them with
+/// dotfiles already at the same paths. A file that is only there to be removed gives way to one
+/// that is produced. Also returns the paths each expanded file was expanded from.
+pub fn expand_dotfiles(
+    dotfiles: HashMap<PathBuf, Box<dyn Dotfile>>,
+) -> Result<(Dotfiles, HashMap<PathBuf, Vec<PathBuf>>), ExpandError> {
+    let mut result = HashMap::new();
+    let mut expanded = Vec::new();
+    let mut expanded_from = HashMap::<PathBuf, Vec<PathBuf>>::new();
+    for (path, dotfile) in dotfiles {
+        match dotfile.expand(&path) {
+            Ok(Some(children)) => {
+                for child in children.keys() {
+                    expanded_from
+                        .entry(child.clone())
+                        .or_default()
+                        .push(path.clone());
+                }
+                expanded.push(children);
+            }
+            Ok(None) => {
+                result.insert(path, dotfile);
+            }
+            Err(err) => return Err(ExpandError::Apply { path, err }),
+        }
+    }
+    for (path, dotfile) in expanded.into_iter().flatten() {
+        let is_absent = |dotfile: &dyn Dotfile| matches!(dotfile.layout(), Layout::Absent);
+        match result.remove(&path) {
+            None => {
+                result.insert(path, dotfile);
+            }
+            Some(existing) if is_absent(existing.as_ref()) => {
+                result.insert(path, dotfile);
+            }
+            Some(existing) if is_absent(dotfile.as_ref()) => {
+                result.insert(path, existing);
+            }
+            Some(mut existing) => {
+                existing.merge(dotfile)?;
+                result.insert(path, existing);
+            }
+        }
+    }
+    Ok((result, expanded_from))
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct ApplyOptions {
+    transactional: bool,
+    backup: Option<BackupRun>,
+}
+
+impl ApplyOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Stop at the first failing file and put back every file that was already replaced.
+    pub fn with_transaction(mut self, transactional: bool) -> Self {
+        self.transactional = transactional;
+        self
+    }
+
+    /// Save the previous content of every file before it's changed.
+    pub fn with_backup(mut self, backup: BackupRun) -> Self {
+        self.backup = Some(backup);
+        self
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct ApplyReport {
+    pub changed_files: HashSet<PathBuf>,
+    /// Files whose content was already right, but whose mode had drifted and was fixed.
+    pub permissions_fixed: HashSet<PathBuf>,
+    /// Files whose owner or group changed, with or without their content.
+    pub ownership_changed: HashSet<PathBuf>,
+    pub errors: HashMap<PathBuf, ApplyError>,
+    /// Files that were replaced and then restored because a later file failed.
+    pub rolled_back: Vec<PathBuf>,
+    /// Files that now hold what their dotfile produces, whether they were changed or already
+    /// right. Files that failed, were rolled back or never got their turn aren't in it.
+    pub applied: HashSet<PathBuf>,
+}
+
 pub fn apply_dotfiles(
     dotfiles: HashMap<PathBuf, Box<dyn Dotfile>>,
-) -> (HashSet<PathBuf>, HashMap<PathBuf, ApplyError>) {
-    let mut changed_files = HashSet::<PathBuf>::new();
-    let mut errors = HashMap::<PathBuf, ApplyError>::new();
+    options: &ApplyOptions,
+) -> ApplyReport {
+    let mut report = ApplyReport::default();
+    let mut replaced = Vec::<(PathBuf, PathBuf, Previous, Option<PreviousDirectory>)>::new();
+    let mut dotfiles: Vec<(PathBuf, Box<dyn Dotfile>)> = dotfiles.into_iter().collect();
+    dotfiles.sort_by(|x, y| x.0.cmp(&y.0));
     for (path, dotfile) in dotfiles {
-        match apply_dotfile(path.as_path(), dotfile) {
-            Ok(changed) => {
-                if changed {
-                    changed_files.insert(path);
+        let outcome = apply_dotfile(path.as_path(), dotfile, options);
+        if outcome.is_ok() {
+            report.applied.insert(path.clone());
+        }
+        match outcome {
+            Ok(Outcome::Unchanged) => {}
+            Ok(Outcome::Replaced {
+                written,
+                previous,
+                ownership_changed,
+                directory,
+            }) => {
+                report.changed_files.insert(path.clone());
+                if ownership_changed {
+                    report.ownership_changed.insert(path.clone());
+                }
+                if options.transactional {
+                    replaced.push((path, written, previous, directory));
+                }
+            }
+            Ok(Outcome::MetadataFixed {
+                written,
+                previous,
+                mode_fixed,
+                ownership_fixed,
+                directory,
+            }) => {
+                if mode_fixed {
+                    report.permissions_fixed.insert(path.clone());
+                }
+                if ownership_fixed {
+                    report.ownership_changed.insert(path.clone());
+                }
+                if options.transactional {
+                    replaced.push((path, written, Previous::Existing(previous), directory));
                 }
             }
             Err(err) => {
-                errors.insert(path, err);
+                report.errors.insert(path, err);
+                if options.transactional {
+                    break;
+                }
+            }
+        }
+    }
+    if options.transactional && !report.errors.is_empty() {
+        for (path, written, previous, directory) in replaced.into_iter().rev() {
+            let result = rollback(&written, previous).and_then(|()| match directory {
+                Some(directory) => directory.restore(),
+                None => Ok(()),
+            });
+            match result {
+                Ok(()) => {
+                    report.applied.remove(&path);
+                    report.changed_files.remove(&path);
+                    report.permissions_fixed.remove(&path);
+                    report.ownership_changed.remove(&path);
+                    report.rolled_back.push(path);
+                }
+                Err(err) => {
+                    report.errors.insert(path, err);
+                }
             }
         }
     }
-    (changed_files, errors)
+    report
+}
+
+/// What was at a path before `apply_dotfile` changed it.
+enum Previous {
+    Absent,
+    Existing(ExistingFile),
+    /// Moved aside to make room for a symlink.
+    MovedAside(PathBuf),
 }
 
-fn apply_dotfile(path: &Path, dotfile: Box<dyn Dotfile>) -> Result<bool, ApplyError> {
-    let lift_err = |operation: &str| {
-        let operation = operation.to_string();
-        |err: io::Error| ApplyError::IO {
-            path: path.to_path_buf(),
-            operation,
-            err,
+/// The mode of the directory containing a file before `apply_dotfile` changed it.
+struct PreviousDirectory {
+    path: PathBuf,
+    mode: u32,
+}
+
+impl PreviousDirectory {
+    fn restore(&self) -> Result<(), ApplyError> {
+        fs::set_permissions(&self.path, fs::Permissions::from_mode(self.mode))
+            .map_err(lift_io_err(&self.path, "fs::set_permissions"))
+    }
+}
+
+/// Puts back the directory mode after the file in it couldn't be changed, and passes on why.
+fn undo_directory(directory: Option<PreviousDirectory>, err: ApplyError) -> ApplyError {
+    if let Some(directory) = directory {
+        let _ = directory.restore();
+    }
+    err
+}
+
+fn rollback(path: &Path, previous: Previous) -> Result<(), ApplyError> {
+    match previous {
+        Previous::Existing(previous) => write_previous(path, &previous),
+        Previous::Absent => fs::remove_file(path).map_err(lift_io_err(path, "fs::remove_file")),
+        Previous::MovedAside(aside) => {
+            fs::rename(&aside, path).map_err(lift_io_err(path, "fs::rename"))
         }
+    }
+}
+
+/// Puts back what `read_existing` or `read_existing_nofollow` found at `path`.
+pub(crate) fn write_previous(path: &Path, previous: &ExistingFile) -> Result<(), ApplyError> {
+    match &previous.link_target {
+        Some(target) => symlink_atomic(path, target),
+        None => write_atomic(
+            path,
+            &previous.content,
+            fs::Permissions::from_mode(previous.mode),
+            previous.ownership(),
+        ),
+    }
+}
+
+/// Content and metadata of a file as it currently is on disk.
+pub(crate) struct ExistingFile {
+    pub(crate) content: Vec<u8>,
+    pub(crate) mode: u32,
+    pub(crate) uid: u32,
+    pub(crate) gid: u32,
+    /// Set if the path itself is a symlink, as seen by `read_existing_nofollow`.
+    pub(crate) link_target: Option<PathBuf>,
+}
+
+impl ExistingFile {
+    pub(crate) fn ownership(&self) -> Ownership {
+        Ownership::new(self.uid, self.gid)
+    }
+}
+
+pub(crate) fn lift_io_err(path: &Path, operation: &str) -> impl FnOnce(io::Error) -> ApplyError {
+    let path = path.to_path_buf();
+    let operation = operation.to_string();
+    |err: io::Error| ApplyError::IO {
+        path,
+        operation,
+        err,
+    }
+}
+
+pub(crate) fn read_existing(path: &Path) -> Result<Option<ExistingFile>, ApplyError> {
+    let metadata = match fs::metadata(path) {
+        Ok(metadata) => metadata,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(lift_io_err(path, "fs::metadata")(err)),
     };
-    let existing_content = if path.exists() {
-        std::fs::read(path).map_err(lift_err("fs::read"))?
-    } else {
-        vec![]
+    let content = fs::read(path).map_err(lift_io_err(path, "fs::read"))?;
+    Ok(Some(ExistingFile {
+        content,
+        mode: metadata.permissions().mode() & 0o7777,
+        uid: metadata.uid(),
+        gid: metadata.gid(),
+        link_target: None,
+    }))
+}
+
+/// Like `read_existing`, but describes a symlink itself rather than the file it points to.
+pub(crate) fn read_existing_nofollow(path: &Path) -> Result<Option<ExistingFile>, ApplyError> {
+    let metadata = match fs::symlink_metadata(path) {
+        Ok(metadata) => metadata,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(lift_io_err(path, "fs::symlink_metadata")(err)),
     };
-    let new_content = dotfile.apply(&existing_content)?;
+    if metadata.is_symlink() {
+        let target = fs::read_link(path).map_err(lift_io_err(path, "fs::read_link"))?;
+        Ok(Some(ExistingFile {
+            content: Vec::new(),
+            mode: 0o777,
+            uid: metadata.uid(),
+            gid: metadata.gid(),
+            link_target: Some(target),
+        }))
+    } else {
+        read_existing(path)
+    }
+}
+
+enum Outcome {
+    Unchanged,
+    /// `written` is where the file was written, which is the file a symlink at the path points
+    /// to, so that rolling back goes there too.
+    Replaced {
+        written: PathBuf,
+        previous: Previous,
+        ownership_changed: bool,
+        directory: Option<PreviousDirectory>,
+    },
+    /// The content was already right, but the mode, owner or group had to be changed.
+    MetadataFixed {
+        written: PathBuf,
+        previous: ExistingFile,
+        mode_fixed: bool,
+        ownership_fixed: bool,
+        directory: Option<PreviousDirectory>,
+    },
+}
+
+fn apply_dotfile(
+    path: &Path,
+    dotfile: Box<dyn Dotfile>,
+    options: &ApplyOptions,
+) -> Result<Outcome, ApplyError> {
+    if let Layout::Symlink {
+        target,
+        existing_file,
+    } = dotfile.layout()
+    {
+        return apply_symlink(path, target, existing_file, options);
+    }
+    if let Layout::Absent = dotfile.layout() {
+        return apply_absent(path, options);
+    }
+    // write through an existing symlink instead of replacing it
+    let resolved = resolve_symlinks(path)?;
+    let path = resolved.as_path();
+    let lift_err = |operation: &str| lift_io_err(path, operation);
+    let existing = read_existing(path)?;
+    let new_content = dotfile.apply(
+        existing
+            .as_ref()
+            .map(|existing| existing.content.as_slice())
+            .unwrap_or_default(),
+    )?;
+    check_secret_permissions(dotfile.as_ref())?;
     let file_permissions = dotfile.file_permission();
+    let ownership = dotfile.ownership();
+    let ownership_drifted = existing
+        .as_ref()
+        .is_some_and(|existing| ownership.differs_from(existing.uid, existing.gid));
+    let directory_permission = dotfile.directory_permission();
+    let directory_drifted = directory_drifted(path, dotfile.as_ref())?;
+    // the directory is only changed once the file is backed up, and is put back if the file then
+    // can't be changed
+    let fix_directory = || -> Result<Option<PreviousDirectory>, ApplyError> {
+        let (true, Some(parent), Some(permission)) =
+            (directory_drifted, path.parent(), &directory_permission)
+        else {
+            return Ok(None);
+        };
+        let mode = fs::metadata(parent)
+            .map_err(lift_io_err(parent, "fs::metadata"))?
+            .permissions()
+            .mode()
+            & 0o7777;
+        fs::set_permissions(parent, permission.clone())
+            .map_err(lift_io_err(parent, "fs::set_permissions"))?;
+        Ok(Some(PreviousDirectory {
+            path: parent.to_path_buf(),
+            mode,
+        }))
+    };
     if let Some(new_content) = new_content {
+        if let Some(backup) = &options.backup {
+            backup.save(path, existing.as_ref())?;
+        }
+        let directory = fix_directory()?;
         if let Some(parent) = path.parent() {
-            fs::create_dir_all(parent).map_err(lift_err("fs::create_dir_all"))?;
+            fs::DirBuilder::new()
+                .recursive(true)
+                .mode(match &directory_permission {
+                    Some(permission) => permission.mode(),
+                    None => 0o777,
+                })
+                .create(parent)
+                .map_err(lift_err("fs::create_dir_all"))?;
+        }
+        // a replaced file keeps its owner unless told otherwise, like it would with `fs::write`
+        let ownership = match &existing {
+            Some(existing) => ownership.or(existing.ownership()),
+            None => ownership,
+        };
+        if let Err(err) = write_atomic(path, &new_content, file_permissions, ownership) {
+            return Err(undo_directory(directory, err));
         }
-        fs::write(path, new_content).map_err(lift_err("fs::write"))?;
-        fs::set_permissions(path, file_permissions).map_err(lift_err("fs::set_permissions"))?;
-        Ok(true)
+        Ok(Outcome::Replaced {
+            written: path.to_path_buf(),
+            ownership_changed: ownership_drifted,
+            previous: existing.map_or(Previous::Absent, Previous::Existing),
+            directory,
+        })
+    } else if let Some(existing) = existing {
+        let mode_drifted = existing.mode != file_permissions.mode() & 0o7777;
+        if !mode_drifted && !ownership_drifted && !directory_drifted {
+            return Ok(Outcome::Unchanged);
+        }
+        if let Some(backup) = &options.backup {
+            backup.save(path, Some(&existing))?;
+        }
+        let directory = fix_directory()?;
+        let fix_file = || -> Result<(), ApplyError> {
+            if ownership_drifted {
+                std::os::unix::fs::chown(path, ownership.uid, ownership.gid)
+                    .map_err(lift_err("chown"))?;
+            }
+            // also after a chown, which may clear the setuid and setgid bits
+            fs::set_permissions(path, file_permissions).map_err(lift_err("fs::set_permissions"))
+        };
+        if let Err(err) = fix_file() {
+            return Err(undo_directory(directory, err));
+        }
+        Ok(Outcome::MetadataFixed {
+            written: path.to_path_buf(),
+            previous: existing,
+            mode_fixed: mode_drifted || directory_drifted,
+            ownership_fixed: ownership_drifted,
+            directory,
+        })
     } else {
-        Ok(false)
+        Ok(Outcome::Unchanged)
+    }
+}
+
+/// The file `path` ends up at after following symlinks, even if the last one dangles.
+pub(crate) fn resolve_symlinks(path: &Path) -> Result<PathBuf, ApplyError> {
+    let mut resolved = path.to_path_buf();
+    // as many as Linux follows before giving up with ELOOP
+    for _ in 0..40 {
+        match fs::symlink_metadata(&resolved) {
+            Ok(metadata) if metadata.is_symlink() => {
+                let target =
+                    fs::read_link(&resolved).map_err(lift_io_err(&resolved, "fs::read_link"))?;
+                resolved = match resolved.parent() {
+                    Some(parent) => parent.join(target),
+                    None => target,
+                };
+            }
+            _ => return Ok(resolved),
+        }
+    }
+    Err(lift_io_err(path, "fs::read_link")(io::Error::other(
+        "too many levels of symbolic links",
+    )))
+}
+
+fn apply_symlink(
+    path: &Path,
+    target: &Path,
+    existing_file: ExistingFilePolicy,
+    options: &ApplyOptions,
+) -> Result<Outcome, ApplyError> {
+    let existing = read_existing_nofollow(path).or_else(|err| match err {
+        // a directory in the way can't be read, but it can still be moved aside
+        ApplyError::IO { .. } if path.is_dir() => Ok(None),
+        err => Err(err),
+    })?;
+    let previous = match existing {
+        Some(existing) if existing.link_target.as_deref() == Some(target) => {
+            return Ok(Outcome::Unchanged)
+        }
+        Some(existing) if existing.link_target.is_some() => {
+            if let Some(backup) = &options.backup {
+                backup.save(path, Some(&existing))?;
+            }
+            Previous::Existing(existing)
+        }
+        None if !path.is_dir() => {
+            if let Some(parent) = path.parent() {
+                fs::create_dir_all(parent).map_err(lift_io_err(parent, "fs::create_dir_all"))?;
+            }
+            Previous::Absent
+        }
+        existing => {
+            let kind = if path.is_dir() { "directory" } else { "file" };
+            match (existing_file, existing) {
+                (ExistingFilePolicy::Replace, Some(existing)) => {
+                    if let Some(backup) = &options.backup {
+                        backup.save(path, Some(&existing))?;
+                    }
+                    Previous::Existing(existing)
+                }
+                (ExistingFilePolicy::Backup, _) => {
+                    let aside = aside_path(path);
+                    fs::rename(path, &aside).map_err(lift_io_err(path, "fs::rename"))?;
+                    Previous::MovedAside(aside)
+                }
+                _ => {
+                    return Err(ApplyError::PathOccupied {
+                        path: path.to_path_buf(),
+                        kind,
+                    })
+                }
+            }
+        }
+    };
+    symlink_atomic(path, target)?;
+    Ok(Outcome::Replaced {
+        written: path.to_path_buf(),
+        previous,
+        ownership_changed: false,
+        directory: None,
+    })
+}
+
+fn apply_absent(path: &Path, options: &ApplyOptions) -> Result<Outcome, ApplyError> {
+    let Some(existing) = read_existing_nofollow(path)? else {
+        return Ok(Outcome::Unchanged);
+    };
+    if let Some(backup) = &options.backup {
+        backup.save(path, Some(&existing))?;
+    }
+    fs::remove_file(path).map_err(lift_io_err(path, "fs::remove_file"))?;
+    Ok(Outcome::Replaced {
+        written: path.to_path_buf(),
+        previous: Previous::Existing(existing),
+        ownership_changed: false,
+        directory: None,
+    })
+}
+
+/// A free `<name>.dotfiles-bak` (or `<name>.dotfiles-bak.<n>`) next to `path`.
+fn aside_path(path: &Path) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(".dotfiles-bak");
+    let mut aside = path.with_file_name(&name);
+    let mut n = 1;
+    while fs::symlink_metadata(&aside).is_ok() {
+        let mut numbered = name.clone();
+        numbered.push(format!(".{}", n));
+        aside = path.with_file_name(numbered);
+        n += 1;
+    }
+    aside
+}
+
+/// Points `path` at `target`, replacing whatever file or symlink is there in a single rename.
+pub(crate) fn symlink_atomic(path: &Path, target: &Path) -> Result<(), ApplyError> {
+    let tmp_path = tmp_path_for(path)?;
+    let _ = fs::remove_file(&tmp_path);
+    std::os::unix::fs::symlink(target, &tmp_path)
+        .map_err(lift_io_err(&tmp_path, "create temporary symlink"))?;
+    if let Err(err) = fs::rename(&tmp_path, path) {
+        let _ = fs::remove_file(&tmp_path);
+        return Err(lift_io_err(path, "fs::rename")(err));
+    }
+    Ok(())
+}
+
+/// Whether the existing directory containing `path` doesn't have the mode the dotfile wants.
+pub(crate) fn directory_drifted(path: &Path, dotfile: &dyn Dotfile) -> Result<bool, ApplyError> {
+    let (Some(parent), Some(permission)) = (path.parent(), dotfile.directory_permission()) else {
+        return Ok(false);
+    };
+    match fs::metadata(parent) {
+        Ok(metadata) => Ok(metadata.permissions().mode() & 0o7777 != permission.mode() & 0o7777),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(false),
+        Err(err) => Err(lift_io_err(parent, "fs::metadata")(err)),
+    }
+}
+
+/// Secrets must only ever be readable by their owner.
+pub(crate) fn check_secret_permissions(dotfile: &dyn Dotfile) -> Result<(), ApplyError> {
+    let mode = dotfile.file_permission().mode() & 0o7777;
+    if dotfile.is_secret() && mode & 0o044 != 0 {
+        Err(ApplyError::InsecureSecretPermissions { mode })
+    } else {
+        Ok(())
+    }
+}
+
+fn parent_dir(path: &Path) -> &Path {
+    match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    }
+}
+
+/// A hidden sibling of `path` to prepare its replacement in.
+fn tmp_path_for(path: &Path) -> Result<PathBuf, ApplyError> {
+    let file_name = path.file_name().ok_or_else(|| ApplyError::IO {
+        path: path.to_path_buf(),
+        operation: "write_atomic".to_string(),
+        err: io::Error::new(io::ErrorKind::InvalidInput, "path has no file name"),
+    })?;
+    let mut tmp_name = std::ffi::OsString::from(".");
+    tmp_name.push(file_name);
+    tmp_name.push(format!(".dotfiles-tmp-{}", std::process::id()));
+    Ok(parent_dir(path).join(tmp_name))
+}
+
+/// Replaces `path` so that it is never observed half-written or with the wrong permissions: the
+/// content goes to a temporary file in the same directory, which gets its final mode and is
+/// fsynced before being renamed over `path`.
+pub(crate) fn write_atomic(
+    path: &Path,
+    content: &[u8],
+    permissions: fs::Permissions,
+    ownership: Ownership,
+) -> Result<(), ApplyError> {
+    let lift_err = |operation: &str| lift_io_err(path, operation);
+    let parent = parent_dir(path);
+    let tmp_path = tmp_path_for(path)?;
+
+    let write_tmp = || -> io::Result<()> {
+        let mut file = fs::OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .mode(permissions.mode() & 0o600)
+            .open(&tmp_path)?;
+        let metadata = file.metadata()?;
+        if ownership.differs_from(metadata.uid(), metadata.gid()) {
+            std::os::unix::fs::fchown(&file, ownership.uid, ownership.gid)?;
+        }
+        file.set_permissions(permissions.clone())?;
+        file.write_all(content)?;
+        file.sync_all()
+    };
+    if let Err(err) = write_tmp() {
+        let _ = fs::remove_file(&tmp_path);
+        return Err(lift_io_err(&tmp_path, "write temporary file")(err));
+    }
+    if let Err(err) = fs::rename(&tmp_path, path) {
+        let _ = fs::remove_file(&tmp_path);
+        return Err(lift_err("fs::rename")(err));
+    }
+    fs::File::open(parent)
+        .and_then(|dir| dir.sync_all())
+        .map_err(lift_io_err(parent, "fsync directory"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        dotfile::{nix_conf::NixConf, unstructured::Unstructured},
+        secret::Secret,
+    };
+
+    #[test]
+    fn transactional_apply_rolls_back() {
+        let dir = tempfile::tempdir().unwrap();
+        let a = dir.path().join("a");
+        let b = dir.path().join("b");
+        let c = dir.path().join("c");
+        fs::write(&a, "old a").unwrap();
+        fs::write(&b, [0xff, 0xfe]).unwrap();
+        let dotfiles: HashMap<PathBuf, Box<dyn Dotfile>> = HashMap::from([
+            (
+                a.clone(),
+                Box::new(Unstructured::new_utf8("new a".to_string())) as Box<dyn Dotfile>,
+            ),
+            (b.clone(), Box::new(NixConf::new())),
+            (
+                c.clone(),
+                Box::new(Unstructured::new_utf8("new c".to_string())),
+            ),
+        ]);
+        let report = apply_dotfiles(dotfiles, &ApplyOptions::new().with_transaction(true));
+        assert!(report.changed_files.is_empty());
+        assert_eq!(report.rolled_back, vec![a.clone()]);
+        assert!(report.errors.contains_key(&b));
+        assert_eq!(fs::read_to_string(&a).unwrap(), "old a");
+        assert!(!c.exists());
+    }
+
+    #[test]
+    fn rollback_through_symlinks() {
+        let dir = tempfile::tempdir().unwrap();
+        let real = dir.path().join("real");
+        fs::write(&real, "old").unwrap();
+        let link = dir.path().join("a-link");
+        std::os::unix::fs::symlink("real", &link).unwrap();
+        let dangling = dir.path().join("b-dangling");
+        std::os::unix::fs::symlink("missing", &dangling).unwrap();
+        // can't be created under a file, so the transaction fails after the links
+        let blocker = dir.path().join("z");
+        fs::write(&blocker, "").unwrap();
+        let broken = blocker.join("broken");
+        let dotfiles: HashMap<PathBuf, Box<dyn Dotfile>> = HashMap::from([
+            (
+                link.clone(),
+                Box::new(Unstructured::new_utf8("new".to_string())) as Box<dyn Dotfile>,
+            ),
+            (
+                dangling.clone(),
+                Box::new(Unstructured::new_utf8("new".to_string())),
+            ),
+            (
+                broken.clone(),
+                Box::new(Unstructured::new_utf8("new".to_string())),
+            ),
+        ]);
+        let report = apply_dotfiles(dotfiles, &ApplyOptions::new().with_transaction(true));
+        assert!(report.errors.contains_key(&broken));
+        assert_eq!(report.rolled_back, vec![dangling.clone(), link.clone()]);
+        assert_eq!(fs::read_link(&link).unwrap(), Path::new("real"));
+        assert_eq!(fs::read_to_string(&real).unwrap(), "old");
+        assert_eq!(fs::read_link(&dangling).unwrap(), Path::new("missing"));
+        assert!(!dir.path().join("missing").exists());
+    }
+
+    #[test]
+    fn rollback_restores_directory_mode() {
+        let dir = tempfile::tempdir().unwrap();
+        let aws_dir = dir.path().join(".aws");
+        fs::create_dir(&aws_dir).unwrap();
+        fs::set_permissions(&aws_dir, fs::Permissions::from_mode(0o755)).unwrap();
+        let credentials = aws_dir.join("credentials");
+        fs::write(&credentials, "key").unwrap();
+        fs::set_permissions(&credentials, fs::Permissions::from_mode(0o600)).unwrap();
+        // can't be created under a file, so the transaction fails after the directory was fixed
+        let blocker = dir.path().join("z");
+        fs::write(&blocker, "").unwrap();
+        let broken = blocker.join("broken");
+        let dotfiles: HashMap<PathBuf, Box<dyn Dotfile>> = HashMap::from([
+            (
+                credentials.clone(),
+                Box::new(Unstructured::from_secret(Secret::new(b"key".to_vec())))
+                    as Box<dyn Dotfile>,
+            ),
+            (
+                broken.clone(),
+                Box::new(Unstructured::new_utf8("new".to_string())),
+            ),
+        ]);
+        let report = apply_dotfiles(dotfiles, &ApplyOptions::new().with_transaction(true));
+        assert!(report.errors.contains_key(&broken));
+        assert_eq!(report.rolled_back, vec![credentials]);
+        assert_eq!(
+            fs::metadata(&aws_dir).unwrap().permissions().mode() & 0o777,
+            0o755
+        );
+    }
+
+    #[test]
+    fn write_atomic_sets_mode() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("secret");
+        write_atomic(
+            &path,
+            b"key",
+            fs::Permissions::from_mode(0o600),
+            Ownership::default(),
+        )
+        .unwrap();
+        assert_eq!(fs::read(&path).unwrap(), b"key");
+        assert_eq!(
+            fs::metadata(&path).unwrap().permissions().mode() & 0o777,
+            0o600
+        );
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn matching_ownership_left_alone() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("gitconfig");
+        fs::write(&path, "content").unwrap();
+        let dotfile = Unstructured::new_utf8("content".to_string())
+            .with_ownership(Ownership::of(&path).unwrap());
+        let dotfiles: HashMap<PathBuf, Box<dyn Dotfile>> =
+            HashMap::from([(path.clone(), Box::new(dotfile) as Box<dyn Dotfile>)]);
+        let report = apply_dotfiles(dotfiles, &ApplyOptions::new());
+        assert!(report.errors.is_empty());
+        assert!(report.changed_files.is_empty());
+        assert!(report.ownership_changed.is_empty());
+    }
+
+    #[test]
+    #[ignore = "chown to another user needs root"]
+    fn ownership_fixed_without_content_change() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("gitconfig");
+        fs::write(&path, "content").unwrap();
+        let dotfile = Unstructured::new_utf8("content".to_string())
+            .with_ownership(Ownership::new(1000, 1000));
+        let dotfiles: HashMap<PathBuf, Box<dyn Dotfile>> =
+            HashMap::from([(path.clone(), Box::new(dotfile) as Box<dyn Dotfile>)]);
+        let report = apply_dotfiles(dotfiles, &ApplyOptions::new());
+        assert!(report.errors.is_empty());
+        assert!(report.changed_files.is_empty());
+        assert_eq!(report.ownership_changed, HashSet::from([path.clone()]));
+        assert_eq!(Ownership::of(&path).unwrap(), Ownership::new(1000, 1000));
+    }
+
+    #[test]
+    fn permissions_fixed_without_content_change() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("secret-key");
+        fs::write(&path, "key").unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o644)).unwrap();
+        let dotfile = Unstructured::new_utf8("key".to_string())
+            .with_permissions(fs::Permissions::from_mode(0o600));
+        let dotfiles: HashMap<PathBuf, Box<dyn Dotfile>> =
+            HashMap::from([(path.clone(), Box::new(dotfile) as Box<dyn Dotfile>)]);
+        let report = apply_dotfiles(dotfiles, &ApplyOptions::new());
+        assert!(report.errors.is_empty());
+        assert!(report.changed_files.is_empty());
+        assert_eq!(report.permissions_fixed, HashSet::from([path.clone()]));
+        assert_eq!(
+            fs::metadata(&path).unwrap().permissions().mode() & 0o777,
+            0o600
+        );
+    }
+
+    #[test]
+    fn secrets_are_private() {
+        let dir = tempfile::tempdir().unwrap();
+        // already there, as it is after the non-secret config was written on its own
+        fs::create_dir(dir.path().join(".aws")).unwrap();
+        fs::set_permissions(dir.path().join(".aws"), fs::Permissions::from_mode(0o755)).unwrap();
+        let credentials = dir.path().join(".aws/credentials");
+        let config = dir.path().join(".aws/config");
+        let leaked = dir.path().join("leaked");
+        let dotfiles: HashMap<PathBuf, Box<dyn Dotfile>> = HashMap::from([
+            (
+                credentials.clone(),
+                Box::new(Unstructured::from_secret(Secret::new(b"key".to_vec())))
+                    as Box<dyn Dotfile>,
+            ),
+            (
+                config.clone(),
+                Box::new(Unstructured::new_utf8("config".to_string())),
+            ),
+            (
+                leaked.clone(),
+                Box::new(
+                    Unstructured::from_secret(Secret::new(b"key".to_vec()))
+                        .with_permissions(fs::Permissions::from_mode(0o644)),
+                ),
+            ),
+        ]);
+        let report = apply_dotfiles(dotfiles, &ApplyOptions::new());
+        assert!(matches!(
+            report.errors.get(&leaked),
+            Some(ApplyError::InsecureSecretPermissions { mode: 0o644 })
+        ));
+        assert!(!leaked.exists());
+        let mode = |path: &Path| fs::metadata(path).unwrap().permissions().mode() & 0o777;
+        assert_eq!(mode(&credentials), 0o600);
+        assert_eq!(mode(&dir.path().join(".aws")), 0o700);
+        assert_eq!(fs::read_to_string(&config).unwrap(), "config");
     }
 }
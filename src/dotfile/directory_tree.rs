@@ -0,0 +1,271 @@
+use super::{
+    lift_io_err, merge_ownership, merge_same_type, unstructured::Unstructured, ApplyError, Dotfile,
+    Dotfiles, MergeError, Ownership,
+};
+use std::{
+    any::Any,
+    collections::BTreeMap,
+    fs, io,
+    os::unix::fs::PermissionsExt,
+    path::{Path, PathBuf},
+};
+
+/// A directory whose files are synced from an embedded copy, e.g. all of `config/<something>/`.
+/// It is expanded into one `Unstructured` per file before being applied, so two targets adding
+/// different files under the same directory merge cleanly.
+#[derive(Debug, Default)]
+pub struct DirectoryTree {
+    /// Keyed by the path relative to the directory.
+    entries: BTreeMap<PathBuf, Entry>,
+    delete_extras: bool,
+    ownership: Ownership,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Entry {
+    content: Vec<u8>,
+    mode: u32,
+}
+
+impl DirectoryTree {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Every file in `dir` and its subdirectories, with mode 0644.
+    pub fn from_embedded(dir: &include_dir::Dir<'_>) -> Self {
+        let mut tree = Self::new();
+        tree.add_embedded(dir, dir.path());
+        tree
+    }
+
+    fn add_embedded(&mut self, dir: &include_dir::Dir<'_>, root: &Path) {
+        for file in dir.files() {
+            let relative = file.path().strip_prefix(root).unwrap_or(file.path());
+            self.entries.insert(
+                relative.to_path_buf(),
+                Entry {
+                    content: file.contents().to_vec(),
+                    mode: 0o644,
+                },
+            );
+        }
+        for subdir in dir.dirs() {
+            self.add_embedded(subdir, root);
+        }
+    }
+
+    pub fn with_file(mut self, relative: PathBuf, content: Vec<u8>) -> Self {
+        self.entries.insert(
+            relative,
+            Entry {
+                content,
+                mode: 0o644,
+            },
+        );
+        self
+    }
+
+    /// Overrides the mode of one file, e.g. to make a script executable. Panics if the tree
+    /// doesn't have the file, since that means the embedded directory and the code disagree.
+    pub fn with_mode(mut self, relative: &Path, mode: u32) -> Self {
+        match self.entries.get_mut(relative) {
+            Some(entry) => entry.mode = mode,
+            None => panic!("{:?} is not in the directory tree", relative),
+        }
+        self
+    }
+
+    /// Also remove files under the directory that aren't part of the tree.
+    pub fn with_delete_extras(self, delete_extras: bool) -> Self {
+        Self {
+            delete_extras,
+            ..self
+        }
+    }
+
+    pub fn with_ownership(self, ownership: Ownership) -> Self {
+        Self { ownership, ..self }
+    }
+}
+
+impl Dotfile for DirectoryTree {
+    /// A tree has no content of its own; see `expand`.
+    fn apply(&self, _old_content: &[u8]) -> Result<Option<Vec<u8>>, ApplyError> {
+        Ok(None)
+    }
+
+    fn file_permission(&self) -> fs::Permissions {
+        fs::Permissions::from_mode(0o755)
+    }
+
+    fn ownership(&self) -> Ownership {
+        self.ownership
+    }
+
+    fn expand(&self, path: &Path) -> Result<Option<Dotfiles>, ApplyError> {
+        let mut expanded = Dotfiles::new();
+        for (relative, entry) in self.entries.iter() {
+            expanded.insert(
+                path.join(relative),
+                Box::new(
+                    Unstructured::new(entry.content.clone())
+                        .with_permissions(fs::Permissions::from_mode(entry.mode))
+                        .with_ownership(self.ownership),
+                ),
+            );
+        }
+        if self.delete_extras {
+            for extra in list_files(path)? {
+                expanded.entry(extra).or_insert_with(|| Box::new(Absent));
+            }
+        }
+        Ok(Some(expanded))
+    }
+
+    fn merge(&mut self, y: Box<dyn Dotfile>) -> Result<(), MergeError> {
+        merge_same_type(self, y, |mut x, y| {
+            for (relative, entry) in y.entries {
+                match x.entries.get(&relative) {
+                    Some(existing) if *existing != entry => {
+                        return Err(MergeError::MergeConflict {
+                            reason: format!(
+                                "directory trees with different content for {:?}",
+                                relative
+                            ),
+                        })
+                    }
+                    _ => {
+                        x.entries.insert(relative, entry);
+                    }
+                }
+            }
+            Ok(DirectoryTree {
+                entries: x.entries,
+                delete_extras: x.delete_extras || y.delete_extras,
+                ownership: merge_ownership(x.ownership, y.ownership)?,
+            })
+        })
+    }
+
+    fn as_any(self: Box<Self>) -> Box<dyn Any> {
+        Box::new(*self)
+    }
+}
+
+/// All files (and symlinks) under `dir`, recursively. Nothing if it doesn't exist.
+fn list_files(dir: &Path) -> Result<Vec<PathBuf>, ApplyError> {
+    let mut files = Vec::new();
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(files),
+        Err(err) => return Err(lift_io_err(dir, "fs::read_dir")(err)),
+    };
+    for entry in entries {
+        let entry = entry.map_err(lift_io_err(dir, "fs::read_dir"))?;
+        let file_type = entry
+            .file_type()
+            .map_err(lift_io_err(dir, "fs::read_dir"))?;
+        if file_type.is_dir() {
+            files.append(&mut list_files(&entry.path())?);
+        } else {
+            files.push(entry.path());
+        }
+    }
+    Ok(files)
+}
+
+/// A path that must not exist. Used for the extra files of a `DirectoryTree`.
+#[derive(Debug, Default)]
+pub(crate) struct Absent;
+
+impl Dotfile for Absent {
+    fn apply(&self, _old_content: &[u8]) -> Result<Option<Vec<u8>>, ApplyError> {
+        Ok(None)
+    }
+
+    fn file_permission(&self) -> fs::Permissions {
+        fs::Permissions::from_mode(0o644)
+    }
+
+    fn layout(&self) -> super::Layout<'_> {
+        super::Layout::Absent
+    }
+
+    fn merge(&mut self, y: Box<dyn Dotfile>) -> Result<(), MergeError> {
+        merge_same_type(self, y, |x, _| Ok(x))
+    }
+
+    fn as_any(self: Box<Self>) -> Box<dyn Any> {
+        Box::new(*self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dotfile::{apply_dotfiles, expand_dotfiles, merge_dotfiles, ApplyOptions};
+    use std::collections::HashMap;
+
+    static FIXTURE: include_dir::Dir<'_> =
+        include_dir::include_dir!("$CARGO_MANIFEST_DIR/testdata/directory_tree");
+
+    #[test]
+    fn from_embedded() {
+        let tree =
+            DirectoryTree::from_embedded(&FIXTURE).with_mode(Path::new("lua/plugins.lua"), 0o600);
+        assert_eq!(
+            tree.entries.keys().collect::<Vec<_>>(),
+            vec![Path::new("init.lua"), Path::new("lua/plugins.lua")]
+        );
+        assert_eq!(tree.entries[Path::new("init.lua")].mode, 0o644);
+        assert_eq!(tree.entries[Path::new("lua/plugins.lua")].mode, 0o600);
+    }
+
+    #[test]
+    #[should_panic(expected = "not in the directory tree")]
+    fn mode_of_missing_file() {
+        DirectoryTree::from_embedded(&FIXTURE).with_mode(Path::new("plugins.lua"), 0o600);
+    }
+
+    #[test]
+    fn merge_and_sync() {
+        let dir = tempfile::tempdir().unwrap();
+        let nvim = dir.path().join("nvim");
+        fs::create_dir_all(nvim.join("lua")).unwrap();
+        fs::write(nvim.join("lua/extra.lua"), "stale").unwrap();
+
+        let x = DirectoryTree::new().with_file(PathBuf::from("init.lua"), b"init".to_vec());
+        let y = DirectoryTree::new()
+            .with_file(PathBuf::from("lua/plugins.lua"), b"plugins".to_vec())
+            .with_mode(Path::new("lua/plugins.lua"), 0o600)
+            .with_delete_extras(true);
+        let dotfiles = merge_dotfiles(vec![
+            HashMap::from([(nvim.clone(), Box::new(x) as Box<dyn Dotfile>)]),
+            HashMap::from([(nvim.clone(), Box::new(y) as Box<dyn Dotfile>)]),
+        ])
+        .unwrap();
+        let (dotfiles, _) = expand_dotfiles(dotfiles).unwrap();
+        assert_eq!(dotfiles.len(), 3);
+        let report = apply_dotfiles(dotfiles, &ApplyOptions::new());
+        assert!(report.errors.is_empty(), "{:?}", report.errors);
+        assert_eq!(fs::read_to_string(nvim.join("init.lua")).unwrap(), "init");
+        assert_eq!(
+            fs::metadata(nvim.join("lua/plugins.lua"))
+                .unwrap()
+                .permissions()
+                .mode()
+                & 0o777,
+            0o600
+        );
+        assert!(!nvim.join("lua/extra.lua").exists());
+        assert!(report.changed_files.contains(&nvim.join("lua/extra.lua")));
+    }
+
+    #[test]
+    fn merge_conflict() {
+        let mut x = DirectoryTree::new().with_file(PathBuf::from("a"), b"x".to_vec());
+        let y = DirectoryTree::new().with_file(PathBuf::from("a"), b"y".to_vec());
+        assert!(x.merge(Box::new(y)).is_err());
+    }
+}
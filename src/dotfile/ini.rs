@@ -0,0 +1,581 @@
+use super::{
+    apply_utf8, merge_ownership, merge_same_type,
+    parts::{Part, Parts},
+    ApplyError, Dotfile, MergeError, Ownership,
+};
+use std::{any::Any, error::Error, fmt, fs, os::unix::fs::PermissionsExt};
+
+/**
+INI-like files such as `~/.gitconfig` or `~/.aws/config`. Like `NixConf`, only the keys set here
+are touched: other keys, sections and comments in the file are kept as they are.
+*/
+#[derive(Debug)]
+pub struct Ini {
+    /// In the order they were first set, which is also the order missing ones are appended in.
+    sections: Vec<(Section, Vec<(String, Value)>)>,
+    permissions: fs::Permissions,
+    ownership: Ownership,
+}
+
+/// `[name]`, or `[name "subsection"]` as git writes it. AWS's `[profile x]` is just a name.
+/// Like git, names are matched case-insensitively and subsections exactly.
+#[derive(Debug, Clone, Eq)]
+pub struct Section {
+    pub name: String,
+    pub subsection: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Value {
+    Scalar(String),
+    /// A key that appears once per value, like git's `remote.<name>.fetch`. Values from several
+    /// targets are merged by union, and so are values already in the file: missing ones are added
+    /// and the rest are left alone.
+    Multi(Vec<String>),
+    /// The key must not be in the file.
+    Unset,
+}
+
+/// Which keys of a file hold several values, one line each. All other keys hold one value.
+#[derive(Debug)]
+pub struct Schema {
+    /// `section.key`, or `section.*.key` for the key in any subsection of `section`.
+    pub multi_valued: &'static [&'static str],
+}
+
+impl Schema {
+    pub fn is_multi_valued(&self, section: &Section, key: &str) -> bool {
+        self.multi_valued.iter().any(|pattern| {
+            let (name, subsection, multi_key) = match pattern.split('.').collect::<Vec<_>>()[..] {
+                [name, key] => (name, false, key),
+                [name, "*", key] => (name, true, key),
+                _ => return false,
+            };
+            section.name.eq_ignore_ascii_case(name)
+                && section.subsection.is_some() == subsection
+                && key.eq_ignore_ascii_case(multi_key)
+        })
+    }
+}
+
+/// The keys of `~/.gitconfig` that git reads every value of.
+pub static GITCONFIG_SCHEMA: Schema = Schema {
+    multi_valued: &[
+        "include.path",
+        "includeIf.*.path",
+        "remote.*.fetch",
+        "remote.*.push",
+        "remote.*.pushurl",
+        "url.*.insteadOf",
+        "url.*.pushInsteadOf",
+    ],
+};
+
+/// For files where every key holds one value, like `~/.aws/config`.
+pub static SINGLE_VALUED_SCHEMA: Schema = Schema { multi_valued: &[] };
+
+impl Default for Ini {
+    fn default() -> Self {
+        Self {
+            sections: Vec::new(),
+            permissions: fs::Permissions::from_mode(0o644),
+            ownership: Ownership::default(),
+        }
+    }
+}
+
+impl Section {
+    pub fn new(name: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            subsection: None,
+        }
+    }
+
+    pub fn with_subsection(self, subsection: &str) -> Self {
+        Self {
+            subsection: Some(subsection.to_string()),
+            ..self
+        }
+    }
+
+    fn parse_header(line: &str) -> Option<Self> {
+        let header = line.trim().strip_prefix('[')?.strip_suffix(']')?.trim();
+        match header.split_once(char::is_whitespace) {
+            Some((name, subsection))
+                if subsection.trim().len() >= 2
+                    && subsection.trim().starts_with('"')
+                    && subsection.trim().ends_with('"') =>
+            {
+                let subsection = subsection.trim();
+                Some(Section::new(name).with_subsection(&subsection[1..subsection.len() - 1]))
+            }
+            _ => Some(Section::new(header)),
+        }
+    }
+}
+
+impl PartialEq for Section {
+    fn eq(&self, other: &Self) -> bool {
+        self.name.eq_ignore_ascii_case(&other.name) && self.subsection == other.subsection
+    }
+}
+
+impl fmt::Display for Section {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.subsection {
+            Some(subsection) => write!(f, "[{} \"{}\"]", self.name, subsection),
+            None => write!(f, "[{}]", self.name),
+        }
+    }
+}
+
+impl Ini {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Every key in `content`, e.g. an embedded config. Keys that `schema` says are
+    /// multi-valued collect all their values; for the others the last one wins, as in git.
+    pub fn parse(content: &str, schema: &Schema) -> Self {
+        let mut ini = Self::new();
+        let mut section = Section::new("");
+        for line in content.lines() {
+            if let Some(header) = Section::parse_header(line) {
+                section = header;
+            } else if let Some((key, value)) = parse_key_value(line) {
+                let value = if schema.is_multi_valued(&section, key) {
+                    let mut values = match ini.get(&section, key) {
+                        Some(Value::Multi(values)) => values.clone(),
+                        _ => Vec::new(),
+                    };
+                    values.push(value.to_string());
+                    Value::Multi(values)
+                } else {
+                    Value::Scalar(value.to_string())
+                };
+                ini = ini.with(section.clone(), key, value);
+            }
+        }
+        ini
+    }
+
+    pub fn with_value(self, section: Section, key: &str, value: &str) -> Self {
+        self.with(section, key, Value::Scalar(value.to_string()))
+    }
+
+    pub fn with_values(self, section: Section, key: &str, values: Vec<String>) -> Self {
+        self.with(section, key, Value::Multi(values))
+    }
+
+    pub fn without(self, section: Section, key: &str) -> Self {
+        self.with(section, key, Value::Unset)
+    }
+
+    pub fn with(mut self, section: Section, key: &str, value: Value) -> Self {
+        let keys = match self.sections.iter_mut().position(|(s, _)| *s == section) {
+            Some(index) => &mut self.sections[index].1,
+            None => {
+                self.sections.push((section, Vec::new()));
+                &mut self.sections.last_mut().unwrap().1
+            }
+        };
+        match keys.iter_mut().find(|(k, _)| k.eq_ignore_ascii_case(key)) {
+            Some((_, existing)) => *existing = value,
+            None => keys.push((key.to_string(), value)),
+        }
+        self
+    }
+
+    pub fn with_permissions(self, permissions: fs::Permissions) -> Self {
+        Self {
+            permissions,
+            ..self
+        }
+    }
+
+    pub fn with_ownership(self, ownership: Ownership) -> Self {
+        Self { ownership, ..self }
+    }
+
+    fn get(&self, section: &Section, key: &str) -> Option<&Value> {
+        self.sections
+            .iter()
+            .find(|(s, _)| s == section)
+            .and_then(|(_, keys)| keys.iter().find(|(k, _)| k.eq_ignore_ascii_case(key)))
+            .map(|(_, value)| value)
+    }
+}
+
+fn parse_key_value(line: &str) -> Option<(&str, &str)> {
+    let trimmed = line.trim();
+    if trimmed.starts_with('#') || trimmed.starts_with(';') {
+        return None;
+    }
+    let (key, value) = trimmed.split_once('=')?;
+    Some((key.trim(), strip_comment(value).trim()))
+}
+
+/// `value` up to the `;` or `#` that starts a comment, if any. Those inside double quotes are
+/// part of the value.
+fn strip_comment(value: &str) -> &str {
+    let mut quoted = false;
+    let mut escaped = false;
+    for (index, c) in value.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' => escaped = true,
+            '"' => quoted = !quoted,
+            ';' | '#' if !quoted => return &value[..index],
+            _ => {}
+        }
+    }
+    value
+}
+
+/// `line` split before its comment, if any, including the whitespace before the comment.
+fn split_comment(line: &str) -> (&str, &str) {
+    let Some(eq) = line.find('=') else {
+        return (line, "");
+    };
+    let end = eq + 1 + strip_comment(&line[eq + 1..]).trim_end().len();
+    line.split_at(end)
+}
+
+/// Sets `key` in `section` of `lines` to `value`, editing in place where the key already is.
+/// Values of a multi-valued key that are already in the file are kept, so only the missing ones
+/// are added, after the key's last line.
+fn apply_key(lines: &mut Vec<String>, section: &Section, key: &str, value: &Value) {
+    let mut in_section = section.name.is_empty() && section.subsection.is_none();
+    let mut key_lines = Vec::new();
+    let mut existing_values = Vec::new();
+    // where a key that is new to the section goes: after its last key
+    let mut section_end = None;
+    for (index, line) in lines.iter().enumerate() {
+        if let Some(header) = Section::parse_header(line) {
+            in_section = header == *section;
+            if in_section {
+                section_end = Some(index + 1);
+            }
+        } else if in_section {
+            if let Some((k, v)) = parse_key_value(line) {
+                section_end = Some(index + 1);
+                if k.eq_ignore_ascii_case(key) {
+                    key_lines.push(index);
+                    existing_values.push(v.to_string());
+                }
+            }
+        }
+    }
+    // keep the indentation of the lines already there
+    let indent: String = key_lines
+        .first()
+        .map(|first| {
+            lines[*first]
+                .chars()
+                .take_while(|c| c.is_whitespace())
+                .collect()
+        })
+        .unwrap_or_default();
+    let (missing, duplicates): (Vec<&String>, &[usize]) = match value {
+        Value::Unset => (Vec::new(), &key_lines[..]),
+        Value::Multi(values) => (
+            values
+                .iter()
+                .filter(|value| !existing_values.contains(value))
+                .collect(),
+            &[],
+        ),
+        // as in git, the last line is the one that counts
+        Value::Scalar(value) => match key_lines.split_last() {
+            Some((last, duplicates)) => {
+                if existing_values.last() != Some(value) {
+                    let (_, comment) = split_comment(&lines[*last]);
+                    lines[*last] = format!("{}{} = {}{}", indent, key, value, comment);
+                }
+                (Vec::new(), duplicates)
+            }
+            None => (vec![value], &[]),
+        },
+    };
+    if !missing.is_empty() {
+        let rendered: Vec<String> = missing
+            .iter()
+            .map(|value| format!("{}{} = {}", indent, key, value))
+            .collect();
+        let insert_at = match (key_lines.last(), section_end) {
+            (Some(last), _) => last + 1,
+            (None, Some(section_end)) => section_end,
+            (None, None) => {
+                if lines.last().is_some_and(|line| !line.trim().is_empty()) {
+                    lines.push(String::new());
+                }
+                lines.push(section.to_string());
+                lines.len()
+            }
+        };
+        lines.splice(insert_at..insert_at, rendered);
+    }
+    for index in duplicates.iter().rev() {
+        lines.remove(*index);
+    }
+}
+
+impl Dotfile for Ini {
+    fn apply(&self, old_content: &[u8]) -> Result<Option<Vec<u8>>, ApplyError> {
+        apply_utf8(
+            old_content,
+            |old_content: String| -> Result<Option<String>, Box<dyn Error + Send + Sync>> {
+                let mut lines: Vec<String> = old_content.lines().map(String::from).collect();
+                for (section, keys) in self.sections.iter() {
+                    for (key, value) in keys {
+                        apply_key(&mut lines, section, key, value);
+                    }
+                }
+                let mut new_content = lines.join("\n");
+                if !new_content.is_empty() {
+                    new_content.push('\n');
+                }
+                if new_content == old_content {
+                    Ok(None)
+                } else {
+                    Ok(Some(new_content))
+                }
+            },
+        )
+    }
+
+    fn file_permission(&self) -> fs::Permissions {
+        self.permissions.clone()
+    }
+
+    fn ownership(&self) -> Ownership {
+        self.ownership
+    }
+
+    fn parts(&self) -> Option<Parts> {
+        Some(Parts::new(self.sections.iter().flat_map(
+            |(section, keys)| {
+                keys.iter()
+                    .filter(|(_, value)| *value != Value::Unset)
+                    .map(|(key, _)| Part::IniKey {
+                        section: section.name.clone(),
+                        subsection: section.subsection.clone(),
+                        key: key.clone(),
+                    })
+            },
+        )))
+    }
+
+    fn merge(&mut self, y: Box<dyn Dotfile>) -> Result<(), MergeError> {
+        merge_same_type(self, y, |mut x, y| {
+            for (section, keys) in y.sections {
+                for (key, value) in keys {
+                    let merged = match (x.get(&section, &key), value) {
+                        (None, value) => value,
+                        (Some(Value::Multi(xs)), Value::Multi(ys)) => {
+                            let mut values = xs.clone();
+                            for y in ys {
+                                if !values.contains(&y) {
+                                    values.push(y);
+                                }
+                            }
+                            Value::Multi(values)
+                        }
+                        (Some(x), y) if *x == y => y,
+                        (Some(_), _) => {
+                            return Err(MergeError::MergeConflict {
+                                reason: format!("conflicting values for {}.{}", section, key),
+                            })
+                        }
+                    };
+                    x = x.with(section.clone(), &key, merged);
+                }
+            }
+            Ok(Ini {
+                ownership: merge_ownership(x.ownership, y.ownership)?,
+                ..x
+            })
+        })
+    }
+
+    fn as_any(self: Box<Self>) -> Box<dyn Any> {
+        Box::new(*self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn apply(ini: &Ini, old_content: &str) -> Option<String> {
+        ini.apply(old_content.as_bytes())
+            .unwrap()
+            .map(|new_content| String::from_utf8(new_content).unwrap())
+    }
+
+    #[test]
+    fn edit_only_owned_keys() {
+        let ini = Ini::parse(
+            "[user]\nname=kokobd\n[core]\neditor=vim\n",
+            &GITCONFIG_SCHEMA,
+        )
+        .with_values(
+            Section::new("remote").with_subsection("origin"),
+            "fetch",
+            vec!["+refs/heads/*:refs/remotes/origin/*".to_string()],
+        )
+        .without(Section::new("credential"), "helper");
+        let old_content = "\
+# by hand
+[user]
+\tname = someone
+\tsigningkey = ABCD
+[credential]
+\thelper = store
+[alias]
+\tco = checkout
+";
+        assert_eq!(
+            apply(&ini, old_content).unwrap(),
+            "\
+# by hand
+[user]
+\tname = kokobd
+\tsigningkey = ABCD
+[credential]
+[alias]
+\tco = checkout
+
+[core]
+editor = vim
+
+[remote \"origin\"]
+fetch = +refs/heads/*:refs/remotes/origin/*
+"
+        );
+        let unchanged = "[user]\n\tname=kokobd\n[core]\neditor = vim\n[remote \"origin\"]\nfetch = +refs/heads/*:refs/remotes/origin/*\n";
+        assert_eq!(apply(&ini, unchanged), None);
+    }
+
+    #[test]
+    fn merge_keys() {
+        let origin = Section::new("remote").with_subsection("origin");
+        let mut x = Ini::parse(include_str!("../../config/.gitconfig"), &GITCONFIG_SCHEMA)
+            .with_values(origin.clone(), "fetch", vec!["a".to_string()]);
+        x.merge(Box::new(
+            Ini::new()
+                .with_value(
+                    Section::new("includeIf").with_subsection("gitdir:~/work/"),
+                    "path",
+                    "~/work/.gitconfig",
+                )
+                .with_values(origin.clone(), "fetch", vec!["b".to_string()]),
+        ))
+        .unwrap();
+        assert_eq!(
+            x.get(&origin, "fetch"),
+            Some(&Value::Multi(vec!["a".to_string(), "b".to_string()]))
+        );
+        assert_eq!(
+            x.get(&Section::new("user"), "name"),
+            Some(&Value::Scalar("kokobd".to_string()))
+        );
+
+        let mut aws = Ini::parse(
+            include_str!("../../config/aws/config"),
+            &SINGLE_VALUED_SCHEMA,
+        );
+        assert!(aws
+            .merge(Box::new(Ini::new().with_value(
+                Section::new("profile minio"),
+                "region",
+                "eu-west-1"
+            )))
+            .is_err());
+    }
+
+    #[test]
+    fn names_comments_and_schema() {
+        let ini = Ini::parse(
+            "[Remote \"origin\"]\nfetch = a ; first\nfetch = b\n[Core]\nEditor = vim # mine\n",
+            &GITCONFIG_SCHEMA,
+        );
+        let origin = Section::new("remote").with_subsection("origin");
+        assert_eq!(
+            ini.get(&origin, "FETCH"),
+            Some(&Value::Multi(vec!["a".to_string(), "b".to_string()]))
+        );
+        assert_eq!(
+            ini.get(&Section::new("core"), "editor"),
+            Some(&Value::Scalar("vim".to_string()))
+        );
+        assert_eq!(
+            Ini::parse("[core]\neditor = vi\neditor = vim\n", &GITCONFIG_SCHEMA)
+                .get(&Section::new("core"), "editor"),
+            Some(&Value::Scalar("vim".to_string())),
+            "a repeated single-valued key takes the last value"
+        );
+        assert_eq!(
+            Ini::parse(
+                "[alias]\nl = \"log ; --oneline #x\" # short\n",
+                &GITCONFIG_SCHEMA
+            )
+            .get(&Section::new("alias"), "l"),
+            Some(&Value::Scalar("\"log ; --oneline #x\"".to_string()))
+        );
+
+        let old_content = "[CORE]\n\teditor = vim ; by hand\n[remote \"Origin\"]\n\tfetch = x\n";
+        assert_eq!(
+            apply(
+                &Ini::new().with_value(Section::new("core"), "EDITOR", "vim"),
+                old_content
+            ),
+            None
+        );
+        assert_eq!(
+            apply(
+                &Ini::new().with_values(origin, "fetch", vec!["a".to_string()]),
+                old_content
+            )
+            .unwrap(),
+            format!("{}\n[remote \"origin\"]\nfetch = a\n", old_content)
+        );
+    }
+
+    #[test]
+    fn keep_users_values_and_comments() {
+        let origin = Section::new("remote").with_subsection("origin");
+        let ini = Ini::new()
+            .with_values(
+                origin.clone(),
+                "fetch",
+                vec!["a".to_string(), "b".to_string()],
+            )
+            .with_value(Section::new("core"), "editor", "vim");
+        let old_content = "\
+[remote \"origin\"]
+\tfetch = mine
+\tfetch = b ; shared
+[core]
+\teditor = vi  # by hand
+\teditor = nano
+";
+        assert_eq!(
+            apply(&ini, old_content).unwrap(),
+            "\
+[remote \"origin\"]
+\tfetch = mine
+\tfetch = b ; shared
+\tfetch = a
+[core]
+\teditor = vim
+"
+        );
+        let old_content = "[core]\n\teditor = vi  # by hand\n";
+        assert_eq!(
+            apply(&ini, old_content).unwrap(),
+            "[core]\n\teditor = vim  # by hand\n\n[remote \"origin\"]\nfetch = a\nfetch = b\n"
+        );
+    }
+}
@@ -0,0 +1,624 @@
+use super::structured::{BoxError, Format, Node, Structured};
+use serde::Serialize;
+use serde_json::Value;
+use std::ops::Range;
+
+/// JSON, with comments and trailing commas (JSONC, as in VS Code's settings) accepted. Only the
+/// values that change are rewritten: comments, key order and formatting elsewhere are kept.
+pub type Json = Structured<JsonFormat>;
+
+#[derive(Debug, Default)]
+pub struct JsonFormat;
+
+/// A parsed JSONC file that remembers where each value came from, so that it can be rendered
+/// again with everything that wasn't changed as it was.
+#[derive(Debug)]
+pub struct JsoncDocument {
+    source: String,
+    /// Where the root value is in `source`, if it isn't blank.
+    root_span: Option<Range<usize>>,
+    root: JsoncNode,
+}
+
+#[derive(Debug)]
+pub struct JsoncNode {
+    /// Where the value is in the source, unless it is new or was replaced.
+    span: Option<Range<usize>>,
+    kind: NodeKind,
+}
+
+#[derive(Debug)]
+enum NodeKind {
+    Object(JsoncObject),
+    /// Anything that isn't an object, which is only ever replaced as a whole.
+    Leaf(Value),
+}
+
+#[derive(Debug, Default)]
+struct JsoncObject {
+    members: Vec<Member>,
+    /// The text between the last member, or its comma, and the closing brace.
+    tail: Option<Range<usize>>,
+    trailing_comma: bool,
+}
+
+#[derive(Debug)]
+struct Member {
+    key: String,
+    value: JsoncNode,
+    /// Where the text around the value is in the source, unless the member is new.
+    source: Option<MemberSource>,
+}
+
+#[derive(Debug)]
+struct MemberSource {
+    /// Whitespace and comments before the key.
+    leading: Range<usize>,
+    /// From the key to the value, with the colon.
+    key: Range<usize>,
+    /// Whitespace and comments between the value and its comma.
+    after: Range<usize>,
+}
+
+impl Format for JsonFormat {
+    const NAME: &'static str = "json";
+    type Document = JsoncDocument;
+    type Node = JsoncNode;
+
+    fn parse(content: &str) -> Result<JsoncDocument, BoxError> {
+        if content.trim().is_empty() {
+            return Ok(JsoncDocument {
+                source: content.to_string(),
+                root_span: None,
+                root: JsoncNode::new(&Value::Object(Default::default())),
+            });
+        }
+        let mut parser = Parser {
+            source: content,
+            position: 0,
+        };
+        let root = parser.value()?;
+        parser.skip_trivia()?;
+        if parser.position < content.len() {
+            return Err(format!("unexpected text at byte {}", parser.position).into());
+        }
+        Ok(JsoncDocument {
+            source: content.to_string(),
+            root_span: root.span.clone(),
+            root,
+        })
+    }
+
+    fn root(document: &mut JsoncDocument) -> &mut JsoncNode {
+        &mut document.root
+    }
+
+    fn render(document: &JsoncDocument, old_content: &str) -> Result<String, BoxError> {
+        let indent = detect_indent(old_content);
+        let root = document.root.render(&document.source, &indent, 0)?;
+        Ok(match &document.root_span {
+            Some(span) => format!(
+                "{}{}{}",
+                &document.source[..span.start],
+                root,
+                &document.source[span.end..]
+            ),
+            None => root + "\n",
+        })
+    }
+}
+
+impl JsoncNode {
+    /// A node for `value`, which isn't in the source.
+    fn new(value: &Value) -> Self {
+        let kind = match value {
+            Value::Object(map) => NodeKind::Object(JsoncObject {
+                members: map
+                    .iter()
+                    .map(|(key, value)| Member {
+                        key: key.clone(),
+                        value: JsoncNode::new(value),
+                        source: None,
+                    })
+                    .collect(),
+                ..Default::default()
+            }),
+            value => NodeKind::Leaf(value.clone()),
+        };
+        Self { span: None, kind }
+    }
+
+    fn to_value(&self) -> Value {
+        match &self.kind {
+            NodeKind::Object(object) => Value::Object(
+                object
+                    .members
+                    .iter()
+                    .map(|member| (member.key.clone(), member.value.to_value()))
+                    .collect(),
+            ),
+            NodeKind::Leaf(value) => value.clone(),
+        }
+    }
+
+    /// The text of this node, `depth` levels deep. Unchanged values are copied from `source`.
+    fn render(&self, source: &str, indent: &str, depth: usize) -> Result<String, BoxError> {
+        let object = match (&self.kind, &self.span) {
+            (NodeKind::Leaf(_), Some(span)) => return Ok(source[span.clone()].to_string()),
+            (NodeKind::Leaf(value), None) => {
+                let mut content = Vec::new();
+                let formatter = serde_json::ser::PrettyFormatter::with_indent(indent.as_bytes());
+                value.serialize(&mut serde_json::Serializer::with_formatter(
+                    &mut content,
+                    formatter,
+                ))?;
+                let newline = format!("\n{}", indent.repeat(depth));
+                return Ok(String::from_utf8(content)?.replace('\n', &newline));
+            }
+            (NodeKind::Object(object), _) => object,
+        };
+        let mut content = "{".to_string();
+        for (index, member) in object.members.iter().enumerate() {
+            let value = member.value.render(source, indent, depth + 1)?;
+            match &member.source {
+                Some(member_source) => {
+                    content += &source[member_source.leading.clone()];
+                    content += &source[member_source.key.clone()];
+                    content += &value;
+                    content += &source[member_source.after.clone()];
+                }
+                None => {
+                    content += &format!(
+                        "\n{}{}: {}",
+                        indent.repeat(depth + 1),
+                        serde_json::to_string(&member.key)?,
+                        value
+                    );
+                }
+            }
+            if index + 1 < object.members.len() || object.trailing_comma {
+                content.push(',');
+            }
+        }
+        let tail = object.tail.clone().map_or("", |tail| &source[tail]);
+        let last_is_new = object
+            .members
+            .last()
+            .is_some_and(|member| member.source.is_none());
+        if last_is_new && !tail.contains('\n') {
+            content += &format!("\n{}{}", indent.repeat(depth), tail.trim());
+        } else {
+            content += tail;
+        }
+        content.push('}');
+        Ok(content)
+    }
+}
+
+impl Node for JsoncNode {
+    fn is_map(&self) -> bool {
+        matches!(self.kind, NodeKind::Object(_))
+    }
+
+    fn child_mut(&mut self, key: &str) -> Option<&mut Self> {
+        match &mut self.kind {
+            NodeKind::Object(object) => object
+                .members
+                .iter_mut()
+                .find(|member| member.key == key)
+                .map(|member| &mut member.value),
+            NodeKind::Leaf(_) => None,
+        }
+    }
+
+    fn insert(&mut self, key: &str, value: &Value) -> Result<(), BoxError> {
+        if let NodeKind::Object(object) = &mut self.kind {
+            object.members.push(Member {
+                key: key.to_string(),
+                value: JsoncNode::new(value),
+                source: None,
+            });
+        }
+        Ok(())
+    }
+
+    fn set(&mut self, value: &Value) -> Result<bool, BoxError> {
+        if same_value(&self.to_value(), value) {
+            Ok(false)
+        } else {
+            *self = JsoncNode::new(value);
+            Ok(true)
+        }
+    }
+
+    fn remove(&mut self, key: &str) -> Result<bool, BoxError> {
+        let NodeKind::Object(object) = &mut self.kind else {
+            return Ok(false);
+        };
+        let Some(index) = object.members.iter().position(|member| member.key == key) else {
+            return Ok(false);
+        };
+        object.members.remove(index);
+        if object.members.is_empty() {
+            object.trailing_comma = false;
+        }
+        Ok(true)
+    }
+}
+
+/// Finds where the values of a JSONC text are. Objects are parsed member by member; everything
+/// else is skipped over and then parsed as a whole.
+struct Parser<'a> {
+    source: &'a str,
+    position: usize,
+}
+
+impl Parser<'_> {
+    fn peek(&self) -> Option<u8> {
+        self.source.as_bytes().get(self.position).copied()
+    }
+
+    fn expect(&mut self, expected: u8) -> Result<(), BoxError> {
+        if self.peek() == Some(expected) {
+            self.position += 1;
+            Ok(())
+        } else {
+            Err(format!("expected {:?} at byte {}", expected as char, self.position).into())
+        }
+    }
+
+    /// Moves past whitespace and comments.
+    fn skip_trivia(&mut self) -> Result<(), BoxError> {
+        loop {
+            let rest = &self.source[self.position..];
+            let trimmed = rest.trim_start();
+            self.position += rest.len() - trimmed.len();
+            if trimmed.starts_with("//") {
+                self.position += trimmed.find('\n').unwrap_or(trimmed.len());
+            } else if let Some(comment) = trimmed.strip_prefix("/*") {
+                self.position += comment.find("*/").ok_or("unterminated comment")? + 4;
+            } else {
+                return Ok(());
+            }
+        }
+    }
+
+    fn value(&mut self) -> Result<JsoncNode, BoxError> {
+        self.skip_trivia()?;
+        if self.peek() == Some(b'{') {
+            return self.object();
+        }
+        let start = self.position;
+        self.skip_value()?;
+        let text = &self.source[start..self.position];
+        Ok(JsoncNode {
+            span: Some(start..self.position),
+            kind: NodeKind::Leaf(serde_json::from_str(&strip_jsonc(text))?),
+        })
+    }
+
+    /// Moves past a value that isn't an object at the top level.
+    fn skip_value(&mut self) -> Result<(), BoxError> {
+        match self.peek() {
+            Some(b'"') => self.skip_string(),
+            Some(b'{') => self.object().map(|_| ()),
+            Some(b'[') => {
+                self.position += 1;
+                loop {
+                    self.skip_trivia()?;
+                    match self.peek() {
+                        Some(b']') => {
+                            self.position += 1;
+                            return Ok(());
+                        }
+                        Some(b',') => self.position += 1,
+                        Some(_) => self.skip_value()?,
+                        None => return Err("unterminated array".into()),
+                    }
+                }
+            }
+            Some(_) => {
+                let rest = &self.source[self.position..];
+                let len = rest
+                    .find(|c: char| c.is_whitespace() || matches!(c, ',' | '}' | ']' | '/'))
+                    .unwrap_or(rest.len());
+                if len == 0 {
+                    return Err(format!("unexpected text at byte {}", self.position).into());
+                }
+                self.position += len;
+                Ok(())
+            }
+            None => Err("unexpected end of file".into()),
+        }
+    }
+
+    fn skip_string(&mut self) -> Result<(), BoxError> {
+        let mut escaped = false;
+        for (index, c) in self.source[self.position + 1..].char_indices() {
+            match c {
+                _ if escaped => escaped = false,
+                '\\' => escaped = true,
+                '"' => {
+                    self.position += index + 2;
+                    return Ok(());
+                }
+                _ => {}
+            }
+        }
+        Err("unterminated string".into())
+    }
+
+    fn object(&mut self) -> Result<JsoncNode, BoxError> {
+        let start = self.position;
+        self.expect(b'{')?;
+        let mut object = JsoncObject::default();
+        let mut leading_start = self.position;
+        loop {
+            self.skip_trivia()?;
+            if self.peek() == Some(b'}') {
+                break;
+            }
+            let key_start = self.position;
+            if self.peek() != Some(b'"') {
+                return Err(format!("expected a key at byte {}", key_start).into());
+            }
+            self.skip_string()?;
+            let key = serde_json::from_str(&self.source[key_start..self.position])?;
+            self.skip_trivia()?;
+            self.expect(b':')?;
+            let value = self.value()?;
+            let value_start = value.span.as_ref().map_or(self.position, |span| span.start);
+            let value_end = self.position;
+            self.skip_trivia()?;
+            let comma = self.peek() == Some(b',');
+            let after = if comma {
+                value_end..self.position
+            } else {
+                value_end..value_end
+            };
+            object.members.push(Member {
+                key,
+                value,
+                source: Some(MemberSource {
+                    leading: leading_start..key_start,
+                    key: key_start..value_start,
+                    after,
+                }),
+            });
+            object.trailing_comma = comma;
+            if comma {
+                self.position += 1;
+                leading_start = self.position;
+            } else {
+                leading_start = value_end;
+                self.skip_trivia()?;
+                break;
+            }
+        }
+        object.tail = Some(leading_start..self.position);
+        self.expect(b'}')?;
+        Ok(JsoncNode {
+            span: Some(start..self.position),
+            kind: NodeKind::Object(object),
+        })
+    }
+}
+
+/// Whether `x` and `y` are equal, taking numbers by their value so that `1.0` is the same as
+/// `1`.
+fn same_value(x: &Value, y: &Value) -> bool {
+    match (x, y) {
+        (Value::Number(x), Value::Number(y)) => {
+            x == y || (x.is_f64() || y.is_f64()) && x.as_f64() == y.as_f64()
+        }
+        (Value::Array(x), Value::Array(y)) => {
+            x.len() == y.len() && x.iter().zip(y).all(|(x, y)| same_value(x, y))
+        }
+        (Value::Object(x), Value::Object(y)) => {
+            x.len() == y.len()
+                && x.iter()
+                    .all(|(key, x)| y.get(key).is_some_and(|y| same_value(x, y)))
+        }
+        (x, y) => x == y,
+    }
+}
+
+/// Removes `//` and `/* */` comments and trailing commas, leaving strings alone.
+fn strip_jsonc(content: &str) -> String {
+    let mut without_comments = String::with_capacity(content.len());
+    let mut chars = content.chars().peekable();
+    let mut in_string = false;
+    while let Some(c) = chars.next() {
+        if in_string {
+            without_comments.push(c);
+            match c {
+                '\\' => without_comments.extend(chars.next()),
+                '"' => in_string = false,
+                _ => {}
+            }
+            continue;
+        }
+        match (c, chars.peek()) {
+            ('"', _) => {
+                in_string = true;
+                without_comments.push(c);
+            }
+            ('/', Some('/')) => {
+                for c in chars.by_ref() {
+                    if c == '\n' {
+                        without_comments.push(c);
+                        break;
+                    }
+                }
+            }
+            ('/', Some('*')) => {
+                chars.next();
+                let mut previous = None;
+                for c in chars.by_ref() {
+                    if previous == Some('*') && c == '/' {
+                        break;
+                    }
+                    previous = Some(c);
+                }
+            }
+            _ => without_comments.push(c),
+        }
+    }
+
+    let mut result = String::with_capacity(without_comments.len());
+    let mut in_string = false;
+    let mut escaped = false;
+    for (index, c) in without_comments.char_indices() {
+        if in_string {
+            match c {
+                _ if escaped => escaped = false,
+                '\\' => escaped = true,
+                '"' => in_string = false,
+                _ => {}
+            }
+        } else if c == '"' {
+            in_string = true;
+        } else if c == ',' {
+            let next = without_comments[index + 1..]
+                .chars()
+                .find(|c| !c.is_whitespace());
+            if matches!(next, Some('}') | Some(']')) {
+                continue;
+            }
+        }
+        result.push(c);
+    }
+    result
+}
+
+/// The indentation of the first indented line, or two spaces.
+fn detect_indent(content: &str) -> String {
+    content
+        .lines()
+        .skip(1)
+        .map(|line| {
+            line.chars()
+                .take_while(|c| *c == ' ' || *c == '\t')
+                .collect::<String>()
+        })
+        .find(|indent| !indent.is_empty())
+        .unwrap_or_else(|| "  ".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dotfile::Dotfile;
+    use serde_json::json;
+
+    #[test]
+    fn deep_merge_into_jsonc() {
+        let settings = Json::new(json!({
+            "files.exclude": { "**/dist-newstyle": true },
+            "editor.formatOnSave": true,
+        }));
+        let old_content = r#"{
+    // added by hand
+    "workbench.colorTheme": "Solarized Dark", /* trailing comma: */
+    "files.exclude": {
+        "**/.yarn/cache": true,
+        "url": "http://example.com//not-a-comment",
+    },
+}
+"#;
+        let new_content = settings.apply(old_content.as_bytes()).unwrap().unwrap();
+        assert_eq!(
+            String::from_utf8(new_content.clone()).unwrap(),
+            r#"{
+    // added by hand
+    "workbench.colorTheme": "Solarized Dark", /* trailing comma: */
+    "files.exclude": {
+        "**/.yarn/cache": true,
+        "url": "http://example.com//not-a-comment",
+        "**/dist-newstyle": true,
+    },
+    "editor.formatOnSave": true,
+}
+"#
+        );
+        assert!(settings.apply(&new_content).unwrap().is_none());
+        assert!(settings.apply(b"").unwrap().is_some());
+    }
+
+    #[test]
+    fn keep_comments() {
+        let old_content = r#"// settings
+{
+    // the theme
+    "workbench.colorTheme": "Solarized Dark",
+    "editor.fontSize": 12, // small
+    "files.exclude": {}
+}
+"#;
+        let new_content =
+            Json::new(json!({ "editor.fontSize": 14, "files.exclude": { "a": true } }))
+                .apply(old_content.as_bytes())
+                .unwrap()
+                .unwrap();
+        assert_eq!(
+            String::from_utf8(new_content.clone()).unwrap(),
+            r#"// settings
+{
+    // the theme
+    "workbench.colorTheme": "Solarized Dark",
+    "editor.fontSize": 14, // small
+    "files.exclude": {
+        "a": true
+    }
+}
+"#
+        );
+
+        let removed = Json::default()
+            .without(vec!["editor.fontSize".to_string()])
+            .without(vec!["files.exclude".to_string()])
+            .apply(&new_content)
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            String::from_utf8(removed).unwrap(),
+            r#"// settings
+{
+    // the theme
+    "workbench.colorTheme": "Solarized Dark"
+}
+"#
+        );
+    }
+
+    #[test]
+    fn keep_equal_values() {
+        let settings = Json::new(json!({ "editor.fontSize": 14, "zoom": [1] }));
+        let old_content =
+            "{\n    // mine\n    \"editor.fontSize\": 14.0,\n    \"zoom\": [1.0]\n}\n";
+        assert!(settings.apply(old_content.as_bytes()).unwrap().is_none());
+        assert!(Json::new(json!({ "zoom": 1.5 }))
+            .apply(b"{ \"zoom\": 1 }")
+            .unwrap()
+            .is_some());
+    }
+
+    #[test]
+    fn merge_targets() {
+        let mut x = Json::new(json!({ "files.exclude": { "a": true }, "x": 1 }));
+        x.merge(Box::new(Json::new(
+            json!({ "files.exclude": { "b": true }, "x": 1 }),
+        )))
+        .unwrap();
+        assert_eq!(
+            x.value(),
+            &json!({ "files.exclude": { "a": true, "b": true }, "x": 1 })
+        );
+
+        let mut x = Json::new(json!({ "files.exclude": { "a": true } }));
+        let conflict = x.merge(Box::new(Json::new(
+            json!({ "files.exclude": { "a": false } }),
+        )));
+        assert!(conflict.is_err());
+    }
+}
@@ -0,0 +1,443 @@
+use super::{
+    apply_utf8, merge_ownership, merge_same_type,
+    parts::{Part, Parts},
+    ApplyError, Dotfile, MergeError, Ownership,
+};
+use base64::Engine;
+use hmac::{Hmac, Mac};
+use sha2::{Digest, Sha256};
+use std::{any::Any, error::Error, fs, os::unix::fs::PermissionsExt};
+
+/// The file a `KeySet` is for.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum KeyFile {
+    /// `[options] keytype key [comment]`. An entry is identified by its key alone, since sshd
+    /// only uses the first line with a key. The options and comment of an entry set here win
+    /// over what is in the file; of the lines the file has for a key, the first is kept.
+    #[default]
+    AuthorizedKeys,
+    /// `[@marker] hostnames keytype key [comment]`. An entry is identified by its marker, key
+    /// and hostnames, which may be hashed in the file.
+    KnownHosts,
+}
+
+/**
+Entries of `~/.ssh/authorized_keys` or `~/.ssh/known_hosts`: https://man.openbsd.org/sshd#AUTHORIZED_KEYS_FILE_FORMAT
+
+Entries set here are added if missing, entries with the given fingerprints are removed, and
+duplicate entries are dropped, keeping the first. Anything else, including comments, is kept. The file is always
+0600 in a 0700 directory, as sshd requires.
+*/
+#[derive(Debug, Default)]
+pub struct KeySet {
+    file: KeyFile,
+    present: Vec<String>,
+    /// Fingerprints, as printed by `ssh-keygen -l`.
+    absent: Vec<String>,
+    ownership: Ownership,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Entry {
+    /// Options for authorized_keys, or the marker and hostnames for known_hosts.
+    prefix: Vec<String>,
+    fingerprint: String,
+}
+
+impl KeySet {
+    pub fn authorized_keys() -> Self {
+        Self::default()
+    }
+
+    pub fn known_hosts() -> Self {
+        Self {
+            file: KeyFile::KnownHosts,
+            ..Default::default()
+        }
+    }
+
+    /// A line in the format of the file. It is only parsed when applied.
+    pub fn with_key(mut self, line: &str) -> Self {
+        let line = line.trim().to_string();
+        if !self.present.contains(&line) {
+            self.present.push(line);
+        }
+        self
+    }
+
+    /// Removes every entry with a key that has the fingerprint `SHA256:...`.
+    pub fn without_fingerprint(mut self, fingerprint: &str) -> Self {
+        if !self.absent.iter().any(|absent| absent == fingerprint) {
+            self.absent.push(fingerprint.to_string());
+        }
+        self
+    }
+
+    pub fn with_ownership(self, ownership: Ownership) -> Self {
+        Self { ownership, ..self }
+    }
+
+    fn parse(&self, line: &str) -> Option<Entry> {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            return None;
+        }
+        let fields = split_fields(line);
+        match self.file {
+            KeyFile::AuthorizedKeys => {
+                // the options are optional, so the key is either at 0 or at 1
+                (0..2).find_map(|start| {
+                    let fingerprint = fingerprint(fields.get(start)?, fields.get(start + 1)?)?;
+                    let prefix = fields[..start].iter().map(|s| s.to_string()).collect();
+                    Some(Entry {
+                        prefix,
+                        fingerprint,
+                    })
+                })
+            }
+            KeyFile::KnownHosts => {
+                let start = if fields.first()?.starts_with('@') {
+                    2
+                } else {
+                    1
+                };
+                let fingerprint = fingerprint(fields.get(start)?, fields.get(start + 1)?)?;
+                let prefix = fields[..start].iter().map(|s| s.to_string()).collect();
+                Some(Entry {
+                    prefix,
+                    fingerprint,
+                })
+            }
+        }
+    }
+
+    /// Whether `x` and `y` are the same entry, so that only one of them is kept.
+    fn same_entry(&self, x: &Entry, y: &Entry) -> bool {
+        match self.file {
+            KeyFile::AuthorizedKeys => x.fingerprint == y.fingerprint,
+            KeyFile::KnownHosts => x == y,
+        }
+    }
+
+    /// Whether `existing`, from the file, already covers `wanted`.
+    fn covers(&self, existing: &Entry, wanted: &Entry) -> bool {
+        if existing.fingerprint != wanted.fingerprint {
+            return false;
+        }
+        match self.file {
+            KeyFile::AuthorizedKeys => true,
+            KeyFile::KnownHosts => {
+                let (existing_hosts, wanted_hosts) =
+                    match (existing.prefix.as_slice(), wanted.prefix.as_slice()) {
+                        ([existing_hosts], [wanted_hosts]) => (existing_hosts, wanted_hosts),
+                        ([existing_marker, existing_hosts], [wanted_marker, wanted_hosts])
+                            if existing_marker == wanted_marker =>
+                        {
+                            (existing_hosts, wanted_hosts)
+                        }
+                        _ => return false,
+                    };
+                wanted_hosts
+                    .split(',')
+                    .all(|host| hosts_match(existing_hosts, host))
+            }
+        }
+    }
+}
+
+/// Splits on whitespace that isn't inside double quotes, as in `command="a b",no-pty`.
+fn split_fields(line: &str) -> Vec<&str> {
+    let mut fields = Vec::new();
+    let mut start = None;
+    let mut quoted = false;
+    for (index, c) in line.char_indices() {
+        match c {
+            '"' => quoted = !quoted,
+            c if c.is_whitespace() && !quoted => {
+                if let Some(start) = start.take() {
+                    fields.push(&line[start..index]);
+                }
+                continue;
+            }
+            _ => {}
+        }
+        start.get_or_insert(index);
+    }
+    fields.extend(start.map(|start| &line[start..]));
+    fields
+}
+
+/// `SHA256:...` of a public key, or `None` if `key` isn't a key of type `key_type`.
+fn fingerprint(key_type: &str, key: &str) -> Option<String> {
+    let blob = base64::engine::general_purpose::STANDARD.decode(key).ok()?;
+    // the blob starts with its own type, as a length-prefixed string
+    let length = u32::from_be_bytes(blob.get(..4)?.try_into().ok()?) as usize;
+    if blob.get(4..4 + length)? != key_type.as_bytes() {
+        return None;
+    }
+    Some(format!(
+        "SHA256:{}",
+        base64::engine::general_purpose::STANDARD_NO_PAD.encode(Sha256::digest(&blob))
+    ))
+}
+
+/// Whether `host` is in the comma-separated `hosts`, which may be hashed
+/// (`|1|<salt>|<HMAC-SHA1 of the host>`).
+fn hosts_match(hosts: &str, host: &str) -> bool {
+    hosts.split(',').any(|pattern| {
+        let Some(hashed) = pattern.strip_prefix("|1|") else {
+            return pattern == host;
+        };
+        let engine = base64::engine::general_purpose::STANDARD;
+        let Some((Ok(salt), Ok(hash))) = hashed
+            .split_once('|')
+            .map(|(salt, hash)| (engine.decode(salt), engine.decode(hash)))
+        else {
+            return false;
+        };
+        let Ok(mut mac) = Hmac::<sha1::Sha1>::new_from_slice(&salt) else {
+            return false;
+        };
+        mac.update(host.as_bytes());
+        mac.verify_slice(&hash).is_ok()
+    })
+}
+
+impl Dotfile for KeySet {
+    fn apply(&self, old_content: &[u8]) -> Result<Option<Vec<u8>>, ApplyError> {
+        apply_utf8(
+            old_content,
+            |old_content: String| -> Result<Option<String>, Box<dyn Error + Send + Sync>> {
+                let wanted = self
+                    .present
+                    .iter()
+                    .map(|line| {
+                        self.parse(line)
+                            .map(|entry| (line, entry))
+                            .ok_or_else(|| format!("not a valid key entry: {:?}", line))
+                    })
+                    .collect::<Result<Vec<_>, _>>()?;
+
+                let mut lines: Vec<String> = Vec::new();
+                let mut entries: Vec<Option<Entry>> = Vec::new();
+                for line in old_content.lines() {
+                    let entry = self.parse(line);
+                    if let Some(entry) = &entry {
+                        let removed = self.absent.contains(&entry.fingerprint);
+                        let duplicate = entries.iter().flatten().any(|e| self.same_entry(e, entry));
+                        if removed || duplicate {
+                            continue;
+                        }
+                    }
+                    lines.push(line.to_string());
+                    entries.push(entry);
+                }
+                for (line, entry) in wanted {
+                    let existing = entries
+                        .iter()
+                        .position(|e| e.as_ref().is_some_and(|e| self.covers(e, &entry)));
+                    match existing {
+                        // the options and comment set here win
+                        Some(index) if self.file == KeyFile::AuthorizedKeys => {
+                            lines[index] = line.clone()
+                        }
+                        Some(_) => {}
+                        None => {
+                            lines.push(line.clone());
+                            entries.push(Some(entry));
+                        }
+                    }
+                }
+
+                let mut new_content = lines.join("\n");
+                if !new_content.is_empty() {
+                    new_content.push('\n');
+                }
+                if new_content == old_content {
+                    Ok(None)
+                } else {
+                    Ok(Some(new_content))
+                }
+            },
+        )
+    }
+
+    fn file_permission(&self) -> fs::Permissions {
+        fs::Permissions::from_mode(0o600)
+    }
+
+    fn directory_permission(&self) -> Option<fs::Permissions> {
+        Some(fs::Permissions::from_mode(0o700))
+    }
+
+    fn ownership(&self) -> Ownership {
+        self.ownership
+    }
+
+    /// The lines of the entries set here, which are written as they are. An entry the file
+    /// already covered keeps its own line, and so isn't removed with them.
+    fn parts(&self) -> Option<Parts> {
+        Some(Parts::new(
+            self.present
+                .iter()
+                .map(|line| Part::Line { line: line.clone() }),
+        ))
+    }
+
+    fn merge(&mut self, y: Box<dyn Dotfile>) -> Result<(), MergeError> {
+        merge_same_type(self, y, |x, y| {
+            if x.file != y.file {
+                return Err(MergeError::MergeConflict {
+                    reason: "authorized_keys and known_hosts entries for the same file".to_string(),
+                });
+            }
+            let ownership = merge_ownership(x.ownership, y.ownership)?;
+            let mut merged = y
+                .present
+                .iter()
+                .fold(x, |merged, line| merged.with_key(line));
+            for fingerprint in y.absent.iter() {
+                merged = merged.without_fingerprint(fingerprint);
+            }
+            let entries: Vec<Entry> = merged
+                .present
+                .iter()
+                .filter_map(|line| merged.parse(line))
+                .collect();
+            if let Some(entry) = entries
+                .iter()
+                .find(|entry| merged.absent.contains(&entry.fingerprint))
+            {
+                return Err(MergeError::MergeConflict {
+                    reason: format!("key {} is both added and removed", entry.fingerprint),
+                });
+            }
+            // two lines for one key would leave which options win to the order of the targets
+            for (index, entry) in entries.iter().enumerate() {
+                if entries[..index].iter().any(|e| merged.same_entry(e, entry)) {
+                    return Err(MergeError::MergeConflict {
+                        reason: format!("key {} is added with different lines", entry.fingerprint),
+                    });
+                }
+            }
+            Ok(KeySet {
+                ownership,
+                ..merged
+            })
+        })
+    }
+
+    fn as_any(self: Box<Self>) -> Box<dyn Any> {
+        Box::new(*self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dotfile::{apply_dotfiles, ApplyOptions};
+    use std::{collections::HashMap, path::PathBuf};
+
+    const ALICE: &str =
+        "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIDMw2jMd8VrGwYd1tRCQwOqIw8zmIcmEVd9kPRjxyE4u";
+    const ALICE_FINGERPRINT: &str = "SHA256:jV51rSVKTXryC6qnEw8j19MjgnKqv9gQ1/2MRkvdmA0";
+    const BOB: &str =
+        "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIHMsW1qa8OtgdAbwl4x82QnIjGpXRGdtEhqQC52gQLhw";
+
+    fn apply(key_set: &KeySet, old_content: &str) -> Option<String> {
+        key_set
+            .apply(old_content.as_bytes())
+            .unwrap()
+            .map(|new_content| String::from_utf8(new_content).unwrap())
+    }
+
+    #[test]
+    fn authorized_keys() {
+        assert_eq!(
+            fingerprint("ssh-ed25519", ALICE.split(' ').nth(1).unwrap()).as_deref(),
+            Some(ALICE_FINGERPRINT)
+        );
+        let key_set = KeySet::authorized_keys()
+            .with_key(&format!("{} bob@team", BOB))
+            .without_fingerprint(ALICE_FINGERPRINT);
+        let old_content = format!(
+            "# mine\ncommand=\"echo a b\",no-pty {} alice\n{} bob@laptop\n{} bob@laptop\n",
+            ALICE, BOB, BOB
+        );
+        assert_eq!(
+            apply(&key_set, &old_content).unwrap(),
+            format!("# mine\n{} bob@team\n", BOB)
+        );
+        assert!(KeySet::authorized_keys()
+            .with_key("ssh-ed25519 not-a-key")
+            .apply(b"")
+            .is_err());
+    }
+
+    #[test]
+    fn authorized_keys_are_identified_by_fingerprint() {
+        let old_content = format!(
+            "{} bob@laptop
+no-pty {} bob@desktop
+{} alice
+restrict {} alice@old
+",
+            BOB, BOB, ALICE, ALICE
+        );
+        let key_set = KeySet::authorized_keys().with_key(&format!("no-pty {} alice", ALICE));
+        assert_eq!(
+            apply(&key_set, &old_content).unwrap(),
+            format!(
+                "{} bob@laptop
+no-pty {} alice
+",
+                BOB, ALICE
+            )
+        );
+
+        let mut x = KeySet::authorized_keys().with_key(&format!("{} alice", ALICE));
+        assert!(x
+            .merge(Box::new(
+                KeySet::authorized_keys().with_key(&format!("no-pty {} alice", ALICE))
+            ))
+            .is_err());
+    }
+
+    #[test]
+    fn known_hosts_with_hashed_hostnames() {
+        // `ssh-keygen -H` of "git.internal"
+        let hashed = format!(
+            "|1|ygnKZrjDgpchsLuC+Vycrx0J+74=|twJhcn/y3XG4FO2BKXTDonknpls= {}",
+            ALICE
+        );
+        let key_set = KeySet::known_hosts()
+            .with_key(&format!("git.internal {}", ALICE))
+            .with_key(&format!("@cert-authority *.internal {}", BOB));
+        assert_eq!(
+            apply(&key_set, &format!("{}\n", hashed)).unwrap(),
+            format!("{}\n@cert-authority *.internal {}\n", hashed, BOB)
+        );
+        assert_eq!(
+            apply(&KeySet::known_hosts(), &format!("other {}\n", ALICE)),
+            None
+        );
+    }
+
+    #[test]
+    fn private_file_and_directory() {
+        let dir = tempfile::tempdir().unwrap();
+        let ssh_dir = dir.path().join(".ssh");
+        fs::create_dir(&ssh_dir).unwrap();
+        fs::set_permissions(&ssh_dir, fs::Permissions::from_mode(0o755)).unwrap();
+        let path = ssh_dir.join("authorized_keys");
+        let dotfiles: HashMap<PathBuf, Box<dyn Dotfile>> = HashMap::from([(
+            path.clone(),
+            Box::new(KeySet::authorized_keys().with_key(ALICE)) as Box<dyn Dotfile>,
+        )]);
+        let report = apply_dotfiles(dotfiles, &ApplyOptions::new());
+        assert!(report.errors.is_empty());
+        let mode = |path: &PathBuf| fs::metadata(path).unwrap().permissions().mode() & 0o777;
+        assert_eq!(mode(&path), 0o600);
+        assert_eq!(mode(&ssh_dir), 0o700);
+    }
+}
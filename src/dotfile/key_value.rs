@@ -0,0 +1,721 @@
+use super::{
+    apply_utf8, merge_ownership, merge_same_type,
+    parts::{Part, Parts},
+    ApplyError, Dotfile, MergeError, Ownership,
+};
+use std::{any::Any, error::Error, fmt, fs, os::unix::fs::PermissionsExt};
+
+/// How the value of a setting is parsed, rendered and merged.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+    /// Space-separated values. Merging takes the union: the values already there keep their
+    /// order, and new ones are placed by the `ListOrder`.
+    List(&'static ListOrder),
+    /// A single string. Different values are a conflict.
+    Scalar,
+    /// `true` or `false`. Different values are a conflict.
+    Boolean,
+    /// Different values are a conflict.
+    Integer,
+}
+
+impl Kind {
+    /// Whether `value` is of this kind.
+    fn accepts(&self, value: &Value) -> bool {
+        matches!(
+            (self, value),
+            (Kind::List(_), Value::List(_))
+                | (Kind::Scalar, Value::Scalar(_))
+                | (Kind::Boolean, Value::Boolean(_))
+                | (Kind::Integer, Value::Integer(_))
+        )
+    }
+
+    /// Whether two rendered values are the same item, so that one replaces the other.
+    fn same_item(&self, x: &str, y: &str) -> bool {
+        match self {
+            Kind::List(order) => (order.key)(x) == (order.key)(y),
+            _ => x == y,
+        }
+    }
+}
+
+/// How the items of a list are matched and ordered.
+#[derive(Debug)]
+pub struct ListOrder {
+    /// The part of an item that identifies it. An item replaces the one with the same key in
+    /// place.
+    pub key: fn(&str) -> &str,
+    /// Where a new item goes among `items`.
+    pub position: fn(items: &[String], item: &str) -> usize,
+}
+
+/// Orders are statics, and two of them are the same if they are the same static.
+impl PartialEq for ListOrder {
+    fn eq(&self, other: &Self) -> bool {
+        std::ptr::eq(self, other)
+    }
+}
+
+impl Eq for ListOrder {}
+
+/// Items are matched whole, and new ones go at the end in the order they were set in.
+pub static APPEND: ListOrder = ListOrder {
+    key: whole_item,
+    position: at_end,
+};
+
+fn whole_item(item: &str) -> &str {
+    item
+}
+
+fn at_end(items: &[String], _item: &str) -> usize {
+    items.len()
+}
+
+/// The settings a `name = value` file knows about, and the kind of each.
+#[derive(Debug)]
+pub struct Schema {
+    pub settings: &'static [(&'static str, Kind)],
+}
+
+impl Schema {
+    pub fn kind(&self, name: &str) -> Option<Kind> {
+        self.settings
+            .iter()
+            .find(|(setting, _)| *setting == name)
+            .map(|(_, kind)| *kind)
+    }
+}
+
+static EMPTY_SCHEMA: Schema = Schema { settings: &[] };
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Value {
+    List(Vec<String>),
+    Scalar(String),
+    Boolean(bool),
+    Integer(i64),
+}
+
+impl Value {
+    pub fn kind(&self) -> Kind {
+        match self {
+            Value::List(_) => Kind::List(&APPEND),
+            Value::Scalar(_) => Kind::Scalar,
+            Value::Boolean(_) => Kind::Boolean,
+            Value::Integer(_) => Kind::Integer,
+        }
+    }
+
+    fn parse(kind: Kind, value: &str) -> Result<Self, Box<dyn Error + Send + Sync>> {
+        Ok(match kind {
+            Kind::List(_) => Value::List(value.split_whitespace().map(String::from).collect()),
+            Kind::Scalar => Value::Scalar(value.to_string()),
+            Kind::Boolean => Value::Boolean(match value.trim() {
+                "true" => true,
+                "false" => false,
+                other => return Err(format!("{:?} is not a boolean", other).into()),
+            }),
+            Kind::Integer => Value::Integer(value.trim().parse()?),
+        })
+    }
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Value::List(values) => write!(f, "{}", values.join(" ")),
+            Value::Scalar(value) => write!(f, "{}", value),
+            Value::Boolean(value) => write!(f, "{}", value),
+            Value::Integer(value) => write!(f, "{}", value),
+        }
+    }
+}
+
+/// What to do with the values of a setting.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    /// The file has these values, and keeps any others it has. For a setting that isn't a list,
+    /// a different value in the file is an error.
+    Present,
+    /// The file doesn't have these values. A list that loses all its values loses its line.
+    Absent,
+    /// The file has exactly this value.
+    Exact,
+}
+
+/// Everything that is wanted of one setting.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Setting {
+    kind: Kind,
+    /// The value the file has, if `exact`; otherwise the values it has at least. If `exact` and
+    /// `None`, the setting's line is deleted.
+    present: Option<Value>,
+    /// The rendered values the file doesn't have: the items of a list, or a whole value.
+    absent: Vec<String>,
+    exact: bool,
+}
+
+impl Value {
+    /// The items of a list, or the whole value of anything else.
+    fn items(&self) -> Vec<String> {
+        match self {
+            Value::List(values) => values.clone(),
+            value => vec![value.to_string()],
+        }
+    }
+
+    /// `None` if every value is in `absent`.
+    fn without(self, kind: &Kind, absent: &[String]) -> Option<Value> {
+        match self {
+            Value::List(mut values) => {
+                if values.is_empty() {
+                    return Some(Value::List(values));
+                }
+                values.retain(|value| !absent.iter().any(|item| kind.same_item(value, item)));
+                (!values.is_empty()).then_some(Value::List(values))
+            }
+            value => (!absent.contains(&value.to_string())).then_some(value),
+        }
+    }
+}
+
+/**
+A file of `name = value` lines, like `nix.conf`. Only the settings set here are touched; how each
+is combined with what the file already has depends on its `Mode` and `Kind`. A setting the file
+doesn't have is appended.
+
+Settings the schema doesn't know take the kind of the value they are set to.
+*/
+#[derive(Debug, Clone)]
+pub struct KeyValueConf {
+    schema: &'static Schema,
+    /// In the order they were set, which is the order new lines are appended in.
+    settings: Vec<(String, Setting)>,
+    permissions: fs::Permissions,
+    ownership: Ownership,
+}
+
+impl Default for KeyValueConf {
+    fn default() -> Self {
+        Self::new(&EMPTY_SCHEMA)
+    }
+}
+
+impl KeyValueConf {
+    pub fn new(schema: &'static Schema) -> Self {
+        Self {
+            schema,
+            settings: Vec::new(),
+            permissions: fs::Permissions::from_mode(0o644),
+            ownership: Ownership::default(),
+        }
+    }
+
+    /// Sets `name` to `value` in `Mode::Present`.
+    pub fn with(self, name: &str, value: Value) -> Self {
+        self.with_mode(name, Mode::Present, value)
+    }
+
+    pub fn with_mode(mut self, name: &str, mode: Mode, value: Value) -> Self {
+        let setting = self.setting(name, value.kind());
+        match mode {
+            Mode::Present => setting.present = Some(value),
+            Mode::Absent => setting.absent.extend(value.items()),
+            Mode::Exact => {
+                setting.present = Some(value);
+                setting.exact = true;
+            }
+        }
+        self
+    }
+
+    /// Deletes the line of `name`.
+    pub fn without(mut self, name: &str) -> Self {
+        let setting = self.setting(name, Kind::Scalar);
+        setting.present = None;
+        setting.exact = true;
+        self
+    }
+
+    pub fn with_list(self, name: &str, values: Vec<String>) -> Self {
+        self.with(name, Value::List(values))
+    }
+
+    pub fn with_scalar(self, name: &str, value: String) -> Self {
+        self.with(name, Value::Scalar(value))
+    }
+
+    pub fn with_bool(self, name: &str, value: bool) -> Self {
+        self.with(name, Value::Boolean(value))
+    }
+
+    pub fn with_integer(self, name: &str, value: i64) -> Self {
+        self.with(name, Value::Integer(value))
+    }
+
+    pub fn with_permissions(self, permissions: fs::Permissions) -> Self {
+        Self {
+            permissions,
+            ..self
+        }
+    }
+
+    pub fn with_ownership(self, ownership: Ownership) -> Self {
+        Self { ownership, ..self }
+    }
+
+    /// The names of the settings set here.
+    pub(crate) fn names(&self) -> Vec<String> {
+        self.settings.iter().map(|(name, _)| name.clone()).collect()
+    }
+
+    /// Whether `name` adds values to a list, rather than setting or removing them.
+    pub(crate) fn adds_to_list(&self, name: &str) -> bool {
+        self.settings.iter().any(|(setting, value)| {
+            setting == name
+                && !value.exact
+                && matches!(value.present, Some(Value::List(ref values)) if !values.is_empty())
+        })
+    }
+
+    /// Stops adding to `name` the values that are in `values`, since they are there already.
+    pub(crate) fn drop_present(&mut self, name: &str, values: &[String]) {
+        for (_, setting) in self
+            .settings
+            .iter_mut()
+            .filter(|(setting, _)| setting == name)
+        {
+            if let Some(Value::List(present)) = &mut setting.present {
+                present.retain(|value| !values.contains(value));
+                if present.is_empty() && !setting.exact {
+                    setting.present = None;
+                }
+            }
+        }
+    }
+
+    /// Sets what is set for `name` for `new_name` instead.
+    pub(crate) fn rename(&mut self, name: &str, new_name: &str) -> Result<(), MergeError> {
+        let Some(index) = self
+            .settings
+            .iter()
+            .position(|(setting, _)| setting == name)
+        else {
+            return Ok(());
+        };
+        let (_, mut setting) = self.settings.remove(index);
+        setting.kind = self.schema.kind(new_name).unwrap_or(setting.kind);
+        match self
+            .settings
+            .iter_mut()
+            .find(|(existing, _)| existing == new_name)
+        {
+            Some((_, existing)) => *existing = merge_setting(new_name, existing.clone(), setting)?,
+            None => self.settings.insert(index, (new_name.to_string(), setting)),
+        }
+        Ok(())
+    }
+
+    /// The setting of `name`, added with the schema's kind, or else `kind`, if it isn't there.
+    fn setting(&mut self, name: &str, kind: Kind) -> &mut Setting {
+        let index = match self
+            .settings
+            .iter()
+            .position(|(setting, _)| setting == name)
+        {
+            Some(index) => index,
+            None => {
+                self.settings.push((
+                    name.to_string(),
+                    Setting {
+                        kind: self.schema.kind(name).unwrap_or(kind),
+                        present: None,
+                        absent: Vec::new(),
+                        exact: false,
+                    },
+                ));
+                self.settings.len() - 1
+            }
+        };
+        &mut self.settings[index].1
+    }
+}
+
+/// Combines two values of a setting according to their kind. The items of `y` go into list `x`
+/// as the kind's `ListOrder` says; an item of `y` that has the same key as a different item of
+/// `x` replaces it if `replace`, and is a conflict otherwise.
+fn merge_value(
+    name: &str,
+    kind: &Kind,
+    x: Value,
+    y: Value,
+    replace: bool,
+) -> Result<Value, MergeError> {
+    let conflict = || MergeError::MergeConflict {
+        reason: format!("Conflicting values set for {}", name),
+    };
+    match (x, y) {
+        (Value::List(mut x), Value::List(y)) => {
+            let order = match kind {
+                Kind::List(order) => order,
+                _ => &APPEND,
+            };
+            for item in y {
+                match x
+                    .iter()
+                    .position(|existing| kind.same_item(existing, &item))
+                {
+                    Some(index) if x[index] == item => {}
+                    Some(index) if replace => x[index] = item,
+                    Some(_) => return Err(conflict()),
+                    None => {
+                        let index = (order.position)(&x, &item);
+                        x.insert(index, item);
+                    }
+                }
+            }
+            Ok(Value::List(x))
+        }
+        (x, y) if x == y => Ok(x),
+        _ => Err(conflict()),
+    }
+}
+
+/// Combines what two targets want of a setting. An exact value must include whatever the other
+/// wants present, and no value may be both present and absent.
+fn merge_setting(name: &str, x: Setting, y: Setting) -> Result<Setting, MergeError> {
+    let conflict = |reason: &str| MergeError::MergeConflict {
+        reason: format!("{} for {}", reason, name),
+    };
+    if x.kind != y.kind {
+        return Err(conflict("Conflicting kinds set"));
+    }
+    let kind = x.kind;
+    let covers = |exact: &Option<Value>, other: &Option<Value>| match other {
+        None => true,
+        Some(other) => exact.as_ref().is_some_and(|exact| {
+            let items = exact.items();
+            other.items().iter().all(|item| items.contains(item))
+        }),
+    };
+    let present = match (x.exact, y.exact) {
+        (true, true) if x.present != y.present => {
+            return Err(conflict("Conflicting exact values set"))
+        }
+        (true, false) if !covers(&x.present, &y.present) => {
+            return Err(conflict("Conflicting values set"))
+        }
+        (false, true) if !covers(&y.present, &x.present) => {
+            return Err(conflict("Conflicting values set"))
+        }
+        (true, _) => x.present,
+        (false, true) => y.present,
+        (false, false) => match (x.present, y.present) {
+            (Some(x), Some(y)) => Some(merge_value(name, &kind, x, y, false)?),
+            (x, y) => x.or(y),
+        },
+    };
+    let mut absent = x.absent;
+    for item in y.absent {
+        if !absent.contains(&item) {
+            absent.push(item);
+        }
+    }
+    if present.as_ref().is_some_and(|present| {
+        present
+            .items()
+            .iter()
+            .any(|item| absent.iter().any(|absent| kind.same_item(item, absent)))
+    }) {
+        return Err(conflict("Values set both present and absent"));
+    }
+    Ok(Setting {
+        kind,
+        present,
+        absent,
+        exact: x.exact || y.exact,
+    })
+}
+
+/// A `name = value` line, split so that it can be rewritten keeping its indentation and comment.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) struct Assignment<'a> {
+    indent: &'a str,
+    pub(crate) name: &'a str,
+    pub(crate) value: &'a str,
+    /// From the end of the value, including the whitespace before the `#`.
+    comment: &'a str,
+}
+
+/// Like Nix, allows any whitespace around the `=` and takes everything from a `#` on as a
+/// comment. `None` for blank lines, comments and lines that aren't assignments, like `include`.
+pub(crate) fn lex(line: &str) -> Option<Assignment<'_>> {
+    let code = line.find('#').map_or(line, |hash| &line[..hash]);
+    let code = code.trim_end();
+    let body = code.trim_start();
+    let (name, value) = body.split_once('=')?;
+    let name = name.trim();
+    if name.is_empty() || name.contains(char::is_whitespace) {
+        return None;
+    }
+    Some(Assignment {
+        indent: &code[..code.len() - body.len()],
+        name,
+        value: value.trim(),
+        comment: &line[code.len()..],
+    })
+}
+
+/// Writes `setting` into `lines`. If the setting is on several lines, the last one is the one
+/// that counts, as it does for Nix, and the others are removed.
+fn apply_setting(
+    lines: &mut Vec<String>,
+    name: &str,
+    setting: &Setting,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    if let Some(present) = &setting.present {
+        if !setting.kind.accepts(present) {
+            return Err(format!(
+                "{} is a {:?} setting, but was set to a {:?}",
+                name,
+                setting.kind,
+                present.kind()
+            )
+            .into());
+        }
+    }
+    let indices: Vec<usize> = (0..lines.len())
+        .filter(|index| lex(&lines[*index]).is_some_and(|line| line.name == name))
+        .collect();
+    let old_value = match indices.last() {
+        Some(index) => Some(Value::parse(
+            setting.kind,
+            lex(&lines[*index]).map_or("", |line| line.value),
+        )?),
+        None => None,
+    };
+    let existing = old_value
+        .clone()
+        .and_then(|value| value.without(&setting.kind, &setting.absent));
+    let value = if setting.exact {
+        setting.present.clone()
+    } else {
+        match (existing, setting.present.clone()) {
+            (Some(existing), Some(present)) => {
+                Some(merge_value(name, &setting.kind, existing, present, true)?)
+            }
+            (existing, present) => existing.or(present),
+        }
+    };
+    let duplicates = match (value, indices.split_last()) {
+        (None, _) => &indices[..],
+        (Some(Value::List(values)), None) if values.is_empty() && !setting.exact => &[],
+        (Some(value), None) => {
+            lines.push(format!("{} = {}", name, value));
+            &[]
+        }
+        (Some(value), Some((last, duplicates))) => {
+            if old_value.as_ref() != Some(&value) {
+                let line = lex(&lines[*last]).expect("an assignment");
+                lines[*last] = format!("{}{} = {}{}", line.indent, name, value, line.comment);
+            }
+            duplicates
+        }
+    };
+    for index in duplicates.iter().rev() {
+        lines.remove(*index);
+    }
+    Ok(())
+}
+
+impl Dotfile for KeyValueConf {
+    fn apply(&self, old_content: &[u8]) -> Result<Option<Vec<u8>>, ApplyError> {
+        apply_utf8(
+            old_content,
+            |old_content: String| -> Result<Option<String>, Box<dyn Error + Send + Sync>> {
+                let mut lines: Vec<String> = old_content.lines().map(String::from).collect();
+                for (name, setting) in self.settings.iter() {
+                    apply_setting(&mut lines, name, setting)?;
+                }
+                let mut new_content = lines.join("\n");
+                // a new file gets a final newline, and an existing one keeps whatever it had
+                if !lines.is_empty() && (old_content.is_empty() || old_content.ends_with('\n')) {
+                    new_content.push('\n');
+                }
+                if old_content == new_content {
+                    Ok(None)
+                } else {
+                    Ok(Some(new_content))
+                }
+            },
+        )
+    }
+
+    fn file_permission(&self) -> fs::Permissions {
+        self.permissions.clone()
+    }
+
+    fn ownership(&self) -> Ownership {
+        self.ownership
+    }
+
+    fn merge(&mut self, y: Box<dyn Dotfile>) -> Result<(), MergeError> {
+        merge_same_type(self, y, |mut x, y| {
+            for (name, setting) in y.settings {
+                match x
+                    .settings
+                    .iter_mut()
+                    .find(|(existing, _)| *existing == name)
+                {
+                    Some((_, existing)) => {
+                        *existing = merge_setting(&name, existing.clone(), setting)?;
+                    }
+                    None => x.settings.push((name, setting)),
+                }
+            }
+            Ok(KeyValueConf {
+                ownership: merge_ownership(x.ownership, y.ownership)?,
+                ..x
+            })
+        })
+    }
+
+    /// The values set here, each item on its own for a list. Removed and deleted settings own
+    /// nothing.
+    fn parts(&self) -> Option<Parts> {
+        Some(Parts::new(self.settings.iter().flat_map(
+            |(name, setting)| {
+                let list = matches!(setting.present, Some(Value::List(_)));
+                setting
+                    .present
+                    .iter()
+                    .flat_map(Value::items)
+                    .map(move |value| Part::Setting {
+                        name: name.clone(),
+                        value,
+                        list,
+                    })
+            },
+        )))
+    }
+
+    fn as_any(self: Box<Self>) -> Box<dyn Any> {
+        Box::new(*self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    static SCHEMA: Schema = Schema {
+        settings: &[("jobs", Kind::Integer), ("features", Kind::List(&APPEND))],
+    };
+
+    fn apply(conf: &KeyValueConf, old_content: &str) -> Result<Option<String>, ApplyError> {
+        conf.apply(old_content.as_bytes())
+            .map(|new_content| new_content.map(|x| String::from_utf8(x).unwrap()))
+    }
+
+    fn list(values: &[&str]) -> Value {
+        Value::List(values.iter().map(|value| value.to_string()).collect())
+    }
+
+    #[test]
+    fn kinds() {
+        let conf = KeyValueConf::new(&SCHEMA)
+            .with_integer("jobs", 8)
+            .with_list("features", vec!["b".to_string()])
+            .with_bool("sandbox", true);
+        assert_eq!(
+            apply(&conf, "jobs = 8\nfeatures = a").unwrap().unwrap(),
+            "jobs = 8\nfeatures = a b\nsandbox = true"
+        );
+        assert!(apply(&conf, "jobs = 4").is_err());
+        assert!(apply(&conf, "jobs = many").is_err());
+        let wrong_kind = KeyValueConf::new(&SCHEMA).with_scalar("jobs", "8".to_string());
+        assert!(apply(&wrong_kind, "").is_err());
+    }
+
+    #[test]
+    fn lexer() {
+        assert_eq!(
+            lex("  jobs=4  # laptop"),
+            Some(Assignment {
+                indent: "  ",
+                name: "jobs",
+                value: "4",
+                comment: "  # laptop",
+            })
+        );
+        assert_eq!(lex("# jobs = 4"), None);
+        assert_eq!(lex("include /etc/nix/extra.conf"), None);
+
+        let conf = KeyValueConf::new(&SCHEMA)
+            .with_mode("jobs", Mode::Exact, Value::Integer(8))
+            .with_list("features", vec!["b".to_string()]);
+        let old_content =
+            "  jobs=4  # laptop\nfeatures  =  a # ours\n\tjobs = 6 # desktop\n# end\n";
+        let new_content = apply(&conf, old_content).unwrap().unwrap();
+        assert_eq!(
+            new_content,
+            "features = a b # ours\n\tjobs = 8 # desktop\n# end\n"
+        );
+        assert_eq!(apply(&conf, &new_content).unwrap(), None);
+        assert_eq!(apply(&conf, "jobs=8\nfeatures=a b").unwrap(), None);
+        assert_eq!(
+            apply(&conf, "").unwrap().unwrap(),
+            "jobs = 8\nfeatures = b\n"
+        );
+    }
+
+    #[test]
+    fn modes() {
+        let conf = KeyValueConf::new(&SCHEMA)
+            .with_mode("features", Mode::Absent, list(&["a"]))
+            .with_mode("hook", Mode::Exact, Value::Scalar("/new".to_string()))
+            .without("jobs");
+        assert_eq!(
+            apply(&conf, "jobs = 4\nfeatures = a b\nhook = /old")
+                .unwrap()
+                .unwrap(),
+            "features = b\nhook = /new"
+        );
+        assert_eq!(
+            apply(&conf, "features = a").unwrap().unwrap(),
+            "hook = /new"
+        );
+        // an empty list is kept, since it means something different from no line
+        assert_eq!(apply(&conf, "features = \nhook = /new").unwrap(), None);
+    }
+
+    #[test]
+    fn merge() {
+        let mut x = KeyValueConf::new(&SCHEMA)
+            .with_list("features", vec!["a".to_string()])
+            .with_integer("jobs", 8);
+        let y = KeyValueConf::new(&SCHEMA)
+            .with_list("features", vec!["b".to_string()])
+            .with_mode("features", Mode::Absent, list(&["c"]))
+            .with_integer("jobs", 8);
+        x.merge(Box::new(y)).unwrap();
+        assert_eq!(
+            apply(&x, "features = c d").unwrap().unwrap(),
+            "features = d a b\njobs = 8"
+        );
+        let z = KeyValueConf::new(&SCHEMA).with_integer("jobs", 4);
+        assert!(x.merge(Box::new(z)).is_err());
+
+        let mut exact =
+            KeyValueConf::new(&SCHEMA).with_mode("features", Mode::Exact, list(&["a", "b"]));
+        let present = KeyValueConf::new(&SCHEMA).with_list("features", vec!["a".to_string()]);
+        exact.merge(Box::new(present)).unwrap();
+        let absent = KeyValueConf::new(&SCHEMA).with_mode("features", Mode::Absent, list(&["b"]));
+        assert!(exact.merge(Box::new(absent)).is_err());
+        let mut deleted = KeyValueConf::new(&SCHEMA).without("jobs");
+        let jobs = KeyValueConf::new(&SCHEMA).with_integer("jobs", 8);
+        assert!(deleted.merge(Box::new(jobs)).is_err());
+    }
+}
@@ -0,0 +1,207 @@
+use super::{
+    apply_utf8, merge_ownership, merge_same_type,
+    parts::{Part, Parts},
+    ApplyError, Dotfile, MergeError, Ownership,
+};
+use std::{any::Any, error::Error, fs, os::unix::fs::PermissionsExt};
+
+/// A file of lines, like a gitignore. Makes sure some lines are present and others are absent,
+/// and leaves every other line where it is. A missing line goes right after the line before it
+/// in `present`, so their relative order, which gitignore's `!` negation depends on, is the one
+/// they were given in. Lines of the user's own that come between them can still override them.
+#[derive(Debug)]
+pub struct LineSet {
+    present: Vec<String>,
+    absent: Vec<String>,
+    permissions: fs::Permissions,
+    ownership: Ownership,
+}
+
+impl Default for LineSet {
+    fn default() -> Self {
+        Self {
+            present: Vec::new(),
+            absent: Vec::new(),
+            permissions: fs::Permissions::from_mode(0o644),
+            ownership: Ownership::default(),
+        }
+    }
+}
+
+impl LineSet {
+    pub fn new(present: Vec<String>) -> Self {
+        Self::default().with_present(present)
+    }
+
+    /// The lines of `content` that aren't blank, comments included, e.g. an embedded gitignore.
+    pub fn from_lines(content: &str) -> Self {
+        Self::new(
+            content
+                .lines()
+                .map(str::trim_end)
+                .filter(|line| !line.is_empty())
+                .map(String::from)
+                .collect(),
+        )
+    }
+
+    pub fn with_present(mut self, lines: Vec<String>) -> Self {
+        for line in lines {
+            if !self.present.contains(&line) {
+                self.present.push(line);
+            }
+        }
+        self
+    }
+
+    /// Lines to remove wherever they are in the file.
+    pub fn with_absent(mut self, lines: Vec<String>) -> Self {
+        for line in lines {
+            if !self.absent.contains(&line) {
+                self.absent.push(line);
+            }
+        }
+        self
+    }
+
+    pub fn with_permissions(self, permissions: fs::Permissions) -> Self {
+        Self {
+            permissions,
+            ..self
+        }
+    }
+
+    pub fn with_ownership(self, ownership: Ownership) -> Self {
+        Self { ownership, ..self }
+    }
+}
+
+impl Dotfile for LineSet {
+    fn apply(&self, old_content: &[u8]) -> Result<Option<Vec<u8>>, ApplyError> {
+        apply_utf8(
+            old_content,
+            |old_content: String| -> Result<Option<String>, Box<dyn Error + Send + Sync>> {
+                let mut lines: Vec<&str> = old_content
+                    .lines()
+                    .filter(|line| !self.absent.iter().any(|absent| absent == line.trim_end()))
+                    .collect();
+                let find = |lines: &[&str], line: &str| {
+                    lines
+                        .iter()
+                        .position(|existing| existing.trim_end() == line)
+                };
+                // where the previous line of `present` is in the file
+                let mut previous = None;
+                for (index, line) in self.present.iter().enumerate() {
+                    let at = match (find(&lines, line), previous) {
+                        (Some(at), _) => at,
+                        (None, Some(previous)) => {
+                            lines.insert(previous + 1, line);
+                            previous + 1
+                        }
+                        // before the first of the following lines that is already there
+                        (None, None) => {
+                            let at = self.present[index + 1..]
+                                .iter()
+                                .find_map(|next| find(&lines, next))
+                                .unwrap_or(lines.len());
+                            lines.insert(at, line);
+                            at
+                        }
+                    };
+                    previous = Some(at);
+                }
+                let mut new_content = lines.join("\n");
+                if !new_content.is_empty() {
+                    new_content.push('\n');
+                }
+                if new_content == old_content {
+                    Ok(None)
+                } else {
+                    Ok(Some(new_content))
+                }
+            },
+        )
+    }
+
+    fn file_permission(&self) -> fs::Permissions {
+        self.permissions.clone()
+    }
+
+    fn ownership(&self) -> Ownership {
+        self.ownership
+    }
+
+    fn parts(&self) -> Option<Parts> {
+        Some(Parts::new(
+            self.present
+                .iter()
+                .map(|line| Part::Line { line: line.clone() }),
+        ))
+    }
+
+    fn merge(&mut self, y: Box<dyn Dotfile>) -> Result<(), MergeError> {
+        merge_same_type(self, y, |x, y| {
+            let merged = LineSet {
+                ownership: merge_ownership(x.ownership, y.ownership)?,
+                ..x
+            }
+            .with_present(y.present)
+            .with_absent(y.absent);
+            match merged
+                .present
+                .iter()
+                .find(|line| merged.absent.contains(line))
+            {
+                Some(line) => Err(MergeError::MergeConflict {
+                    reason: format!("line {:?} is both required and removed", line),
+                }),
+                None => Ok(merged),
+            }
+        })
+    }
+
+    fn as_any(self: Box<Self>) -> Box<dyn Any> {
+        Box::new(*self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keep_local_lines_and_merge() {
+        let mut gitignore = LineSet::from_lines("# editors\n.direnv\n\nghcid.txt\n");
+        gitignore
+            .merge(Box::new(
+                LineSet::new(vec![".envrc".to_string()]).with_absent(vec!["*.log".to_string()]),
+            ))
+            .unwrap();
+        let old_content = "# my own\ntarget/\n*.log\nghcid.txt\n";
+        let new_content = gitignore.apply(old_content.as_bytes()).unwrap().unwrap();
+        assert_eq!(
+            String::from_utf8(new_content.clone()).unwrap(),
+            "# my own\ntarget/\n# editors\n.direnv\nghcid.txt\n.envrc\n"
+        );
+        assert!(gitignore.apply(&new_content).unwrap().is_none());
+
+        let mut x = LineSet::new(vec!["*.log".to_string()]);
+        let y = LineSet::default().with_absent(vec!["*.log".to_string()]);
+        assert!(x.merge(Box::new(y)).is_err());
+    }
+
+    #[test]
+    fn keep_source_order() {
+        let gitignore = LineSet::from_lines("# logs\n*.log\n!keep.log\n");
+        assert_eq!(
+            String::from_utf8(gitignore.apply(b"").unwrap().unwrap()).unwrap(),
+            "# logs\n*.log\n!keep.log\n"
+        );
+        let new_content = gitignore.apply(b"!keep.log\ntarget/\n").unwrap().unwrap();
+        assert_eq!(
+            String::from_utf8(new_content).unwrap(),
+            "# logs\n*.log\n!keep.log\ntarget/\n"
+        );
+    }
+}
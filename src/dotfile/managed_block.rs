@@ -0,0 +1,280 @@
+use super::{
+    apply_utf8, merge_ownership, merge_same_type,
+    parts::{Part, Parts},
+    ApplyError, Dotfile, MergeError, Ownership,
+};
+use std::{
+    any::Any,
+    collections::{BTreeMap, BTreeSet},
+    error::Error,
+    fs,
+    os::unix::fs::PermissionsExt,
+};
+
+/// Blocks of text in a file that is shared with other tools and people, like `~/.bashrc`. Only
+/// what is between `# BEGIN dotfiles:<id>` and `# END dotfiles:<id>` is managed; a block whose
+/// markers are missing is appended, and everything else is left alone.
+#[derive(Debug)]
+pub struct ManagedBlock {
+    /// Block content by id, without the markers.
+    blocks: BTreeMap<String, String>,
+    /// Ids of blocks to take out of the file, markers and all.
+    absent: BTreeSet<String>,
+    comment: String,
+    permissions: fs::Permissions,
+    ownership: Ownership,
+}
+
+impl Default for ManagedBlock {
+    fn default() -> Self {
+        Self {
+            blocks: BTreeMap::new(),
+            absent: BTreeSet::new(),
+            comment: "#".to_string(),
+            permissions: fs::Permissions::from_mode(0o644),
+            ownership: Ownership::default(),
+        }
+    }
+}
+
+impl ManagedBlock {
+    pub fn new(id: &str, content: String) -> Self {
+        Self {
+            blocks: BTreeMap::from([(id.to_string(), content)]),
+            ..Default::default()
+        }
+    }
+
+    /// Removes the block `id`, if the file has it.
+    pub fn without(mut self, id: &str) -> Self {
+        self.absent.insert(id.to_string());
+        self
+    }
+
+    /// What starts a comment line in the file, `#` by default (e.g. `"` for a vimrc).
+    pub fn with_comment(self, comment: &str) -> Self {
+        Self {
+            comment: comment.to_string(),
+            ..self
+        }
+    }
+
+    pub fn with_permissions(self, permissions: fs::Permissions) -> Self {
+        Self {
+            permissions,
+            ..self
+        }
+    }
+
+    pub fn with_ownership(self, ownership: Ownership) -> Self {
+        Self { ownership, ..self }
+    }
+
+    fn begin_marker(&self, id: &str) -> String {
+        format!("{} BEGIN dotfiles:{}", self.comment, id)
+    }
+
+    fn end_marker(&self, id: &str) -> String {
+        format!("{} END dotfiles:{}", self.comment, id)
+    }
+}
+
+impl Dotfile for ManagedBlock {
+    fn apply(&self, old_content: &[u8]) -> Result<Option<Vec<u8>>, ApplyError> {
+        apply_utf8(
+            old_content,
+            |old_content: String| -> Result<Option<String>, Box<dyn Error + Send + Sync>> {
+                let mut lines: Vec<String> = old_content.lines().map(String::from).collect();
+                for id in self.absent.iter() {
+                    let begin = lines
+                        .iter()
+                        .position(|line| line.trim_end() == self.begin_marker(id));
+                    let end = lines
+                        .iter()
+                        .position(|line| line.trim_end() == self.end_marker(id));
+                    match (begin, end) {
+                        (Some(begin), Some(end)) if begin < end => {
+                            lines.drain(begin..=end);
+                            // along with the blank line that was put before it
+                            if begin > 0
+                                && lines[begin - 1].trim().is_empty()
+                                && lines.get(begin).is_none_or(|line| line.trim().is_empty())
+                            {
+                                lines.remove(begin - 1);
+                            }
+                        }
+                        (None, None) => {}
+                        _ => {
+                            return Err(
+                                format!("unbalanced markers for managed block {:?}", id).into()
+                            )
+                        }
+                    }
+                }
+                for (id, content) in self.blocks.iter() {
+                    let begin_marker = self.begin_marker(id);
+                    let end_marker = self.end_marker(id);
+                    let mut block = vec![begin_marker.clone()];
+                    block.extend(content.lines().map(String::from));
+                    block.push(end_marker.clone());
+
+                    let begin = lines
+                        .iter()
+                        .position(|line| line.trim_end() == begin_marker);
+                    let end = lines.iter().position(|line| line.trim_end() == end_marker);
+                    match (begin, end) {
+                        (Some(begin), Some(end)) if begin < end => {
+                            lines.splice(begin..=end, block);
+                        }
+                        (None, None) => {
+                            if lines.last().is_some_and(|line| !line.trim().is_empty()) {
+                                lines.push(String::new());
+                            }
+                            lines.extend(block);
+                        }
+                        _ => {
+                            return Err(
+                                format!("unbalanced markers for managed block {:?}", id).into()
+                            )
+                        }
+                    }
+                }
+                let mut new_content = lines.join("\n");
+                if !lines.is_empty() {
+                    new_content.push('\n');
+                }
+                if new_content == old_content {
+                    Ok(None)
+                } else {
+                    Ok(Some(new_content))
+                }
+            },
+        )
+    }
+
+    fn file_permission(&self) -> fs::Permissions {
+        self.permissions.clone()
+    }
+
+    fn ownership(&self) -> Ownership {
+        self.ownership
+    }
+
+    fn parts(&self) -> Option<Parts> {
+        Some(Parts::new(self.blocks.keys().map(|id| Part::Block {
+            comment: self.comment.clone(),
+            id: id.clone(),
+        })))
+    }
+
+    fn merge(&mut self, y: Box<dyn Dotfile>) -> Result<(), MergeError> {
+        merge_same_type(self, y, |mut x, y| {
+            if x.comment != y.comment {
+                return Err(MergeError::MergeConflict {
+                    reason: "managed blocks with different comment markers".to_string(),
+                });
+            }
+            for (id, content) in y.blocks {
+                match x.blocks.get(&id) {
+                    Some(existing) if *existing != content => {
+                        return Err(MergeError::MergeConflict {
+                            reason: format!("managed blocks {:?} with different content", id),
+                        })
+                    }
+                    _ => {
+                        x.blocks.insert(id, content);
+                    }
+                }
+            }
+            x.absent.extend(y.absent);
+            if let Some(id) = x.blocks.keys().find(|id| x.absent.contains(*id)) {
+                return Err(MergeError::MergeConflict {
+                    reason: format!("managed block {:?} is both added and removed", id),
+                });
+            }
+            Ok(ManagedBlock {
+                ownership: merge_ownership(x.ownership, y.ownership)?,
+                ..x
+            })
+        })
+    }
+
+    fn as_any(self: Box<Self>) -> Box<dyn Any> {
+        Box::new(*self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn apply(dotfile: &ManagedBlock, old_content: &str) -> Option<String> {
+        dotfile
+            .apply(old_content.as_bytes())
+            .unwrap()
+            .map(|new_content| String::from_utf8(new_content).unwrap())
+    }
+
+    #[test]
+    fn insert_and_replace_blocks() {
+        let mut bashrc = ManagedBlock::new("git", "export EDITOR=nvim".to_string());
+        bashrc
+            .merge(Box::new(ManagedBlock::new(
+                "nix",
+                ". ~/.nix-profile/etc/profile.d/nix.sh".to_string(),
+            )))
+            .unwrap();
+
+        let inserted = apply(&bashrc, "alias ll='ls -l'\n").unwrap();
+        assert_eq!(
+            inserted,
+            "alias ll='ls -l'\n\
+             \n\
+             # BEGIN dotfiles:git\n\
+             export EDITOR=nvim\n\
+             # END dotfiles:git\n\
+             \n\
+             # BEGIN dotfiles:nix\n\
+             . ~/.nix-profile/etc/profile.d/nix.sh\n\
+             # END dotfiles:nix\n"
+        );
+        assert_eq!(apply(&bashrc, &inserted), None);
+
+        let edited = inserted.replace("EDITOR=nvim", "EDITOR=nano") + "alias g=git\n";
+        let replaced = apply(&bashrc, &edited).unwrap();
+        assert_eq!(replaced, inserted.clone() + "alias g=git\n");
+
+        let removed = apply(&ManagedBlock::default().without("nix"), &inserted).unwrap();
+        assert_eq!(
+            removed,
+            "alias ll='ls -l'\n\
+             \n\
+             # BEGIN dotfiles:git\n\
+             export EDITOR=nvim\n\
+             # END dotfiles:git\n"
+        );
+        assert_eq!(
+            apply(&ManagedBlock::default().without("nix"), &removed),
+            None
+        );
+    }
+
+    #[test]
+    fn merge_conflict_and_unbalanced_markers() {
+        let mut x = ManagedBlock::new("git", "a".to_string());
+        assert!(x
+            .merge(Box::new(ManagedBlock::new("git", "b".to_string())))
+            .is_err());
+        let x = ManagedBlock::new("git", "a".to_string());
+        assert!(x.apply(b"# BEGIN dotfiles:git\nmanual\n").is_err());
+    }
+
+    #[test]
+    fn merge_keeps_first_permissions() {
+        let mut x = ManagedBlock::new("git", "a".to_string())
+            .with_permissions(fs::Permissions::from_mode(0o600));
+        x.merge(Box::new(ManagedBlock::new("nix", "b".to_string())))
+            .unwrap();
+        assert_eq!(x.file_permission().mode() & 0o777, 0o600);
+    }
+}
@@ -1,65 +1,183 @@
-use super::{merge_same_type, ApplyError, Dotfile, MergeError};
-use crate::dotfile::apply_utf8;
-use std::{any::Any, convert::identity, error::Error, os::unix::fs::PermissionsExt};
+use super::{
+    key_value::{KeyValueConf, Kind, ListOrder, Mode, Schema, Value, APPEND},
+    merge_same_type,
+    parts::Parts,
+    ApplyError, Dotfile, MergeError, Ownership,
+};
+use crate::{
+    dotfile::{key_value::lex, lift_io_err, Dotfiles},
+    store_url::{store_identity, StoreUrl},
+};
+use std::{
+    any::Any,
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+/// The settings of `nix.conf` and their kinds. Settings not listed here can still be set; they
+/// take the kind of the value they are set to.
+pub static NIX_CONF_SCHEMA: Schema = Schema {
+    settings: &[
+        ("allowed-users", Kind::List(&APPEND)),
+        ("auto-optimise-store", Kind::Boolean),
+        ("build-users-group", Kind::Scalar),
+        ("builders", Kind::Scalar),
+        ("builders-use-substitutes", Kind::Boolean),
+        ("connect-timeout", Kind::Integer),
+        ("cores", Kind::Integer),
+        ("experimental-features", Kind::List(&APPEND)),
+        ("extra-experimental-features", Kind::List(&APPEND)),
+        ("extra-substituters", Kind::List(&STORES)),
+        ("extra-trusted-public-keys", Kind::List(&APPEND)),
+        ("http-connections", Kind::Integer),
+        ("keep-derivations", Kind::Boolean),
+        ("keep-outputs", Kind::Boolean),
+        // also accepts `auto`
+        ("max-jobs", Kind::Scalar),
+        ("netrc-file", Kind::Scalar),
+        ("post-build-hook", Kind::Scalar),
+        ("require-sigs", Kind::Boolean),
+        ("secret-key-files", Kind::List(&APPEND)),
+        ("substituters", Kind::List(&STORES)),
+        ("trusted-public-keys", Kind::List(&APPEND)),
+        ("trusted-substituters", Kind::List(&STORES)),
+        ("trusted-users", Kind::List(&APPEND)),
+    ],
+};
+
+/// The file the Determinate Nix installer has nix.conf include for settings of its user, which
+/// it leaves alone on upgrades.
+const INSTALLER_INCLUDE: &str = "nix.custom.conf";
+
+/// Stores are matched by their `store_identity`, the URL without parameters, so changing a
+/// parameter like `priority=` edits the entry in place. A new store goes before the first one
+/// with a larger priority, which Nix queries after it anyway; a store without `priority=` counts
+/// as 50, the default of a binary cache. Among stores of the same priority, the order in the file
+/// is the order they are queried in, and new ones go last.
+pub static STORES: ListOrder = ListOrder {
+    key: store_key,
+    position: store_position,
+};
+
+fn store_key(url: &str) -> &str {
+    store_identity(url)
+}
+
+fn store_priority(url: &str) -> u32 {
+    url.parse::<StoreUrl>()
+        .ok()
+        .and_then(|url| url.priority())
+        .unwrap_or(50)
+}
+
+fn store_position(stores: &[String], store: &str) -> usize {
+    let priority = store_priority(store);
+    stores
+        .iter()
+        .position(|existing| store_priority(existing) > priority)
+        .unwrap_or(stores.len())
+}
 
 /**
 /etc/nix/nix.conf: https://nixos.org/manual/nix/stable/command-ref/conf-file
 */
-#[derive(Debug, Default)]
-pub struct NixConf(NixConfExt<()>);
-
-#[derive(Debug, Default, Clone)]
-pub struct NixConfExt<T> {
-    substituters: (Vec<String>, T),
-    trusted_public_keys: (Vec<String>, T),
-    post_build_hook: (Option<String>, T),
-    secret_key_files: (Vec<String>, T),
+#[derive(Debug)]
+pub struct NixConf {
+    conf: KeyValueConf,
+    /// The file included by nix.conf that the settings go in, relative to nix.conf.
+    include: Option<PathBuf>,
 }
 
-impl<T> NixConfExt<T> {
-    fn new<F>(new_ext: F) -> Self
-    where
-        F: Fn() -> T,
-    {
+impl Default for NixConf {
+    fn default() -> Self {
         Self {
-            substituters: (Vec::new(), new_ext()),
-            trusted_public_keys: (Vec::new(), new_ext()),
-            post_build_hook: (None, new_ext()),
-            secret_key_files: (Vec::new(), new_ext()),
+            conf: KeyValueConf::new(&NIX_CONF_SCHEMA),
+            include: None,
         }
     }
 }
 
 impl NixConf {
     pub fn new() -> Self {
-        Self(NixConfExt::new(|| ()))
+        Self::default()
     }
 
-    pub fn with_substituters(mut self, substituters: Vec<String>) -> Self {
-        self.0.substituters.0 = substituters;
-        self
+    /// Writes the settings into `include`, a file that nix.conf includes, rather than into
+    /// nix.conf itself. A nix.conf that includes `nix.custom.conf`, as the one the Determinate
+    /// Nix installer writes does, gets this for that file.
+    pub fn with_include(self, include: PathBuf) -> Self {
+        Self {
+            include: Some(include),
+            ..self
+        }
     }
 
-    pub fn with_trusted_public_keys(mut self, trusted_public_keys: Vec<String>) -> Self {
-        self.0.trusted_public_keys.0 = trusted_public_keys;
-        self
+    /// Sets any Nix setting.
+    pub fn with(self, name: &str, value: Value) -> Self {
+        Self {
+            conf: self.conf.with(name, value),
+            ..self
+        }
+    }
+
+    pub fn with_mode(self, name: &str, mode: Mode, value: Value) -> Self {
+        Self {
+            conf: self.conf.with_mode(name, mode, value),
+            ..self
+        }
     }
 
-    pub fn with_post_build_hook(mut self, post_build_hook: String) -> Self {
-        self.0.post_build_hook.0 = Some(post_build_hook);
-        self
+    /// Deletes the line of `name`, leaving it at Nix's default.
+    pub fn without(self, name: &str) -> Self {
+        Self {
+            conf: self.conf.without(name),
+            ..self
+        }
     }
 
-    pub fn with_secret_key_files(mut self, secret_key_files: Vec<String>) -> Self {
-        self.0.secret_key_files.0 = secret_key_files;
-        self
+    pub fn with_list(self, name: &str, values: Vec<String>) -> Self {
+        Self {
+            conf: self.conf.with_list(name, values),
+            ..self
+        }
     }
-}
 
-#[derive(Debug)]
-struct Line {
-    index: Option<usize>,
-    content: String,
+    pub fn with_scalar(self, name: &str, value: String) -> Self {
+        Self {
+            conf: self.conf.with_scalar(name, value),
+            ..self
+        }
+    }
+
+    pub fn with_bool(self, name: &str, value: bool) -> Self {
+        Self {
+            conf: self.conf.with_bool(name, value),
+            ..self
+        }
+    }
+
+    pub fn with_integer(self, name: &str, value: i64) -> Self {
+        Self {
+            conf: self.conf.with_integer(name, value),
+            ..self
+        }
+    }
+
+    pub fn with_substituters(self, substituters: Vec<String>) -> Self {
+        self.with_list("substituters", substituters)
+    }
+
+    pub fn with_trusted_public_keys(self, trusted_public_keys: Vec<String>) -> Self {
+        self.with_list("trusted-public-keys", trusted_public_keys)
+    }
+
+    pub fn with_post_build_hook(self, post_build_hook: String) -> Self {
+        self.with_scalar("post-build-hook", post_build_hook)
+    }
+
+    pub fn with_secret_key_files(self, secret_key_files: Vec<String>) -> Self {
+        self.with_list("secret-key-files", secret_key_files)
+    }
 }
 
 impl Dotfile for NixConf {
@@ -67,212 +185,237 @@ impl Dotfile for NixConf {
         Box::new(*self)
     }
 
-    fn file_permission(&self) -> std::fs::Permissions {
-        std::fs::Permissions::from_mode(0o644)
+    fn file_permission(&self) -> fs::Permissions {
+        self.conf.file_permission()
+    }
+
+    fn ownership(&self) -> Ownership {
+        self.conf.ownership()
     }
 
     fn apply(&self, old_content: &[u8]) -> Result<Option<Vec<u8>>, ApplyError> {
-        apply_utf8::<Self, _>(
-            old_content,
-            |old_content: String| -> Result<Option<String>, Box<dyn Error + Send + Sync>> {
-                let lines: Vec<String> = old_content.lines().map(|s| String::from(s)).collect();
-
-                let mut existing_config: NixConfExt<Option<Line>> = NixConfExt::new(|| None);
-
-                for (index, content) in lines.iter().enumerate() {
-                    parse_list(
-                        "substituters",
-                        index,
-                        content,
-                        &mut existing_config.substituters,
-                    );
-                    parse_list(
-                        "trusted-public-keys",
-                        index,
-                        content,
-                        &mut existing_config.trusted_public_keys,
-                    );
-                    parse_single_value(
-                        "post-build-hook",
-                        index,
-                        content,
-                        &mut existing_config.post_build_hook,
-                    );
-                    parse_list(
-                        "secret-key-files",
-                        index,
-                        content,
-                        &mut existing_config.secret_key_files,
-                    );
-                }
-                let new_config = merge(self.0.clone(), existing_config)?;
-                let new_lines: Vec<Line> = vec![
-                    render_list("substituters", new_config.substituters),
-                    render_list("trusted-public-keys", new_config.trusted_public_keys),
-                    render_single_value("post-build-hook", new_config.post_build_hook),
-                    render_list("secret-key-files", new_config.secret_key_files),
-                ]
-                .into_iter()
-                .filter_map(identity)
-                .collect();
-
-                let lines = merge_lines(lines, new_lines);
-                let new_content = lines.join("\n");
-                if old_content == new_content {
-                    Ok(None)
-                } else {
-                    Ok(Some(new_content))
-                }
-            },
-        )
+        self.conf.apply(old_content)
+    }
+
+    fn parts(&self) -> Option<Parts> {
+        self.conf.parts()
     }
 
     fn merge(&mut self, y: Box<dyn Dotfile>) -> Result<(), MergeError> {
-        merge_same_type(self, y, |x, y| Ok(NixConf(merge(x.0, y.0)?)))
+        merge_same_type(self, y, |mut x, y| {
+            x.conf.merge(Box::new(y.conf))?;
+            match (&x.include, y.include) {
+                (Some(include), Some(other)) if *include != other => {
+                    Err(MergeError::MergeConflict {
+                        reason: format!(
+                            "nix.conf settings set to go into both {:?} and {:?}",
+                            include, other
+                        ),
+                    })
+                }
+                (None, Some(other)) => Ok(NixConf {
+                    include: Some(other),
+                    ..x
+                }),
+                _ => Ok(x),
+            }
+        })
+    }
+
+    /// Works out where the settings go, following the includes of nix.conf, and what of them
+    /// Nix already has. The included files are only read.
+    fn expand(&self, path: &Path) -> Result<Option<Dotfiles>, ApplyError> {
+        let Some(content) = read_optional(path)? else {
+            return Ok(None);
+        };
+        let mut files = ConfigFiles::default();
+        files.read(path, &content, &mut Vec::new())?;
+        let target = match &self.include {
+            Some(include) => {
+                let target = resolve(path, include);
+                if !files.ends.iter().any(|(file, _)| *file == target) {
+                    return Err(other_error(format!(
+                        "{:?} doesn't include {:?}",
+                        path, target
+                    )));
+                }
+                target
+            }
+            None => files
+                .includes
+                .iter()
+                .find(|include| include.file_name() == Some(INSTALLER_INCLUDE.as_ref()))
+                .cloned()
+                .unwrap_or_else(|| path.to_path_buf()),
+        };
+        let target_end = files
+            .ends
+            .iter()
+            .find(|(file, _)| *file == target)
+            .map_or(files.assignments.len(), |(_, end)| *end);
+        let mut conf = self.conf.clone();
+        for name in self.conf.names() {
+            let own = files
+                .assignments
+                .iter()
+                .rposition(|source| source.file == target && source.name == name);
+            let after = own.map_or(target_end, |own| own + 1);
+            if let Some(source) = files.assignments[after..]
+                .iter()
+                .find(|source| source.name == name && source.file != target)
+            {
+                return Err(other_error(format!(
+                    "{} would be overridden by {:?}",
+                    name, source.file
+                )));
+            }
+            if !conf.adds_to_list(&name) {
+                continue;
+            }
+            // values the target has itself are left in, so that they keep counting as ours
+            let others = |from: usize| {
+                files.assignments[from..]
+                    .iter()
+                    .filter(|source| source.file != target)
+            };
+            let (name, effective) = match own {
+                Some(own) => (name.clone(), effective_value(others(own), &name)),
+                // a new line at the end of the target would replace what comes before it
+                None if !name.starts_with("extra-")
+                    && effective_value(&files.assignments[..target_end], &name).is_some() =>
+                {
+                    let extra = format!("extra-{}", name);
+                    conf.rename(&name, &extra)
+                        .map_err(|err| ApplyError::Other(err.into()))?;
+                    (extra, effective_value(others(0), &name))
+                }
+                None => (name.clone(), effective_value(others(0), &name)),
+            };
+            if let Some(effective) = effective {
+                let values: Vec<String> = effective.split_whitespace().map(String::from).collect();
+                conf.drop_present(&name, &values);
+            }
+        }
+        Ok(Some(Dotfiles::from([(
+            target,
+            Box::new(NixConf {
+                conf,
+                include: None,
+            }) as Box<dyn Dotfile>,
+        )])))
     }
 }
 
-fn merge<Ext>(x: NixConfExt<()>, y: NixConfExt<Ext>) -> Result<NixConfExt<Ext>, MergeError> {
-    Ok(NixConfExt {
-        substituters: merge_list(x.substituters, y.substituters),
-        trusted_public_keys: merge_list(x.trusted_public_keys, y.trusted_public_keys),
-        post_build_hook: merge_single_value(
-            "post_build_hook",
-            x.post_build_hook,
-            y.post_build_hook,
-        )?,
-        secret_key_files: merge_list(x.secret_key_files, y.secret_key_files),
-    })
+/// An assignment in nix.conf or a file it includes.
+#[derive(Debug)]
+struct Source {
+    file: PathBuf,
+    name: String,
+    value: String,
 }
 
-fn merge_list<T: Ord, U>(mut x: (Vec<T>, ()), mut y: (Vec<T>, U)) -> (Vec<T>, U) {
-    x.0.append(&mut y.0);
-    x.0.sort();
-    x.0.dedup();
-    (x.0, y.1)
+/// nix.conf and the files it includes, flattened in the order Nix reads them.
+#[derive(Debug, Default)]
+struct ConfigFiles {
+    assignments: Vec<Source>,
+    /// Every file read, with the number of assignments read by the time it was done.
+    ends: Vec<(PathBuf, usize)>,
+    /// The files nix.conf itself includes, in order.
+    includes: Vec<PathBuf>,
 }
 
-fn merge_single_value<T, U>(
-    field_name: &str,
-    x: (Option<T>, ()),
-    y: (Option<T>, U),
-) -> Result<(Option<T>, U), MergeError>
-where
-    T: Eq,
-{
-    match (x.0, y.0) {
-        (None, None) => Ok((None, y.1)),
-        (Some(xv), None) => Ok((Some(xv), y.1)),
-        (None, Some(yv)) => Ok((Some(yv), y.1)),
-        (Some(xv), Some(yv)) => {
-            if xv == yv {
-                Ok((Some(xv), y.1))
-            } else {
-                Err(MergeError::MergeConflict {
-                    reason: format!("Conflicting values set for {}", field_name),
-                })
+impl ConfigFiles {
+    /// Reads `content` of `file`, and what it includes in its place. `stack` is the files
+    /// being read, to refuse an include cycle.
+    fn read(
+        &mut self,
+        file: &Path,
+        content: &str,
+        stack: &mut Vec<PathBuf>,
+    ) -> Result<(), ApplyError> {
+        if stack.iter().any(|including| including == file) {
+            return Err(other_error(format!("{:?} includes itself", file)));
+        }
+        stack.push(file.to_path_buf());
+        for line in content.lines() {
+            if let Some((include, required)) = include_directive(line) {
+                let include = resolve(file, Path::new(include));
+                if stack.len() == 1 {
+                    self.includes.push(include.clone());
+                }
+                match read_optional(&include)? {
+                    Some(content) => self.read(&include, &content, stack)?,
+                    None if required => {
+                        return Err(lift_io_err(&include, "fs::read")(
+                            io::ErrorKind::NotFound.into(),
+                        ))
+                    }
+                    None => {}
+                }
+            } else if let Some(assignment) = lex(line) {
+                self.assignments.push(Source {
+                    file: file.to_path_buf(),
+                    name: assignment.name.to_string(),
+                    value: assignment.value.to_string(),
+                });
             }
         }
+        stack.pop();
+        self.ends.push((file.to_path_buf(), self.assignments.len()));
+        Ok(())
     }
 }
 
-fn merge_lines(mut old_lines: Vec<String>, new_lines: Vec<Line>) -> Vec<String> {
-    for line in new_lines {
-        match line.index {
-            Some(index) => old_lines[index] = line.content,
-            None => old_lines.push(line.content),
-        }
+/// `include <path>`, or `!include <path>` for a file that may be missing.
+fn include_directive(line: &str) -> Option<(&str, bool)> {
+    let code = line.find('#').map_or(line, |hash| &line[..hash]).trim();
+    let (keyword, path) = code.split_once(char::is_whitespace)?;
+    match keyword {
+        "include" => Some((path.trim(), true)),
+        "!include" => Some((path.trim(), false)),
+        _ => None,
     }
-    old_lines
-}
-
-fn new_line_from_content(content: Option<String>, line: Option<Line>) -> Option<Line> {
-    content.map(|content| {
-        let mut line = line.unwrap_or_else(|| {
-            let line = Line {
-                content: String::new(),
-                index: None,
-            };
-            line
-        });
-        line.content = content;
-        line
-    })
 }
 
-fn render_list(field_name: &str, conf_entry_ext: (Vec<String>, Option<Line>)) -> Option<Line> {
-    let (values, line) = conf_entry_ext;
-    let content = if values.is_empty() {
-        None
-    } else {
-        Some(format!("{} = {}", field_name, values.join(" ")))
-    };
-
-    new_line_from_content(content, line)
-}
-
-fn render_single_value(
-    field_name: &str,
-    conf_entry_ext: (Option<String>, Option<Line>),
-) -> Option<Line> {
-    let (value, line) = conf_entry_ext;
-    let content = value.map(|value| format!("{} = {}", field_name, value));
-
-    new_line_from_content(content, line)
+/// A relative include is relative to the directory of the file that has it.
+fn resolve(file: &Path, include: &Path) -> PathBuf {
+    match file.parent() {
+        Some(dir) => dir.join(include),
+        None => include.to_path_buf(),
+    }
 }
 
-fn parse_field<T, F>(
-    field_name: &str,
-    index: usize,
-    content: &str,
-    parse_value: F,
-    result: &mut (T, Option<Line>),
-) where
-    F: FnOnce(&str) -> T,
-{
-    if let Some(value_str) = content.strip_prefix(format!("{} = ", field_name).as_str()) {
-        let value = parse_value(value_str);
-        result.0 = value;
-        result.1 = Some(Line {
-            index: Some(index),
-            content: content.to_string(),
-        });
+fn read_optional(path: &Path) -> Result<Option<String>, ApplyError> {
+    match fs::read_to_string(path) {
+        Ok(content) => Ok(Some(content)),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(lift_io_err(path, "fs::read_to_string")(err)),
     }
 }
 
-fn parse_list(
-    field_name: &str,
-    index: usize,
-    content: &str,
-    result: &mut (Vec<String>, Option<Line>),
-) {
-    parse_field(
-        field_name,
-        index,
-        content,
-        |value_str| {
-            value_str
-                .split_whitespace()
-                .map(|s| s.to_string())
-                .collect()
-        },
-        result,
-    )
+fn other_error(message: String) -> ApplyError {
+    ApplyError::Other(message.into())
 }
 
-fn parse_single_value(
-    field_name: &str,
-    index: usize,
-    content: &str,
-    result: &mut (Option<String>, Option<Line>),
-) {
-    parse_field(
-        field_name,
-        index,
-        content,
-        |value_str| Some(value_str.to_string()),
-        result,
-    )
+/// The value Nix ends up with for `name` after `assignments`: each `name` replaces what came
+/// before, and each `extra-name` adds to it.
+fn effective_value<'a>(
+    assignments: impl IntoIterator<Item = &'a Source>,
+    name: &str,
+) -> Option<String> {
+    let extra = format!("extra-{}", name);
+    let mut value: Option<String> = None;
+    for source in assignments {
+        if source.name == name {
+            value = Some(source.value.clone());
+        } else if source.name == extra {
+            value = Some(match value {
+                Some(value) if !value.is_empty() => format!("{} {}", value, source.value),
+                _ => source.value.clone(),
+            });
+        }
+    }
+    value
 }
 
 #[cfg(test)]
@@ -306,4 +449,86 @@ substituters = s3://nix-cache?endpoint=http://192.168.31.2:9091&profile=minio&co
                     .to_string()
         )
     }
+
+    #[test]
+    fn store_order() {
+        let old_content = "substituters = https://cache.nixos.org s3://cache?priority=60";
+        let nix_conf = NixConf::new()
+            .with_substituters(vec![
+                "https://b.example".to_string(),
+                "https://a.example?priority=10".to_string(),
+                "s3://cache?priority=30".to_string(),
+            ])
+            .with_secret_key_files(vec!["/z".to_string(), "/a".to_string()]);
+        assert_eq!(
+            String::from_utf8(nix_conf.apply(old_content.as_bytes()).unwrap().unwrap()).unwrap(),
+            "substituters = https://a.example?priority=10 https://cache.nixos.org https://b.example s3://cache?priority=30\nsecret-key-files = /z /a"
+        );
+    }
+
+    #[test]
+    fn follow_includes() {
+        let dir = tempfile::tempdir().unwrap();
+        let nix_conf_path = dir.path().join("nix.conf");
+        fs::write(
+            &nix_conf_path,
+            "substituters = https://cache.nixos.org\n\
+             trusted-public-keys = cache.nixos.org-1:a\n\
+             !include nix.custom.conf\n",
+        )
+        .unwrap();
+        let custom_path = dir.path().join("nix.custom.conf");
+        fs::write(&custom_path, "extra-trusted-public-keys = b\n").unwrap();
+        let nix_conf = NixConf::new()
+            .with_substituters(vec![
+                "https://cache.nixos.org".to_string(),
+                "s3://mine".to_string(),
+            ])
+            .with_trusted_public_keys(vec!["b".to_string(), "c".to_string()]);
+        let mut expanded = nix_conf.expand(&nix_conf_path).unwrap().unwrap();
+        assert_eq!(expanded.len(), 1);
+        let custom = expanded.remove(&custom_path).unwrap();
+        let new_content = custom
+            .apply(&fs::read(&custom_path).unwrap())
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            String::from_utf8(new_content).unwrap(),
+            "extra-trusted-public-keys = b c\nextra-substituters = s3://mine\n"
+        );
+
+        // a header alone doesn't make it the installer's layout
+        fs::write(
+            &nix_conf_path,
+            "# Generated by https://github.com/DeterminateSystems/nix-installer.\n\
+             include extra.conf\n",
+        )
+        .unwrap();
+        fs::write(dir.path().join("extra.conf"), "").unwrap();
+        let expanded = nix_conf.expand(&nix_conf_path).unwrap().unwrap();
+        assert!(expanded.contains_key(&nix_conf_path));
+
+        fs::write(&nix_conf_path, "post-build-hook = /a\ninclude hooks.conf\n").unwrap();
+        fs::write(dir.path().join("hooks.conf"), "post-build-hook = /b\n").unwrap();
+        let nix_conf = NixConf::new().with_post_build_hook("/a".to_string());
+        assert!(nix_conf.expand(&nix_conf_path).is_err());
+    }
+
+    #[test]
+    fn move_cache() {
+        let old_content =
+            "substituters = https://cache.nixos.org s3://old\npost-build-hook = /old-hook";
+        let nix_conf = NixConf::new()
+            .with_mode(
+                "substituters",
+                Mode::Absent,
+                Value::Scalar("s3://old".to_string()),
+            )
+            .with_substituters(vec!["s3://new".to_string()])
+            .without("post-build-hook");
+        assert_eq!(
+            String::from_utf8(nix_conf.apply(old_content.as_bytes()).unwrap().unwrap()).unwrap(),
+            "substituters = https://cache.nixos.org s3://new"
+        );
+    }
 }
@@ -0,0 +1,176 @@
+use super::{
+    ini::{Ini, Section},
+    json::Json,
+    key_value::{KeyValueConf, Mode, Value},
+    line_set::LineSet,
+    managed_block::ManagedBlock,
+    ssh_config::SshConfig,
+    toml::Toml,
+    yaml::Yaml,
+    ApplyError, Dotfile,
+};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+/// Something a dotfile puts in a file it shares with other tools and people, recorded so that
+/// pruning can take out just that and leave the rest of the file alone.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "kebab-case")]
+pub enum Part {
+    /// A `name = value` setting, or one item of it if it is a list.
+    Setting {
+        name: String,
+        value: String,
+        list: bool,
+    },
+    Line {
+        line: String,
+    },
+    /// A `ManagedBlock`, with the comment its markers start with.
+    Block {
+        comment: String,
+        id: String,
+    },
+    IniKey {
+        section: String,
+        subsection: Option<String>,
+        key: String,
+    },
+    /// A key of a `Structured` document, by its path from the root.
+    TreeKey {
+        format: String,
+        path: Vec<String>,
+    },
+    SshOption {
+        header: String,
+        option: String,
+    },
+}
+
+/// The parts a dotfile owns in a file, without duplicates.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct Parts(Vec<Part>);
+
+impl Part {
+    /// A dotfile that removes this part from a file, and leaves everything else.
+    fn remover(&self) -> Result<Box<dyn Dotfile>, ApplyError> {
+        Ok(match self {
+            Part::Setting { name, value, list } => {
+                let value = if *list {
+                    Value::List(vec![value.clone()])
+                } else {
+                    Value::Scalar(value.clone())
+                };
+                Box::new(KeyValueConf::default().with_mode(name, Mode::Absent, value))
+            }
+            Part::Line { line } => Box::new(LineSet::default().with_absent(vec![line.clone()])),
+            Part::Block { comment, id } => {
+                Box::new(ManagedBlock::default().with_comment(comment).without(id))
+            }
+            Part::IniKey {
+                section,
+                subsection,
+                key,
+            } => {
+                let section = match subsection {
+                    Some(subsection) => Section::new(section).with_subsection(subsection),
+                    None => Section::new(section),
+                };
+                Box::new(Ini::new().without(section, key))
+            }
+            Part::TreeKey { format, path } => match format.as_str() {
+                "json" => Box::new(Json::default().without(path.clone())),
+                "toml" => Box::new(Toml::default().without(path.clone())),
+                "yaml" => Box::new(Yaml::default().without(path.clone())),
+                format => {
+                    return Err(ApplyError::Other(
+                        format!("unknown document format {:?}", format).into(),
+                    ))
+                }
+            },
+            Part::SshOption { header, option } => {
+                Box::new(SshConfig::new().without_option(header, option))
+            }
+        })
+    }
+}
+
+impl Parts {
+    pub fn new(parts: impl IntoIterator<Item = Part>) -> Self {
+        Self::default().union(Self(parts.into_iter().collect()))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Part> {
+        self.0.iter()
+    }
+
+    /// These parts, followed by those of `other` that aren't in them yet.
+    pub fn union(mut self, other: Parts) -> Self {
+        for part in other.0 {
+            if !self.0.contains(&part) {
+                self.0.push(part);
+            }
+        }
+        self
+    }
+
+    /// These parts without those in `other`.
+    pub fn minus(&self, other: &Parts) -> Self {
+        Self(
+            self.0
+                .iter()
+                .filter(|part| !other.0.contains(part))
+                .cloned()
+                .collect(),
+        )
+    }
+
+    /// `content` without these parts, or `None` if none of them is in it.
+    pub fn remove_from(&self, content: &[u8]) -> Result<Option<Vec<u8>>, ApplyError> {
+        let mut removed: Option<Vec<u8>> = None;
+        for part in self.0.iter() {
+            let current = removed.as_deref().unwrap_or(content);
+            if let Some(new_content) = part.remover()?.apply(current)? {
+                removed = Some(new_content);
+            }
+        }
+        Ok(removed)
+    }
+}
+
+impl fmt::Display for Part {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Part::Setting { name, value, .. } => write!(f, "{} = {}", name, value),
+            Part::Line { line } => write!(f, "line {:?}", line),
+            Part::Block { id, .. } => write!(f, "block {}", id),
+            Part::IniKey {
+                section,
+                subsection,
+                key,
+            } => match subsection {
+                Some(subsection) => write!(f, "{}.{}.{}", section, subsection, key),
+                None => write!(f, "{}.{}", section, key),
+            },
+            Part::TreeKey { path, .. } => write!(f, "/{}", path.join("/")),
+            Part::SshOption { header, option } => write!(f, "{} in {}", option, header),
+        }
+    }
+}
+
+impl fmt::Display for Parts {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (index, part) in self.0.iter().enumerate() {
+            if index > 0 {
+                f.write_str(", ")?;
+            }
+            write!(f, "{}", part)?;
+        }
+        Ok(())
+    }
+}
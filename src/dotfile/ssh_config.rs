@@ -0,0 +1,403 @@
+use super::{
+    apply_utf8, merge_ownership, merge_same_type,
+    parts::{Part, Parts},
+    ApplyError, Dotfile, MergeError, Ownership,
+};
+use std::{any::Any, error::Error, fs, os::unix::fs::PermissionsExt};
+
+/**
+`~/.ssh/config`: https://man.openbsd.org/ssh_config
+
+Only the `Host`/`Match` blocks set here are touched, and in them only the options set here.
+Lines before the first block (like `Include`) and the user's own blocks are kept. A missing block
+is added before a catch-all `Host *`, since the first value ssh finds for an option wins.
+*/
+#[derive(Debug)]
+pub struct SshConfig {
+    blocks: Vec<Block>,
+    permissions: fs::Permissions,
+    ownership: Ownership,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Block {
+    /// `Host` or `Match`, followed by its patterns or criteria.
+    header: String,
+    /// In order. An option that may be repeated, like `IdentityFile`, has several values, and
+    /// one without values is removed.
+    options: Vec<(String, Vec<String>)>,
+}
+
+impl Default for SshConfig {
+    fn default() -> Self {
+        Self {
+            blocks: Vec::new(),
+            permissions: fs::Permissions::from_mode(0o600),
+            ownership: Ownership::default(),
+        }
+    }
+}
+
+impl SshConfig {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets `options` in the `Host <patterns>` block.
+    pub fn with_host(self, patterns: &str, options: &[(&str, &str)]) -> Self {
+        self.with_block(format!("Host {}", patterns), options)
+    }
+
+    /// Sets `options` in the `Match <criteria>` block.
+    pub fn with_match(self, criteria: &str, options: &[(&str, &str)]) -> Self {
+        self.with_block(format!("Match {}", criteria), options)
+    }
+
+    /// Removes `option` from the block that starts with `header`, like `Host dev`.
+    pub fn without_option(mut self, header: &str, option: &str) -> Self {
+        let block = self.block_mut(header);
+        match block
+            .options
+            .iter_mut()
+            .find(|(k, _)| k.eq_ignore_ascii_case(option))
+        {
+            Some((_, values)) => values.clear(),
+            None => block.options.push((option.to_string(), Vec::new())),
+        }
+        self
+    }
+
+    pub fn with_permissions(self, permissions: fs::Permissions) -> Self {
+        Self {
+            permissions,
+            ..self
+        }
+    }
+
+    pub fn with_ownership(self, ownership: Ownership) -> Self {
+        Self { ownership, ..self }
+    }
+
+    fn with_block(mut self, header: String, options: &[(&str, &str)]) -> Self {
+        let block = self.block_mut(&header);
+        for (key, value) in options {
+            match block
+                .options
+                .iter_mut()
+                .find(|(k, _)| k.eq_ignore_ascii_case(key))
+            {
+                Some((_, values)) => values.push(value.to_string()),
+                None => block
+                    .options
+                    .push((key.to_string(), vec![value.to_string()])),
+            }
+        }
+        self
+    }
+
+    fn block_mut(&mut self, header: &str) -> &mut Block {
+        let header = normalize_header(header);
+        let index = match self.blocks.iter().position(|block| block.header == header) {
+            Some(index) => index,
+            None => {
+                self.blocks.push(Block {
+                    header,
+                    options: Vec::new(),
+                });
+                self.blocks.len() - 1
+            }
+        };
+        &mut self.blocks[index]
+    }
+}
+
+/// `host  a b` and `Host=a b` are the same header as `Host a b`.
+fn normalize_header(line: &str) -> String {
+    let (keyword, rest) = split_option(line).unwrap_or((line.trim(), ""));
+    let keyword = if keyword.eq_ignore_ascii_case("host") {
+        "Host"
+    } else if keyword.eq_ignore_ascii_case("match") {
+        "Match"
+    } else {
+        keyword
+    };
+    let rest: Vec<&str> = rest.split_whitespace().collect();
+    format!("{} {}", keyword, rest.join(" "))
+}
+
+/// The keyword and the arguments of a line, which may be separated by whitespace or `=`.
+fn split_option(line: &str) -> Option<(&str, &str)> {
+    let line = line.trim();
+    if line.is_empty() || line.starts_with('#') {
+        return None;
+    }
+    let end = line
+        .find(|c: char| c.is_whitespace() || c == '=')
+        .unwrap_or(line.len());
+    let (keyword, rest) = line.split_at(end);
+    let rest = rest.trim_start();
+    let rest = rest.strip_prefix('=').unwrap_or(rest).trim();
+    Some((keyword, rest))
+}
+
+fn is_block_header(line: &str) -> bool {
+    split_option(line).is_some_and(|(keyword, _)| {
+        keyword.eq_ignore_ascii_case("host") || keyword.eq_ignore_ascii_case("match")
+    })
+}
+
+/// Sets the options of `block` in `lines`, adding the block if it isn't there.
+fn apply_block(lines: &mut Vec<String>, block: &Block) {
+    let headers: Vec<usize> = (0..lines.len())
+        .filter(|index| is_block_header(&lines[*index]))
+        .collect();
+    let found = headers
+        .iter()
+        .enumerate()
+        .find(|(_, index)| normalize_header(&lines[**index]) == block.header);
+    let Some((nth, start)) = found else {
+        if block.options.iter().all(|(_, values)| values.is_empty()) {
+            return;
+        }
+        let mut new_block = vec![block.header.clone()];
+        for (key, values) in block.options.iter() {
+            new_block.extend(values.iter().map(|value| format!("    {} {}", key, value)));
+        }
+        new_block.push(String::new());
+        let catch_all = headers
+            .iter()
+            .find(|index| normalize_header(&lines[**index]) == "Host *");
+        match catch_all {
+            Some(index) => {
+                lines.splice(*index..*index, new_block);
+            }
+            None => {
+                if lines.last().is_some_and(|line| !line.trim().is_empty()) {
+                    lines.push(String::new());
+                }
+                new_block.pop();
+                lines.extend(new_block);
+            }
+        }
+        return;
+    };
+    let start = *start;
+    let mut end = headers.get(nth + 1).copied().unwrap_or(lines.len());
+    for (key, values) in block.options.iter() {
+        let existing: Vec<usize> = (start + 1..end)
+            .filter(|index| {
+                split_option(&lines[*index]).is_some_and(|(k, _)| k.eq_ignore_ascii_case(key))
+            })
+            .collect();
+        let existing_values: Vec<&str> = existing
+            .iter()
+            .filter_map(|index| split_option(&lines[*index]).map(|(_, value)| value))
+            .collect();
+        if existing_values == *values {
+            continue;
+        }
+        // follow the indentation of the block
+        let option_line = (start + 1..end).find(|index| split_option(&lines[*index]).is_some());
+        let indent: String = option_line
+            .map(|index| {
+                lines[index]
+                    .chars()
+                    .take_while(|c| c.is_whitespace())
+                    .collect()
+            })
+            .unwrap_or_else(|| "    ".to_string());
+        let rendered: Vec<String> = values
+            .iter()
+            .map(|value| format!("{}{} {}", indent, key, value))
+            .collect();
+        let insert_at = match existing.first() {
+            Some(first) => *first,
+            // after the last option, before trailing blank lines and comments
+            None => (start..end)
+                .rev()
+                .find(|index| *index == start || split_option(&lines[*index]).is_some())
+                .map_or(start + 1, |index| index + 1),
+        };
+        for index in existing.iter().rev() {
+            lines.remove(*index);
+        }
+        end -= existing.len();
+        end += rendered.len();
+        lines.splice(insert_at..insert_at, rendered);
+    }
+}
+
+impl Dotfile for SshConfig {
+    fn apply(&self, old_content: &[u8]) -> Result<Option<Vec<u8>>, ApplyError> {
+        apply_utf8(
+            old_content,
+            |old_content: String| -> Result<Option<String>, Box<dyn Error + Send + Sync>> {
+                let mut lines: Vec<String> = old_content.lines().map(String::from).collect();
+                for block in self.blocks.iter() {
+                    apply_block(&mut lines, block);
+                }
+                let mut new_content = lines.join("\n");
+                if !new_content.is_empty() {
+                    new_content.push('\n');
+                }
+                if new_content == old_content {
+                    Ok(None)
+                } else {
+                    Ok(Some(new_content))
+                }
+            },
+        )
+    }
+
+    fn file_permission(&self) -> fs::Permissions {
+        self.permissions.clone()
+    }
+
+    fn ownership(&self) -> Ownership {
+        self.ownership
+    }
+
+    fn parts(&self) -> Option<Parts> {
+        Some(Parts::new(self.blocks.iter().flat_map(|block| {
+            block
+                .options
+                .iter()
+                .filter(|(_, values)| !values.is_empty())
+                .map(|(option, _)| Part::SshOption {
+                    header: block.header.clone(),
+                    option: option.clone(),
+                })
+        })))
+    }
+
+    fn merge(&mut self, y: Box<dyn Dotfile>) -> Result<(), MergeError> {
+        merge_same_type(self, y, |mut x, y| {
+            if x.permissions != y.permissions {
+                return Err(MergeError::MergeConflict {
+                    reason: "ssh configs with different permissions".to_string(),
+                });
+            }
+            for block in y.blocks {
+                let Some(existing) = x.blocks.iter_mut().find(|b| b.header == block.header) else {
+                    x.blocks.push(block);
+                    continue;
+                };
+                for (key, values) in block.options {
+                    match existing
+                        .options
+                        .iter()
+                        .find(|(k, _)| k.eq_ignore_ascii_case(&key))
+                    {
+                        Some((_, existing_values)) if *existing_values != values => {
+                            return Err(MergeError::MergeConflict {
+                                reason: format!(
+                                    "conflicting values for {} in {:?}",
+                                    key, block.header
+                                ),
+                            })
+                        }
+                        Some(_) => {}
+                        None => existing.options.push((key, values)),
+                    }
+                }
+            }
+            Ok(SshConfig {
+                ownership: merge_ownership(x.ownership, y.ownership)?,
+                ..x
+            })
+        })
+    }
+
+    fn as_any(self: Box<Self>) -> Box<dyn Any> {
+        Box::new(*self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn apply(ssh_config: &SshConfig, old_content: &str) -> Option<String> {
+        ssh_config
+            .apply(old_content.as_bytes())
+            .unwrap()
+            .map(|new_content| String::from_utf8(new_content).unwrap())
+    }
+
+    #[test]
+    fn update_owned_blocks() {
+        let mut ssh_config = SshConfig::new().with_host(
+            "bastion",
+            &[("HostName", "bastion.example.com"), ("User", "ec2-user")],
+        );
+        ssh_config
+            .merge(Box::new(SshConfig::new().with_host(
+                "dev",
+                &[
+                    ("HostName", "10.0.0.5"),
+                    ("ProxyJump", "bastion"),
+                    ("IdentityFile", "~/.ssh/id_ed25519"),
+                    ("IdentityFile", "~/.ssh/id_rsa"),
+                ],
+            )))
+            .unwrap();
+        let old_content = "\
+Include ~/.orbstack/ssh/config
+
+host=bastion
+  HostName old.example.com
+  ForwardAgent yes
+
+Host personal
+  User me
+
+Host *
+  User nobody
+";
+        let new_content = apply(&ssh_config, old_content).unwrap();
+        assert_eq!(
+            new_content,
+            "\
+Include ~/.orbstack/ssh/config
+
+host=bastion
+  HostName bastion.example.com
+  ForwardAgent yes
+  User ec2-user
+
+Host personal
+  User me
+
+Host dev
+    HostName 10.0.0.5
+    ProxyJump bastion
+    IdentityFile ~/.ssh/id_ed25519
+    IdentityFile ~/.ssh/id_rsa
+
+Host *
+  User nobody
+"
+        );
+        assert_eq!(apply(&ssh_config, &new_content), None);
+        assert_eq!(
+            apply(
+                &SshConfig::new().with_match("host dev", &[("User", "x")]),
+                ""
+            ),
+            Some("Match host dev\n    User x\n".to_string())
+        );
+    }
+
+    #[test]
+    fn merge_conflict() {
+        let mut x = SshConfig::new().with_host("dev", &[("User", "a")]);
+        let y = SshConfig::new().with_host("dev", &[("user", "b")]);
+        assert!(x.merge(Box::new(y)).is_err());
+
+        let mut x = SshConfig::new().with_host("dev", &[("User", "a")]);
+        let y = SshConfig::new()
+            .with_host("prod", &[("User", "b")])
+            .with_permissions(fs::Permissions::from_mode(0o644));
+        assert!(x.merge(Box::new(y)).is_err());
+    }
+}
@@ -0,0 +1,224 @@
+use super::{
+    apply_utf8, merge_ownership, merge_same_type,
+    parts::{Part, Parts},
+    ApplyError, Dotfile, MergeError, Ownership,
+};
+use serde_json::Value;
+use std::{any::Any, error::Error, fmt, fs, marker::PhantomData, os::unix::fs::PermissionsExt};
+
+pub(crate) type BoxError = Box<dyn Error + Send + Sync>;
+
+/// A file format that holds a tree of maps, like JSON, TOML or YAML.
+pub trait Format: Any + Default + fmt::Debug {
+    /// How the format is named in the recorded `Parts`.
+    const NAME: &'static str;
+    type Document;
+    type Node: Node;
+
+    /// An empty document if `content` is blank.
+    fn parse(content: &str) -> Result<Self::Document, BoxError>;
+    fn root(document: &mut Self::Document) -> &mut Self::Node;
+    /// `old_content` is what was parsed, for formats that follow its style.
+    fn render(document: &Self::Document, old_content: &str) -> Result<String, BoxError>;
+}
+
+/// A node of a parsed document, which a desired `Value` is deep-merged into.
+pub trait Node {
+    fn is_map(&self) -> bool;
+    /// The value at `key`, if this is a map that has it.
+    fn child_mut(&mut self, key: &str) -> Option<&mut Self>;
+    /// Adds `key` to this map.
+    fn insert(&mut self, key: &str, value: &Value) -> Result<(), BoxError>;
+    /// Replaces this node with `value`, returning whether that changed anything.
+    fn set(&mut self, value: &Value) -> Result<bool, BoxError>;
+    /// Removes `key` from this map, returning whether it was there.
+    fn remove(&mut self, key: &str) -> Result<bool, BoxError>;
+}
+
+/// A structured document that `value` is deep-merged into: maps are merged key by key, and
+/// anything else in `value` replaces what is in the file. Keys only the file has are kept. The
+/// same rules apply to every `Format`, and so do the rules for merging the values of several
+/// targets: maps are unioned, and different values at the same key are a conflict.
+#[derive(Debug)]
+pub struct Structured<F> {
+    value: Value,
+    /// Paths of keys to take out of the document.
+    absent: Vec<Vec<String>>,
+    permissions: fs::Permissions,
+    ownership: Ownership,
+    format: PhantomData<F>,
+}
+
+impl<F> Default for Structured<F> {
+    fn default() -> Self {
+        Self {
+            value: Value::Object(Default::default()),
+            absent: Vec::new(),
+            permissions: fs::Permissions::from_mode(0o644),
+            ownership: Ownership::default(),
+            format: PhantomData,
+        }
+    }
+}
+
+impl<F> Structured<F> {
+    pub fn new(value: Value) -> Self {
+        Self {
+            value,
+            ..Default::default()
+        }
+    }
+
+    /// Removes the key at `path`, like `["editor", "fontSize"]`, if the document has it.
+    pub fn without(mut self, path: Vec<String>) -> Self {
+        if !self.absent.contains(&path) {
+            self.absent.push(path);
+        }
+        self
+    }
+
+    pub fn with_permissions(self, permissions: fs::Permissions) -> Self {
+        Self {
+            permissions,
+            ..self
+        }
+    }
+
+    pub fn with_ownership(self, ownership: Ownership) -> Self {
+        Self { ownership, ..self }
+    }
+
+    #[cfg(test)]
+    pub(crate) fn value(&self) -> &Value {
+        &self.value
+    }
+}
+
+impl<F: Format> Dotfile for Structured<F> {
+    fn apply(&self, old_content: &[u8]) -> Result<Option<Vec<u8>>, ApplyError> {
+        apply_utf8(
+            old_content,
+            |old_content: String| -> Result<Option<String>, BoxError> {
+                let mut document = F::parse(&old_content)?;
+                let mut changed = deep_merge(F::root(&mut document), &self.value)?;
+                for path in self.absent.iter() {
+                    changed |= remove_path(F::root(&mut document), path)?;
+                }
+                if !changed {
+                    return Ok(None);
+                }
+                let new_content = F::render(&document, &old_content)?;
+                Ok((new_content != old_content).then_some(new_content))
+            },
+        )
+    }
+
+    fn file_permission(&self) -> fs::Permissions {
+        self.permissions.clone()
+    }
+
+    fn ownership(&self) -> Ownership {
+        self.ownership
+    }
+
+    fn merge(&mut self, y: Box<dyn Dotfile>) -> Result<(), MergeError> {
+        merge_same_type(self, y, |mut x, y| {
+            for path in y.absent {
+                if !x.absent.contains(&path) {
+                    x.absent.push(path);
+                }
+            }
+            Ok(Structured {
+                value: merge_values("", x.value, y.value)?,
+                ownership: merge_ownership(x.ownership, y.ownership)?,
+                ..x
+            })
+        })
+    }
+
+    /// The path of every value set here, down to the values that aren't maps.
+    fn parts(&self) -> Option<Parts> {
+        let mut paths = Vec::new();
+        leaf_paths(&self.value, &mut Vec::new(), &mut paths);
+        Some(Parts::new(paths.into_iter().map(|path| Part::TreeKey {
+            format: F::NAME.to_string(),
+            path,
+        })))
+    }
+
+    fn as_any(self: Box<Self>) -> Box<dyn Any> {
+        Box::new(*self)
+    }
+}
+
+/// Writes `desired` into `existing`, recursing into maps both have. Returns whether anything
+/// changed.
+pub(crate) fn deep_merge<N: Node>(existing: &mut N, desired: &Value) -> Result<bool, BoxError> {
+    match desired {
+        Value::Object(desired) if existing.is_map() => {
+            let mut changed = false;
+            for (key, desired) in desired {
+                match existing.child_mut(key) {
+                    Some(existing) => changed |= deep_merge(existing, desired)?,
+                    None => {
+                        existing.insert(key, desired)?;
+                        changed = true;
+                    }
+                }
+            }
+            Ok(changed)
+        }
+        desired => existing.set(desired),
+    }
+}
+
+/// Collects the paths from `value` to every value in it that isn't a non-empty map.
+fn leaf_paths(value: &Value, path: &mut Vec<String>, paths: &mut Vec<Vec<String>>) {
+    match value {
+        Value::Object(map) if !map.is_empty() => {
+            for (key, value) in map {
+                path.push(key.clone());
+                leaf_paths(value, path, paths);
+                path.pop();
+            }
+        }
+        _ if !path.is_empty() => paths.push(path.clone()),
+        _ => {}
+    }
+}
+
+/// Removes the key at `path` under `node`. Returns whether it was there.
+fn remove_path<N: Node>(node: &mut N, path: &[String]) -> Result<bool, BoxError> {
+    match path {
+        [] => Ok(false),
+        [key] => node.remove(key),
+        [key, rest @ ..] => match node.child_mut(key) {
+            Some(child) => remove_path(child, rest),
+            None => Ok(false),
+        },
+    }
+}
+
+/// Combines what two targets want, failing if they want different values at the same key.
+fn merge_values(path: &str, x: Value, y: Value) -> Result<Value, MergeError> {
+    match (x, y) {
+        (Value::Object(mut x), Value::Object(y)) => {
+            for (key, y) in y {
+                // in place rather than removed and re-inserted, to keep the key order
+                match x.get_mut(&key) {
+                    Some(x) => {
+                        *x = merge_values(&format!("{}/{}", path, key), x.take(), y)?;
+                    }
+                    None => {
+                        x.insert(key, y);
+                    }
+                }
+            }
+            Ok(Value::Object(x))
+        }
+        (x, y) if x == y => Ok(x),
+        (x, y) => Err(MergeError::MergeConflict {
+            reason: format!("conflicting values at {:?}: {} and {}", path, x, y),
+        }),
+    }
+}
@@ -0,0 +1,153 @@
+use super::{merge_same_type, ApplyError, Dotfile, Layout, MergeError};
+use std::{
+    any::Any,
+    os::unix::fs::PermissionsExt,
+    path::{Path, PathBuf},
+};
+
+/// A symbolic link to `target`, e.g. `~/.config/nvim` pointing into a checked out repo.
+#[derive(Debug, Default)]
+pub struct Symlink {
+    target: PathBuf,
+    existing_file: ExistingFilePolicy,
+}
+
+/// What to do when a regular file or directory, rather than a symlink, is already at the path.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum ExistingFilePolicy {
+    /// Move it aside to `<name>.dotfiles-bak` and create the link.
+    #[default]
+    Backup,
+    /// Leave it alone and fail.
+    Refuse,
+    /// Overwrite it. Only the backup store (if enabled) keeps its content. Directories are
+    /// never replaced.
+    Replace,
+}
+
+impl Symlink {
+    pub fn new(target: PathBuf) -> Self {
+        Self {
+            target,
+            ..Default::default()
+        }
+    }
+
+    pub fn with_existing_file(self, existing_file: ExistingFilePolicy) -> Self {
+        Self {
+            existing_file,
+            ..self
+        }
+    }
+
+    pub fn target(&self) -> &Path {
+        &self.target
+    }
+}
+
+impl Dotfile for Symlink {
+    /// Links have no content of their own; see `layout`.
+    fn apply(&self, _old_content: &[u8]) -> Result<Option<Vec<u8>>, ApplyError> {
+        Ok(None)
+    }
+
+    fn file_permission(&self) -> std::fs::Permissions {
+        std::fs::Permissions::from_mode(0o777)
+    }
+
+    fn layout(&self) -> Layout<'_> {
+        Layout::Symlink {
+            target: &self.target,
+            existing_file: self.existing_file,
+        }
+    }
+
+    fn merge(&mut self, y: Box<dyn Dotfile>) -> Result<(), MergeError> {
+        merge_same_type(self, y, |x, y| {
+            if x.target != y.target {
+                Err(MergeError::MergeConflict {
+                    reason: format!(
+                        "symlinks to different targets {:?} and {:?}",
+                        x.target, y.target
+                    ),
+                })
+            } else if x.existing_file != y.existing_file {
+                Err(MergeError::MergeConflict {
+                    reason: "symlinks with different policies for existing files".to_string(),
+                })
+            } else {
+                Ok(x)
+            }
+        })
+    }
+
+    fn as_any(self: Box<Self>) -> Box<dyn Any> {
+        Box::new(*self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dotfile::{apply_dotfiles, ApplyOptions};
+    use std::{collections::HashMap, fs};
+
+    fn apply(path: &Path, symlink: Symlink) -> crate::dotfile::ApplyReport {
+        let dotfiles: HashMap<PathBuf, Box<dyn Dotfile>> =
+            HashMap::from([(path.to_path_buf(), Box::new(symlink) as Box<dyn Dotfile>)]);
+        apply_dotfiles(dotfiles, &ApplyOptions::new())
+    }
+
+    #[test]
+    fn create_and_fix_link() {
+        let dir = tempfile::tempdir().unwrap();
+        let link = dir.path().join(".config/nvim");
+        let report = apply(&link, Symlink::new(PathBuf::from("/repo/nvim")));
+        assert!(report.errors.is_empty());
+        assert!(report.changed_files.contains(&link));
+        assert_eq!(fs::read_link(&link).unwrap(), Path::new("/repo/nvim"));
+
+        let report = apply(&link, Symlink::new(PathBuf::from("/repo/nvim")));
+        assert!(report.changed_files.is_empty());
+
+        let report = apply(&link, Symlink::new(PathBuf::from("/other/nvim")));
+        assert!(report.changed_files.contains(&link));
+        assert_eq!(fs::read_link(&link).unwrap(), Path::new("/other/nvim"));
+    }
+
+    #[test]
+    fn existing_file_policies() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("settings.json");
+        fs::write(&path, "by hand").unwrap();
+
+        let refused = apply(
+            &path,
+            Symlink::new(PathBuf::from("/repo/settings.json"))
+                .with_existing_file(ExistingFilePolicy::Refuse),
+        );
+        assert!(matches!(
+            refused.errors.get(&path),
+            Some(ApplyError::PathOccupied { .. })
+        ));
+        assert_eq!(fs::read_to_string(&path).unwrap(), "by hand");
+
+        let backed_up = apply(&path, Symlink::new(PathBuf::from("/repo/settings.json")));
+        assert!(backed_up.errors.is_empty());
+        assert!(path.is_symlink());
+        assert_eq!(
+            fs::read_to_string(dir.path().join("settings.json.dotfiles-bak")).unwrap(),
+            "by hand"
+        );
+
+        let dir_path = dir.path().join("nvim");
+        fs::create_dir(&dir_path).unwrap();
+        let replaced = apply(
+            &dir_path,
+            Symlink::new(PathBuf::from("/repo/nvim"))
+                .with_existing_file(ExistingFilePolicy::Replace),
+        );
+        assert!(replaced.errors.contains_key(&dir_path));
+        assert!(dir_path.is_dir());
+    }
+}
@@ -0,0 +1,171 @@
+use super::structured::{BoxError, Format, Node, Structured};
+use serde_json::Value;
+use toml_edit::{DocumentMut, Item};
+
+/// TOML, edited in place: comments, key order and the formatting of values that don't change
+/// are kept.
+pub type Toml = Structured<TomlFormat>;
+
+#[derive(Debug, Default)]
+pub struct TomlFormat;
+
+impl Format for TomlFormat {
+    const NAME: &'static str = "toml";
+    type Document = DocumentMut;
+    type Node = Item;
+
+    fn parse(content: &str) -> Result<DocumentMut, BoxError> {
+        Ok(content.parse()?)
+    }
+
+    fn root(document: &mut DocumentMut) -> &mut Item {
+        document.as_item_mut()
+    }
+
+    fn render(document: &DocumentMut, _old_content: &str) -> Result<String, BoxError> {
+        Ok(document.to_string())
+    }
+}
+
+impl Node for Item {
+    fn is_map(&self) -> bool {
+        self.is_table_like()
+    }
+
+    fn child_mut(&mut self, key: &str) -> Option<&mut Self> {
+        self.as_table_like_mut()
+            .and_then(|table| table.get_mut(key))
+    }
+
+    fn insert(&mut self, key: &str, value: &Value) -> Result<(), BoxError> {
+        let item = to_item(value)?;
+        if let Some(table) = self.as_table_like_mut() {
+            table.insert(key, item);
+        }
+        Ok(())
+    }
+
+    fn set(&mut self, value: &Value) -> Result<bool, BoxError> {
+        if self.as_value().and_then(to_json).as_ref() == Some(value) {
+            return Ok(false);
+        }
+        let mut item = to_item(value)?;
+        // keep the comment after a replaced value
+        if let (Some(old), Item::Value(new)) = (self.as_value(), &mut item) {
+            *new.decor_mut() = old.decor().clone();
+        }
+        *self = item;
+        Ok(true)
+    }
+
+    fn remove(&mut self, key: &str) -> Result<bool, BoxError> {
+        Ok(self
+            .as_table_like_mut()
+            .is_some_and(|table| table.remove(key).is_some()))
+    }
+}
+
+/// Objects become tables, or inline tables inside arrays.
+fn to_item(value: &Value) -> Result<Item, BoxError> {
+    match value {
+        Value::Object(map) => {
+            let mut table = toml_edit::Table::new();
+            for (key, value) in map {
+                table.insert(key, to_item(value)?);
+            }
+            Ok(Item::Table(table))
+        }
+        value => Ok(Item::Value(to_value(value)?)),
+    }
+}
+
+fn to_value(value: &Value) -> Result<toml_edit::Value, BoxError> {
+    Ok(match value {
+        Value::Null => return Err("TOML has no null".into()),
+        Value::Bool(b) => (*b).into(),
+        Value::Number(n) => match n.as_i64() {
+            Some(i) => i.into(),
+            None => n.as_f64().ok_or("number out of range for TOML")?.into(),
+        },
+        Value::String(s) => s.as_str().into(),
+        Value::Array(values) => {
+            let mut array = toml_edit::Array::new();
+            for value in values {
+                array.push(to_value(value)?);
+            }
+            array.into()
+        }
+        Value::Object(map) => {
+            let mut table = toml_edit::InlineTable::new();
+            for (key, value) in map {
+                table.insert(key, to_value(value)?);
+            }
+            table.into()
+        }
+    })
+}
+
+/// `None` for what JSON can't represent, like dates, which then always count as different.
+fn to_json(value: &toml_edit::Value) -> Option<Value> {
+    Some(match value {
+        toml_edit::Value::String(s) => Value::String(s.value().clone()),
+        toml_edit::Value::Integer(i) => Value::from(*i.value()),
+        toml_edit::Value::Float(f) => Value::from(*f.value()),
+        toml_edit::Value::Boolean(b) => Value::Bool(*b.value()),
+        toml_edit::Value::Datetime(_) => return None,
+        toml_edit::Value::Array(array) => {
+            Value::Array(array.iter().map(to_json).collect::<Option<_>>()?)
+        }
+        toml_edit::Value::InlineTable(table) => Value::Object(
+            table
+                .iter()
+                .map(|(key, value)| Some((key.to_string(), to_json(value)?)))
+                .collect::<Option<_>>()?,
+        ),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dotfile::{json::Json, Dotfile};
+    use serde_json::json;
+
+    #[test]
+    fn edit_in_place() {
+        let cargo_config = Toml::new(json!({
+            "build": { "jobs": 8 },
+            "net": { "git-fetch-with-cli": true },
+        }));
+        let old_content = r#"# my cargo config
+[build]
+jobs = 4 # laptop
+target-dir = "/tmp/cargo"
+"#;
+        let new_content = cargo_config.apply(old_content.as_bytes()).unwrap().unwrap();
+        assert_eq!(
+            String::from_utf8(new_content.clone()).unwrap(),
+            r#"# my cargo config
+[build]
+jobs = 8 # laptop
+target-dir = "/tmp/cargo"
+
+[net]
+git-fetch-with-cli = true
+"#
+        );
+        assert!(cargo_config.apply(&new_content).unwrap().is_none());
+    }
+
+    #[test]
+    fn same_merge_rules_as_json() {
+        let x = json!({ "a": { "b": 1 } });
+        let y = json!({ "a": { "b": 2 } });
+        let mut toml = Toml::new(x.clone());
+        let mut json = Json::new(x);
+        assert_eq!(
+            toml.merge(Box::new(Toml::new(y.clone()))),
+            json.merge(Box::new(Json::new(y)))
+        );
+    }
+}
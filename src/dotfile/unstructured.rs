@@ -1,11 +1,15 @@
-use super::{merge_same_type, ApplyError, Dotfile, MergeError};
+use super::{merge_ownership, merge_same_type, ApplyError, Dotfile, MergeError, Ownership};
+use crate::secret::Secret;
 use std::any::Any;
+use std::fmt;
 use std::os::unix::fs::PermissionsExt;
 
-#[derive(Debug)]
 pub struct Unstructured {
     content: Vec<u8>,
     permissions: std::fs::Permissions,
+    directory_permission: Option<std::fs::Permissions>,
+    ownership: Ownership,
+    secret: bool,
 }
 
 impl Default for Unstructured {
@@ -13,6 +17,9 @@ impl Default for Unstructured {
         Self {
             content: Vec::new(),
             permissions: std::fs::Permissions::from_mode(0o644),
+            directory_permission: None,
+            ownership: Ownership::default(),
+            secret: false,
         }
     }
 }
@@ -38,6 +45,50 @@ impl Unstructured {
             ..self
         }
     }
+
+    /// The mode of the directory the file is in. `None` leaves it as it is, e.g. for a secret in
+    /// a directory other programs read, like `/etc/nix`.
+    pub fn with_directory_permission(
+        self,
+        directory_permission: Option<std::fs::Permissions>,
+    ) -> Self {
+        Self {
+            directory_permission,
+            ..self
+        }
+    }
+
+    pub fn with_ownership(self, ownership: Ownership) -> Self {
+        Self { ownership, ..self }
+    }
+
+    /// Content from a `Decrpytor`. It defaults to mode 0600 in a 0700 directory, and is redacted
+    /// wherever it would be displayed.
+    pub fn from_secret(secret: Secret) -> Self {
+        Self {
+            content: secret.into_bytes(),
+            permissions: std::fs::Permissions::from_mode(0o600),
+            directory_permission: Some(std::fs::Permissions::from_mode(0o700)),
+            secret: true,
+            ..Default::default()
+        }
+    }
+}
+
+impl fmt::Debug for Unstructured {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut debug = f.debug_struct("Unstructured");
+        if self.secret {
+            debug.field("content", &"<redacted>");
+        } else {
+            debug.field("content", &String::from_utf8_lossy(&self.content));
+        }
+        debug
+            .field("permissions", &self.permissions)
+            .field("ownership", &self.ownership)
+            .field("secret", &self.secret)
+            .finish()
+    }
 }
 
 impl Dotfile for Unstructured {
@@ -53,10 +104,26 @@ impl Dotfile for Unstructured {
         self.permissions.clone()
     }
 
+    fn directory_permission(&self) -> Option<std::fs::Permissions> {
+        self.directory_permission.clone()
+    }
+
+    fn ownership(&self) -> Ownership {
+        self.ownership
+    }
+
+    fn is_secret(&self) -> bool {
+        self.secret
+    }
+
     fn merge(&mut self, y: Box<dyn Dotfile>) -> Result<(), MergeError> {
         merge_same_type(self, y, |x, y| {
             if x.content == y.content {
-                Ok(x)
+                Ok(Unstructured {
+                    ownership: merge_ownership(x.ownership, y.ownership)?,
+                    secret: x.secret || y.secret,
+                    ..x
+                })
             } else {
                 Err(MergeError::MergeConflict {
                     reason: "Unstructured files with different content cannot be merged"
@@ -0,0 +1,161 @@
+use super::structured::{BoxError, Format, Node, Structured};
+
+/// YAML. The parser drops comments and formatting, so a file with comments is never rewritten:
+/// applying changes to it is an error instead. Key order survives a rewrite.
+pub type Yaml = Structured<YamlFormat>;
+
+#[derive(Debug, Default)]
+pub struct YamlFormat;
+
+impl Format for YamlFormat {
+    const NAME: &'static str = "yaml";
+    type Document = serde_yaml_ng::Value;
+    type Node = serde_yaml_ng::Value;
+
+    fn parse(content: &str) -> Result<serde_yaml_ng::Value, BoxError> {
+        if content.trim().is_empty() {
+            Ok(serde_yaml_ng::Value::Mapping(Default::default()))
+        } else {
+            Ok(serde_yaml_ng::from_str(content)?)
+        }
+    }
+
+    fn root(document: &mut serde_yaml_ng::Value) -> &mut serde_yaml_ng::Value {
+        document
+    }
+
+    fn render(document: &serde_yaml_ng::Value, old_content: &str) -> Result<String, BoxError> {
+        if has_comment(old_content) {
+            return Err("the file has comments, which rewriting it would lose".into());
+        }
+        Ok(serde_yaml_ng::to_string(document)?)
+    }
+}
+
+impl Node for serde_yaml_ng::Value {
+    fn is_map(&self) -> bool {
+        self.is_mapping()
+    }
+
+    fn child_mut(&mut self, key: &str) -> Option<&mut Self> {
+        self.as_mapping_mut().and_then(|map| map.get_mut(key))
+    }
+
+    fn insert(&mut self, key: &str, value: &serde_json::Value) -> Result<(), BoxError> {
+        let value = serde_yaml_ng::to_value(value)?;
+        if let Some(map) = self.as_mapping_mut() {
+            map.insert(key.into(), value);
+        }
+        Ok(())
+    }
+
+    fn set(&mut self, value: &serde_json::Value) -> Result<bool, BoxError> {
+        let value = serde_yaml_ng::to_value(value)?;
+        if *self == value {
+            Ok(false)
+        } else {
+            *self = value;
+            Ok(true)
+        }
+    }
+
+    fn remove(&mut self, key: &str) -> Result<bool, BoxError> {
+        Ok(self
+            .as_mapping_mut()
+            .is_some_and(|map| map.shift_remove(key).is_some()))
+    }
+}
+
+/// Whether `content` has a `#` comment. A `#` only starts one at the start of a line or after
+/// whitespace, and never inside a quoted or block scalar.
+fn has_comment(content: &str) -> bool {
+    // the indentation of the line that started the block scalar being read
+    let mut block_indent = None;
+    let mut quote = None;
+    for line in content.lines() {
+        let indent = line.len() - line.trim_start().len();
+        if let Some(header_indent) = block_indent {
+            if line.trim().is_empty() || indent > header_indent {
+                continue;
+            }
+            block_indent = None;
+        }
+        let mut previous = ' ';
+        let mut chars = line.chars().peekable();
+        while let Some(c) = chars.next() {
+            match (quote, c) {
+                (Some('"'), '\\') => {
+                    chars.next();
+                }
+                (Some('\''), '\'') if chars.peek() == Some(&'\'') => {
+                    chars.next();
+                }
+                (Some(q), c) if c == q => quote = None,
+                (Some(_), _) => {}
+                // a quote only starts a scalar, otherwise it is part of a plain one, as in `it's`
+                (None, '"' | '\'') if previous.is_whitespace() || "[{,".contains(previous) => {
+                    quote = Some(c)
+                }
+                (None, '#') if previous.is_whitespace() => return true,
+                _ => {}
+            }
+            previous = c;
+        }
+        // a block scalar's header ends its line, like `key: |` or `- >-`
+        let is_block_header = line.split_whitespace().last().is_some_and(|token| {
+            token.starts_with(['|', '>'])
+                && token[1..]
+                    .chars()
+                    .all(|c| c == '+' || c == '-' || c.is_ascii_digit())
+        });
+        if quote.is_none() && is_block_header {
+            block_indent = Some(indent);
+        }
+    }
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dotfile::Dotfile;
+    use serde_json::json;
+
+    #[test]
+    fn merge_into_hosts() {
+        let hosts = Yaml::new(json!({
+            "github.com": { "git_protocol": "ssh" },
+        }));
+        let old_content = "github.com:\n  user: kokobd\n  git_protocol: https\n";
+        let new_content = hosts.apply(old_content.as_bytes()).unwrap().unwrap();
+        assert_eq!(
+            String::from_utf8(new_content.clone()).unwrap(),
+            "github.com:\n  user: kokobd\n  git_protocol: ssh\n"
+        );
+        assert!(hosts.apply(&new_content).unwrap().is_none());
+    }
+
+    #[test]
+    fn keep_comments() {
+        let hosts = Yaml::new(json!({ "github.com": { "git_protocol": "ssh" } }));
+        let old_content = "github.com:\n  # set by gh\n  git_protocol: https\n";
+        assert!(hosts.apply(old_content.as_bytes()).is_err());
+        assert!(hosts
+            .apply(b"github.com:\n  git_protocol: ssh # already\n")
+            .unwrap()
+            .is_none());
+
+        assert!(has_comment("a: 1 # one\n"));
+        assert!(has_comment("a: |\n  text\n# after the block\n"));
+        for content in [
+            "a: \"#1\"\n",
+            "a: 'it''s # not'\n",
+            "a: b#c\n",
+            "a: it's\nb: c\n",
+            "a: |\n  # text\n\n  # more\nb: 1\n",
+            "- >-\n  # text\n",
+        ] {
+            assert!(!has_comment(content), "{:?}", content);
+        }
+    }
+}
@@ -1,17 +1,47 @@
-mod dotfile;
+mod backup;
+pub mod dotfile;
+mod nix_key;
+mod plan;
+mod prune;
 mod secret;
+mod state;
+pub mod store_url;
 mod targets;
+pub mod upload_queue;
+use backup::{BackupRun, BackupStore, RestoreError};
 use base64::Engine;
-use dotfile::apply_dotfiles;
-use dotfile::{merge_dotfiles, Dotfile};
+use dotfile::{apply_dotfiles, ApplyOptions, ApplyReport};
+use dotfile::{expand_dotfiles, merge_dotfiles, parts::Parts, Dotfile, Ownership};
+use nix_key::NixKeyError;
+use plan::{plan_dotfiles, ChangeKind};
+use prune::{find_orphans, prune_orphan};
 use secret::{AgeDecryptor, AgeIdentityParseError};
-use std::{collections::HashMap, path::PathBuf};
+use state::State;
+use std::{
+    collections::{HashMap, HashSet},
+    path::PathBuf,
+};
+use store_url::StoreUrl;
 use thiserror::Error;
+use upload_queue::{FlushOptions, NixUploader, UploadQueue};
 
-#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, clap::ValueEnum)]
+#[derive(
+    Debug,
+    Copy,
+    Clone,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    clap::ValueEnum,
+    serde::Serialize,
+    serde::Deserialize,
+)]
+#[serde(rename_all = "kebab-case")]
 pub enum Target {
     PersonalNixCache,
     Git,
+    #[serde(rename = "aws")]
     AWS,
 }
 
@@ -30,6 +60,11 @@ pub struct Config {
     region: Region,
     decryptor: AgeDecryptor,
     home_dir: PathBuf,
+    /// Owner of the home directory, which files written there should have even when running
+    /// through sudo.
+    home_owner: Ownership,
+    /// This binary, which the Nix post-build hook gets a root-owned copy of to call back into.
+    bootstrap_exe: PathBuf,
 }
 
 impl Config {
@@ -40,7 +75,9 @@ impl Config {
         Ok(Self {
             region,
             decryptor: AgeDecryptor::new(ssh_private_key)?,
+            home_owner: Ownership::of(&home_dir).unwrap_or_default(),
             home_dir,
+            bootstrap_exe: std::env::current_exe().map_err(InitConfigError::NoCurrentExe)?,
         })
     }
 }
@@ -49,6 +86,8 @@ impl Config {
 pub enum InitConfigError {
     #[error("Could not find home directory")]
     NoHomeDir,
+    #[error("Could not find the running executable: {0}")]
+    NoCurrentExe(std::io::Error),
     #[error("Could not parse ssh private key")]
     AgeIdentityParseError(#[from] AgeIdentityParseError),
     #[error("Provided ssh private key is not valid base64")]
@@ -61,24 +100,286 @@ pub enum BootStrapError {
     DecryptError(#[from] DecryptError),
 }
 
-pub fn bootstrap(config: Config, targets: Vec<Target>) -> anyhow::Result<()> {
+struct CollectedDotfiles {
+    dotfiles: HashMap<PathBuf, Box<dyn Dotfile>>,
+    /// The targets that contributed to each path.
+    owners: HashMap<PathBuf, Vec<Target>>,
+    /// What the dotfile of each path puts in it, if the file is shared with others.
+    parts: HashMap<PathBuf, Option<Parts>>,
+}
+
+fn collect_dotfiles(config: &Config, targets: Vec<Target>) -> anyhow::Result<CollectedDotfiles> {
+    let mut owners = HashMap::<PathBuf, Vec<Target>>::new();
     let dotfiles = merge_dotfiles({
         let mut dotfiles_vec = Vec::new();
         for target in targets {
-            let dotfiles = target.bootstrap_dotfiles(&config)?;
+            let dotfiles = target.bootstrap_dotfiles(config)?;
+            for path in dotfiles.keys() {
+                owners.entry(path.clone()).or_default().push(target);
+            }
             dotfiles_vec.push(dotfiles);
         }
         dotfiles_vec
     })?;
-    let (changed_files, errors) = apply_dotfiles(dotfiles);
-    if changed_files.is_empty() {
+    let (dotfiles, expanded_from) = expand_dotfiles(dotfiles)?;
+    // an expanded file belongs to the targets of what it was expanded from
+    let owners = dotfiles
+        .keys()
+        .filter_map(|path| {
+            let mut targets = owners.get(path).cloned().unwrap_or_default();
+            for from in expanded_from.get(path).into_iter().flatten() {
+                for target in owners.get(from).into_iter().flatten() {
+                    if !targets.contains(target) {
+                        targets.push(*target);
+                    }
+                }
+            }
+            (!targets.is_empty()).then(|| (path.clone(), targets))
+        })
+        .collect();
+    let parts = dotfiles
+        .iter()
+        .map(|(path, dotfile)| (path.clone(), dotfile.parts()))
+        .collect();
+    Ok(CollectedDotfiles {
+        dotfiles,
+        owners,
+        parts,
+    })
+}
+
+#[derive(Debug, Clone)]
+pub struct BootstrapOptions {
+    transactional: bool,
+    keep_backups: usize,
+    prune: bool,
+}
+
+impl Default for BootstrapOptions {
+    fn default() -> Self {
+        Self {
+            transactional: false,
+            keep_backups: 10,
+            prune: false,
+        }
+    }
+}
+
+impl BootstrapOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_transaction(mut self, transactional: bool) -> Self {
+        self.transactional = transactional;
+        self
+    }
+
+    /// How many backup runs to keep; older ones are deleted after each bootstrap.
+    pub fn with_keep_backups(mut self, keep_backups: usize) -> Self {
+        self.keep_backups = keep_backups;
+        self
+    }
+
+    /// Remove files that a previous run wrote but no target produces anymore, or restore what was
+    /// there before they were managed.
+    pub fn with_prune(mut self, prune: bool) -> Self {
+        self.prune = prune;
+        self
+    }
+}
+
+/// Records every file `apply_dotfiles` wrote or found already right as managed by the targets it
+/// came from, along with its parts. Files it didn't get to, like those after a failure in a
+/// transaction, are left out, so that they are never pruned as if they were ours.
+fn record_applied(
+    state: &mut State,
+    report: &mut ApplyReport,
+    collected: &CollectedDotfiles,
+    backup_run: &BackupRun,
+) {
+    let backed_up: HashSet<PathBuf> = backup_run
+        .entries()
+        .into_iter()
+        .map(|entry| entry.path)
+        .collect();
+    for path in report.applied.iter() {
+        let Some(targets) = collected.owners.get(path) else {
+            continue;
+        };
+        let backup_id = backed_up.contains(path).then(|| backup_run.id());
+        let parts = collected.parts.get(path).cloned().flatten();
+        if let Err(err) = state.record(path, targets.clone(), backup_id, parts) {
+            report.errors.insert(path.clone(), err);
+        }
+    }
+}
+
+pub fn bootstrap(
+    config: Config,
+    targets: Vec<Target>,
+    options: &BootstrapOptions,
+) -> anyhow::Result<()> {
+    let mut collected = collect_dotfiles(&config, targets.clone())?;
+    let state_path = State::path(&config.home_dir);
+    let mut state = State::load(&state_path)?;
+    let orphans = find_orphans(&state, &targets, &collected.parts);
+    // before nix.conf points at the hook that runs it
+    if collected
+        .owners
+        .values()
+        .any(|owners| owners.contains(&Target::PersonalNixCache))
+    {
+        targets::personal_nix_cache::install_hook_exe(&config)?;
+    }
+    let backups = BackupStore::in_home(&config.home_dir);
+    let backup_run = backups.begin_run();
+    let mut report = apply_dotfiles(
+        std::mem::take(&mut collected.dotfiles),
+        &ApplyOptions::new()
+            .with_transaction(options.transactional)
+            .with_backup(backup_run.clone()),
+    );
+    record_applied(&mut state, &mut report, &collected, &backup_run);
+    let mut prune_errors = Vec::new();
+    if !orphans.is_empty() {
+        if !options.prune {
+            println!("orphaned files (run with --prune to clean them up): ");
+            for orphan in orphans.iter() {
+                println!("- {}", orphan);
+            }
+        } else if options.transactional && !report.errors.is_empty() {
+            println!("not pruning orphaned files, because the transaction was rolled back");
+        } else {
+            println!("pruned files: ");
+            for orphan in orphans {
+                // shared files come first, and may still point at the files after them
+                if orphan.parts.is_none() && !prune_errors.is_empty() {
+                    println!("not pruning the remaining files, because a shared file failed");
+                    break;
+                }
+                match prune_orphan(&orphan, &backups, &backup_run) {
+                    Ok(()) => {
+                        println!("- {}", orphan);
+                        match &orphan.parts {
+                            Some(parts) => state.forget_parts(&orphan.path, parts),
+                            None => state.forget(&orphan.path),
+                        }
+                    }
+                    Err(err) => prune_errors.push((orphan.path, err)),
+                }
+            }
+        }
+    }
+    state.save(&state_path)?;
+    if report.changed_files.is_empty()
+        && report.permissions_fixed.is_empty()
+        && report.ownership_changed.is_empty()
+    {
         println!("no files changed");
-    } else {
+    } else if !report.changed_files.is_empty() {
         println!("changed files: ");
-        for file in changed_files {
+        for file in report.changed_files {
             println!("- {}", file.display());
         }
     }
+    if !report.permissions_fixed.is_empty() {
+        println!("permissions fixed: ");
+        for file in report.permissions_fixed {
+            println!("- {}", file.display());
+        }
+    }
+    if !report.ownership_changed.is_empty() {
+        println!("ownership changed: ");
+        for file in report.ownership_changed {
+            println!("- {}", file.display());
+        }
+    }
+    if !report.rolled_back.is_empty() {
+        println!("rolled back: ");
+        for file in report.rolled_back {
+            println!("- {}", file.display());
+        }
+    }
+    let errors = report.errors;
+    if !errors.is_empty() || !prune_errors.is_empty() {
+        println!("errors: ");
+        for (file, error) in errors.into_iter() {
+            println!("- {}: {:?}", file.display(), error);
+        }
+        for (file, error) in prune_errors.into_iter() {
+            println!("- {}: {}", file.display(), error);
+        }
+    }
+    if !backup_run.entries().is_empty() {
+        println!("previous content saved in backup run {}", backup_run.id());
+    }
+    backups.prune(options.keep_backups, &state.referenced_backups())?;
+
+    Ok(())
+}
+
+/// Puts files back the way they were before a bootstrap run (the latest one if `run` is `None`).
+/// If `paths` is empty, every file saved in that run is restored.
+pub fn restore(run: Option<String>, paths: Vec<PathBuf>) -> anyhow::Result<()> {
+    let home_dir = dirs::home_dir().ok_or(InitConfigError::NoHomeDir)?;
+    let backups = BackupStore::in_home(&home_dir);
+    let run = match run {
+        Some(id) => backups.run(&id)?,
+        None => backups.latest_run()?,
+    };
+    let entries = run.entries();
+    let selected = if paths.is_empty() {
+        entries
+    } else {
+        let mut selected = Vec::new();
+        for path in paths {
+            let path = std::path::absolute(&path)?;
+            match entries.iter().find(|entry| entry.path == path) {
+                Some(entry) => selected.push(entry.clone()),
+                None => {
+                    return Err(RestoreError::PathNotInRun {
+                        run: run.id().to_string(),
+                        path,
+                    }
+                    .into())
+                }
+            }
+        }
+        selected
+    };
+    for entry in selected {
+        run.restore(&entry)?;
+        println!("restored {} from run {}", entry.path.display(), run.id());
+    }
+
+    Ok(())
+}
+
+/// Prints what `bootstrap` would change, as a unified diff per path, without writing anything.
+pub fn plan(config: Config, targets: Vec<Target>) -> anyhow::Result<()> {
+    let CollectedDotfiles {
+        dotfiles, parts, ..
+    } = collect_dotfiles(&config, targets.clone())?;
+    let state = State::load(&State::path(&config.home_dir))?;
+    let orphans = find_orphans(&state, &targets, &parts);
+    let (changes, errors) = plan_dotfiles(&dotfiles);
+    for change in changes.iter() {
+        print!("{}", change);
+    }
+    for orphan in orphans.iter() {
+        println!("orphaned, pruned with --prune: {}", orphan);
+    }
+    let count = |kind: ChangeKind| changes.iter().filter(|c| c.kind == kind).count();
+    println!(
+        "plan: {} to create, {} to update, {} to delete, {} permission-only, {} unchanged, {} orphaned",
+        count(ChangeKind::Create),
+        count(ChangeKind::Update),
+        count(ChangeKind::Delete),
+        count(ChangeKind::PermissionOnly),
+        count(ChangeKind::Unchanged),
+        orphans.len(),
+    );
     if !errors.is_empty() {
         println!("errors: ");
         for (file, error) in errors.into_iter() {
@@ -89,15 +390,80 @@ pub fn bootstrap(config: Config, targets: Vec<Target>) -> anyhow::Result<()> {
     Ok(())
 }
 
+/// Compares the files written by previous bootstrap runs with what is on disk now. This only
+/// needs the recorded state, not the ssh key.
+pub fn status() -> anyhow::Result<()> {
+    let home_dir = dirs::home_dir().ok_or(InitConfigError::NoHomeDir)?;
+    let state = State::load(&State::path(&home_dir))?;
+    if state.files().is_empty() {
+        println!("no managed files recorded");
+    }
+    for (path, status) in state.status() {
+        match status {
+            Ok(status) => println!("{}: {}", status, path.display()),
+            Err(err) => println!("error: {}: {:?}", path.display(), err),
+        }
+    }
+
+    Ok(())
+}
+
+/// Queues the store paths Nix just built, from `OUT_PATHS`, for `flush_uploads`. The Nix
+/// post-build hook calls this, so it must be quick.
+pub fn post_build_hook(spool_dir: PathBuf) -> anyhow::Result<()> {
+    let out_paths = std::env::var("OUT_PATHS").unwrap_or_default();
+    let paths: Vec<String> = out_paths.split_whitespace().map(String::from).collect();
+    UploadQueue::new(spool_dir).enqueue(&paths)?;
+    println!("queued {} paths for upload", paths.len());
+
+    Ok(())
+}
+
+/// Signs the store paths queued by `post_build_hook` with `key_file` and copies them to `to`.
+pub fn flush_uploads(
+    spool_dir: PathBuf,
+    key_file: PathBuf,
+    to: StoreUrl,
+    retries: usize,
+) -> anyhow::Result<()> {
+    let report = UploadQueue::new(spool_dir).flush(
+        &NixUploader::new(key_file, to),
+        &FlushOptions::new().with_retries(retries),
+    )?;
+    if report.busy {
+        println!("another flush is running");
+        return Ok(());
+    }
+    for path in report.missing.iter() {
+        println!("skipped {}: no longer in the store", path);
+    }
+    for (file, err) in report.failed.iter() {
+        println!("failed to upload {}: {}", file.display(), err);
+    }
+    for file in report.given_up.iter() {
+        println!("gave up on {}", file.display());
+    }
+    println!(
+        "uploaded {} paths, skipped {} missing",
+        report.uploaded.len(),
+        report.missing.len()
+    );
+    if !report.failed.is_empty() {
+        anyhow::bail!("failed to upload {} queued files", report.failed.len());
+    }
+
+    Ok(())
+}
+
 impl Target {
     fn bootstrap_dotfiles(
         self,
         config: &Config,
-    ) -> Result<HashMap<PathBuf, Box<dyn Dotfile>>, DecryptError> {
+    ) -> Result<HashMap<PathBuf, Box<dyn Dotfile>>, TargetError> {
         match self {
             Target::PersonalNixCache => targets::personal_nix_cache::dotfiles(config),
-            Target::Git => targets::git::dotfiles(config),
-            Target::AWS => targets::aws::dotfiles(config),
+            Target::Git => Ok(targets::git::dotfiles(config)?),
+            Target::AWS => Ok(targets::aws::dotfiles(config)?),
         }
     }
 }
@@ -108,3 +474,81 @@ pub struct DecryptError {
     path: String,
     error: Box<dyn std::error::Error + Send + Sync>,
 }
+
+#[derive(Debug, Error)]
+pub enum TargetError {
+    #[error(transparent)]
+    Decrypt(#[from] DecryptError),
+    #[error(transparent)]
+    NixKey(#[from] NixKeyError),
+    #[error("Could not install the running executable at {path:?}: {err}")]
+    InstallExe { path: PathBuf, err: std::io::Error },
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use dotfile::{nix_conf::NixConf, unstructured::Unstructured};
+    use std::{fs, os::unix::fs::PermissionsExt};
+
+    #[test]
+    fn aborted_transaction_records_only_applied_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let backups = BackupStore::new(dir.path().join("backups"));
+        let (a, b, c) = (
+            dir.path().join("a"),
+            dir.path().join("b"),
+            dir.path().join("c"),
+        );
+        fs::write(&a, "managed a").unwrap();
+        fs::set_permissions(&a, fs::Permissions::from_mode(0o644)).unwrap();
+        // not UTF-8, so the transaction stops here before getting to `c`
+        fs::write(&b, [0xff, 0xfe]).unwrap();
+        fs::write(&c, "mine").unwrap();
+        let dotfiles: HashMap<PathBuf, Box<dyn Dotfile>> = HashMap::from([
+            (
+                a.clone(),
+                Box::new(Unstructured::new_utf8("managed a".to_string())) as Box<dyn Dotfile>,
+            ),
+            (b.clone(), Box::new(NixConf::new())),
+            (
+                c.clone(),
+                Box::new(Unstructured::new_utf8("managed c".to_string())),
+            ),
+        ]);
+        let collected = CollectedDotfiles {
+            owners: dotfiles
+                .keys()
+                .map(|path| (path.clone(), vec![Target::Git]))
+                .collect(),
+            parts: dotfiles
+                .iter()
+                .map(|(path, dotfile)| (path.clone(), dotfile.parts()))
+                .collect(),
+            dotfiles: HashMap::new(),
+        };
+        let backup_run = backups.begin_run();
+        let mut report = apply_dotfiles(
+            dotfiles,
+            &ApplyOptions::new()
+                .with_transaction(true)
+                .with_backup(backup_run.clone()),
+        );
+        let mut state = State::default();
+        record_applied(&mut state, &mut report, &collected, &backup_run);
+        assert_eq!(
+            state.files().keys().collect::<Vec<_>>(),
+            vec![&a],
+            "only the file that was verified is recorded"
+        );
+
+        // the next run no longer produces any of them
+        let orphans = find_orphans(&state, &[Target::Git], &HashMap::new());
+        for orphan in orphans.iter() {
+            prune_orphan(orphan, &backups, &backups.begin_run()).unwrap();
+        }
+        assert_eq!(fs::read_to_string(&c).unwrap(), "mine");
+        assert!(fs::read(&b).is_ok());
+        assert!(!a.exists());
+    }
+}
@@ -0,0 +1,172 @@
+use base64::Engine;
+use ed25519_dalek::{SigningKey, VerifyingKey};
+use std::{fmt, str::FromStr};
+use thiserror::Error;
+
+/// A key for signing store paths, as written by `nix key generate-secret`: `name:` followed by
+/// the base64 of an ed25519 secret key, which is its 32-byte seed and then its public key.
+pub struct NixSecretKey {
+    name: String,
+    signing_key: SigningKey,
+}
+
+/// A public key as listed in `trusted-public-keys`: `name:` followed by the base64 of an ed25519
+/// public key.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NixPublicKey {
+    name: String,
+    key: VerifyingKey,
+}
+
+#[derive(Debug, Error)]
+pub enum NixKeyError {
+    /// For a secret key, the reason never includes the key material.
+    #[error("malformed Nix {kind} key: {reason}")]
+    Malformed { kind: &'static str, reason: String },
+    #[error("the Nix signing key belongs to {public_key}, which isn't one of the trusted public keys {trusted:?}")]
+    Untrusted {
+        public_key: String,
+        trusted: Vec<String>,
+    },
+}
+
+impl NixSecretKey {
+    pub fn public_key(&self) -> NixPublicKey {
+        NixPublicKey {
+            name: self.name.clone(),
+            key: self.signing_key.verifying_key(),
+        }
+    }
+
+    /// Fails unless the public key of this key is one of `trusted`, all of which must be well
+    /// formed.
+    pub fn check_trusted(&self, trusted: &[String]) -> Result<(), NixKeyError> {
+        let public_key = self.public_key();
+        let mut found = false;
+        for key in trusted {
+            found |= key.parse::<NixPublicKey>()? == public_key;
+        }
+        if found {
+            Ok(())
+        } else {
+            Err(NixKeyError::Untrusted {
+                public_key: public_key.to_string(),
+                trusted: trusted.to_vec(),
+            })
+        }
+    }
+}
+
+impl FromStr for NixSecretKey {
+    type Err = NixKeyError;
+
+    fn from_str(s: &str) -> Result<Self, NixKeyError> {
+        let malformed = |reason: &str| NixKeyError::Malformed {
+            kind: "secret",
+            reason: reason.to_string(),
+        };
+        let (name, bytes) = split_key(s.trim()).map_err(|reason| malformed(&reason))?;
+        let bytes: [u8; 64] = bytes
+            .try_into()
+            .map_err(|bytes: Vec<u8>| malformed(&format!("{} bytes instead of 64", bytes.len())))?;
+        let (seed, public_half) = bytes.split_at(32);
+        let signing_key = SigningKey::from_bytes(seed.try_into().expect("32 bytes"));
+        if signing_key.verifying_key().as_bytes() != public_half {
+            return Err(malformed(
+                "the public key it holds isn't the one derived from its seed",
+            ));
+        }
+        Ok(Self { name, signing_key })
+    }
+}
+
+impl FromStr for NixPublicKey {
+    type Err = NixKeyError;
+
+    fn from_str(s: &str) -> Result<Self, NixKeyError> {
+        let malformed = |reason: String| NixKeyError::Malformed {
+            kind: "public",
+            reason: format!("{:?}: {}", s, reason),
+        };
+        let (name, bytes) = split_key(s.trim()).map_err(malformed)?;
+        let bytes: [u8; 32] = bytes
+            .try_into()
+            .map_err(|bytes: Vec<u8>| malformed(format!("{} bytes instead of 32", bytes.len())))?;
+        let key = VerifyingKey::from_bytes(&bytes)
+            .map_err(|_| malformed("not an ed25519 public key".to_string()))?;
+        Ok(Self { name, key })
+    }
+}
+
+impl fmt::Display for NixPublicKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}:{}",
+            self.name,
+            base64::engine::general_purpose::STANDARD.encode(self.key.as_bytes())
+        )
+    }
+}
+
+/// The name and the decoded key of `name:base64`.
+fn split_key(s: &str) -> Result<(String, Vec<u8>), String> {
+    let (name, key) = s
+        .split_once(':')
+        .ok_or_else(|| "expected name:base64".to_string())?;
+    if name.is_empty() {
+        return Err("the name is empty".to_string());
+    }
+    let bytes = base64::engine::general_purpose::STANDARD
+        .decode(key)
+        .map_err(|_| "the key isn't valid base64".to_string())?;
+    Ok((name.to_string(), bytes))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // the first test vector of RFC 8032
+    const SECRET_KEY: &str = "test-1:nWGxne/9WmC6hEr0kuwsxERJxWl7MmkZcDusAxyuf2DXWpgBgrEKt9VL/tPJZAc6DuFy89qmIyWvAhpo9wdRGg==";
+    const PUBLIC_KEY: &str = "test-1:11qYAYKxCrfVS/7TyWQHOg7hcvPapiMlrwIaaPcHURo=";
+
+    #[test]
+    fn derive_public_key() {
+        let secret_key: NixSecretKey = format!("{}\n", SECRET_KEY).parse().unwrap();
+        assert_eq!(secret_key.public_key().to_string(), PUBLIC_KEY);
+        secret_key
+            .check_trusted(&[
+                "cache.nixos.org-1:6NCHdD59X431o0gWypbMrAURkbJ16ZPMQFGspcDShjY=".to_string(),
+                PUBLIC_KEY.to_string(),
+            ])
+            .unwrap();
+    }
+
+    #[test]
+    fn reject_mismatch_and_malformed_keys() {
+        let secret_key: NixSecretKey = SECRET_KEY.parse().unwrap();
+        let other = "cache.nixos.org-1:6NCHdD59X431o0gWypbMrAURkbJ16ZPMQFGspcDShjY=".to_string();
+        assert!(matches!(
+            secret_key.check_trusted(&[other]),
+            Err(NixKeyError::Untrusted { .. })
+        ));
+        // same key under another name
+        assert!(secret_key
+            .check_trusted(&[PUBLIC_KEY.replace("test-1", "test-2")])
+            .is_err());
+        for malformed in [
+            "test-1",
+            ":11qYAYKxCrfVS/7TyWQHOg7hcvPapiMlrwIaaPcHURo=",
+            "test-1:AAAA",
+        ] {
+            assert!(matches!(
+                secret_key.check_trusted(&[PUBLIC_KEY.to_string(), malformed.to_string()]),
+                Err(NixKeyError::Malformed { .. })
+            ));
+        }
+        // the public half doesn't belong to the seed
+        let tampered = SECRET_KEY.replace("DXWpgB", "DXWpgC");
+        assert!(tampered.parse::<NixSecretKey>().is_err());
+    }
+}
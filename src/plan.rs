@@ -0,0 +1,305 @@
+use crate::dotfile::{
+    check_secret_permissions, directory_drifted, read_existing, read_existing_nofollow,
+    symlink::ExistingFilePolicy, ApplyError, Dotfile, Layout, Ownership,
+};
+use similar::TextDiff;
+use std::{
+    collections::HashMap,
+    fmt::{self, Display},
+    os::unix::fs::PermissionsExt,
+    path::{Path, PathBuf},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChangeKind {
+    Create,
+    Update,
+    Delete,
+    PermissionOnly,
+    Unchanged,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ContentDiff {
+    Unified(String),
+    /// The content changed, but it is secret and must not be shown.
+    Redacted,
+    /// The content changed, but either side is not valid utf8.
+    Binary,
+    /// A symlink is created or retargeted. `from` is where it pointed before, if it was a link.
+    Symlink {
+        from: Option<PathBuf>,
+        to: PathBuf,
+    },
+    None,
+}
+
+#[derive(Debug)]
+pub struct PlannedChange {
+    pub path: PathBuf,
+    pub kind: ChangeKind,
+    pub old_mode: Option<u32>,
+    pub new_mode: u32,
+    pub old_ownership: Option<Ownership>,
+    pub new_ownership: Ownership,
+    pub diff: ContentDiff,
+}
+
+/// Computes what `apply_dotfiles` would do to each path, without writing anything.
+pub fn plan_dotfiles(
+    dotfiles: &HashMap<PathBuf, Box<dyn Dotfile>>,
+) -> (Vec<PlannedChange>, HashMap<PathBuf, ApplyError>) {
+    let mut changes = Vec::new();
+    let mut errors = HashMap::new();
+    for (path, dotfile) in dotfiles {
+        match plan_dotfile(path, dotfile.as_ref()) {
+            Ok(change) => changes.push(change),
+            Err(err) => {
+                errors.insert(path.clone(), err);
+            }
+        }
+    }
+    changes.sort_by(|x, y| x.path.cmp(&y.path));
+    (changes, errors)
+}
+
+fn plan_dotfile(path: &Path, dotfile: &dyn Dotfile) -> Result<PlannedChange, ApplyError> {
+    check_secret_permissions(dotfile)?;
+    if let Layout::Symlink {
+        target,
+        existing_file,
+    } = dotfile.layout()
+    {
+        return plan_symlink(path, target, existing_file);
+    }
+    if let Layout::Absent = dotfile.layout() {
+        return plan_absent(path);
+    }
+    let existing = read_existing(path)?;
+    let old_content = existing
+        .as_ref()
+        .map(|existing| existing.content.as_slice())
+        .unwrap_or_default();
+    let old_mode = existing.as_ref().map(|existing| existing.mode);
+    let new_mode = dotfile.file_permission().mode() & 0o7777;
+    let old_ownership = existing.as_ref().map(|existing| existing.ownership());
+    let new_ownership = match old_ownership {
+        Some(old_ownership) => dotfile.ownership().or(old_ownership),
+        None => dotfile.ownership(),
+    };
+    let new_content = dotfile.apply(old_content)?;
+    // classified by what `apply` returns, since a dotfile may have nothing to write to a missing
+    // file, like one that only removes settings
+    let kind = match (&existing, &new_content) {
+        (None, Some(_)) => ChangeKind::Create,
+        (None, None) => ChangeKind::Unchanged,
+        (Some(_), Some(_)) => ChangeKind::Update,
+        (Some(_), None)
+            if old_mode != Some(new_mode)
+                || old_ownership != Some(new_ownership)
+                || directory_drifted(path, dotfile)? =>
+        {
+            ChangeKind::PermissionOnly
+        }
+        (Some(_), None) => ChangeKind::Unchanged,
+    };
+    let diff = match new_content {
+        None => ContentDiff::None,
+        Some(_) if dotfile.is_secret() => ContentDiff::Redacted,
+        Some(new_content) => unified_diff(path, old_content, &new_content),
+    };
+    Ok(PlannedChange {
+        path: path.to_path_buf(),
+        kind,
+        old_mode,
+        new_mode,
+        old_ownership,
+        new_ownership,
+        diff,
+    })
+}
+
+fn plan_symlink(
+    path: &Path,
+    target: &Path,
+    existing_file: ExistingFilePolicy,
+) -> Result<PlannedChange, ApplyError> {
+    let existing = if path.is_dir() && !path.is_symlink() {
+        None
+    } else {
+        read_existing_nofollow(path)?
+    };
+    let occupied = path.is_dir() && !path.is_symlink() || existing.is_some();
+    let (kind, from) = match existing.and_then(|existing| existing.link_target) {
+        Some(from) if from == target => (ChangeKind::Unchanged, Some(from)),
+        Some(from) => (ChangeKind::Update, Some(from)),
+        None if !occupied => (ChangeKind::Create, None),
+        None if existing_file == ExistingFilePolicy::Refuse => {
+            return Err(ApplyError::PathOccupied {
+                path: path.to_path_buf(),
+                kind: if path.is_dir() { "directory" } else { "file" },
+            })
+        }
+        None => (ChangeKind::Update, None),
+    };
+    let diff = if kind == ChangeKind::Unchanged {
+        ContentDiff::None
+    } else {
+        ContentDiff::Symlink {
+            from,
+            to: target.to_path_buf(),
+        }
+    };
+    Ok(PlannedChange {
+        path: path.to_path_buf(),
+        kind,
+        // links have no mode of their own
+        old_mode: Some(0o777),
+        new_mode: 0o777,
+        old_ownership: None,
+        new_ownership: Ownership::default(),
+        diff,
+    })
+}
+
+fn plan_absent(path: &Path) -> Result<PlannedChange, ApplyError> {
+    let existing = read_existing_nofollow(path)?;
+    let old_mode = existing.as_ref().map(|existing| existing.mode);
+    Ok(PlannedChange {
+        path: path.to_path_buf(),
+        kind: match existing {
+            Some(_) => ChangeKind::Delete,
+            None => ChangeKind::Unchanged,
+        },
+        old_mode,
+        new_mode: old_mode.unwrap_or_default(),
+        old_ownership: None,
+        new_ownership: Ownership::default(),
+        diff: ContentDiff::None,
+    })
+}
+
+fn unified_diff(path: &Path, old_content: &[u8], new_content: &[u8]) -> ContentDiff {
+    match (
+        std::str::from_utf8(old_content),
+        std::str::from_utf8(new_content),
+    ) {
+        (Ok(old_content), Ok(new_content)) => {
+            let path = path.display().to_string();
+            ContentDiff::Unified(
+                TextDiff::from_lines(old_content, new_content)
+                    .unified_diff()
+                    .header(&path, &path)
+                    .to_string(),
+            )
+        }
+        _ => ContentDiff::Binary,
+    }
+}
+
+impl Display for ChangeKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = match self {
+            ChangeKind::Create => "create",
+            ChangeKind::Update => "update",
+            ChangeKind::Delete => "delete",
+            ChangeKind::PermissionOnly => "permission-only",
+            ChangeKind::Unchanged => "unchanged",
+        };
+        f.write_str(s)
+    }
+}
+
+impl Display for PlannedChange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.kind, self.path.display())?;
+        match self.old_mode {
+            Some(old_mode) if old_mode != self.new_mode => {
+                write!(f, " (mode {:o} -> {:o})", old_mode, self.new_mode)?
+            }
+            None if self.kind != ChangeKind::Unchanged => write!(f, " (mode {:o})", self.new_mode)?,
+            _ => {}
+        }
+        match self.old_ownership {
+            Some(old_ownership) if old_ownership != self.new_ownership => {
+                write!(f, " (owner {} -> {})", old_ownership, self.new_ownership)?
+            }
+            None if self.new_ownership != Ownership::default() => {
+                write!(f, " (owner {})", self.new_ownership)?
+            }
+            _ => {}
+        }
+        writeln!(f)?;
+        match &self.diff {
+            ContentDiff::Unified(diff) => write!(f, "{}", diff),
+            ContentDiff::Redacted => writeln!(f, "  content changed (redacted)"),
+            ContentDiff::Binary => writeln!(f, "  content changed (binary)"),
+            ContentDiff::Symlink {
+                from: Some(from),
+                to,
+            } => {
+                writeln!(f, "  symlink {} -> {}", from.display(), to.display())
+            }
+            ContentDiff::Symlink { from: None, to } => {
+                writeln!(f, "  symlink to {}", to.display())
+            }
+            ContentDiff::None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        dotfile::{nix_conf::NixConf, unstructured::Unstructured},
+        secret::Secret,
+    };
+
+    #[test]
+    fn plan_create_and_redact() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().join("home");
+        let dotfiles: HashMap<PathBuf, Box<dyn Dotfile>> = HashMap::from([
+            (
+                dir.join("plain"),
+                Box::new(Unstructured::new_utf8("hello\n".to_string())) as Box<dyn Dotfile>,
+            ),
+            (
+                dir.join("secret"),
+                Box::new(Unstructured::from_secret(Secret::new(
+                    b"hunter2\n".to_vec(),
+                ))),
+            ),
+        ]);
+        let (changes, errors) = plan_dotfiles(&dotfiles);
+        assert!(errors.is_empty());
+        assert_eq!(changes.len(), 2);
+        assert_eq!(changes[0].kind, ChangeKind::Create);
+        match &changes[0].diff {
+            ContentDiff::Unified(diff) => assert!(diff.contains("+hello")),
+            diff => panic!("unexpected diff {:?}", diff),
+        }
+        assert_eq!(changes[1].diff, ContentDiff::Redacted);
+        assert!(!changes[1].to_string().contains("hunter2"));
+        assert!(!dir.exists());
+    }
+
+    #[test]
+    fn nothing_to_write_is_unchanged() {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join("nix.conf");
+        let dotfiles: HashMap<PathBuf, Box<dyn Dotfile>> = HashMap::from([(
+            path.clone(),
+            Box::new(NixConf::new().without("post-build-hook")) as Box<dyn Dotfile>,
+        )]);
+        let (changes, errors) = plan_dotfiles(&dotfiles);
+        assert!(errors.is_empty());
+        assert_eq!(changes[0].kind, ChangeKind::Unchanged);
+        assert_eq!(changes[0].diff, ContentDiff::None);
+        assert_eq!(
+            changes[0].to_string(),
+            format!("unchanged: {}\n", path.display())
+        );
+    }
+}
@@ -0,0 +1,254 @@
+use crate::{
+    backup::{BackupRun, BackupStore, RestoreError},
+    dotfile::{
+        parts::Parts, read_existing, read_existing_nofollow, resolve_symlinks, write_atomic,
+    },
+    state::State,
+    Target,
+};
+use std::{
+    collections::HashMap,
+    fmt::{self, Display},
+    fs,
+    os::unix::fs::PermissionsExt,
+    path::PathBuf,
+};
+
+/// A file a previous run wrote, but which none of the targets produces anymore, or the parts of
+/// a shared file that none of them puts there anymore.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Orphan {
+    pub path: PathBuf,
+    /// The backup run holding the file as it was before it was first managed. Pruning restores
+    /// that version, or removes the file if there is none.
+    pub original_backup: Option<String>,
+    /// What to take out of a file shared with others. Pruning leaves the rest of it alone, and
+    /// never restores or removes it.
+    pub parts: Option<Parts>,
+}
+
+/// Finds the recorded files that belong only to `targets`, but that aren't in `produced` anymore,
+/// and the recorded parts of shared files that the dotfiles in `produced` no longer have. Files
+/// owned by targets that aren't part of this run are left alone.
+///
+/// Parts come first, so that a setting pointing at an orphaned file, like the `post-build-hook`
+/// of nix.conf, is pruned before the file is.
+pub fn find_orphans(
+    state: &State,
+    targets: &[Target],
+    produced: &HashMap<PathBuf, Option<Parts>>,
+) -> Vec<Orphan> {
+    let mut orphans: Vec<Orphan> = state
+        .files()
+        .iter()
+        .filter(|(_, managed)| {
+            managed
+                .targets
+                .iter()
+                .all(|target| targets.contains(target))
+        })
+        .filter_map(|(path, managed)| {
+            let parts = match (produced.get(path), &managed.parts) {
+                (None, parts) => parts.clone(),
+                (Some(Some(produced)), Some(recorded)) => {
+                    let stale = recorded.minus(produced);
+                    if stale.is_empty() {
+                        return None;
+                    }
+                    Some(stale)
+                }
+                (Some(_), _) => return None,
+            };
+            Some(Orphan {
+                path: path.clone(),
+                original_backup: managed.original_backup.clone(),
+                parts,
+            })
+        })
+        .collect();
+    orphans.sort_by_key(|orphan| orphan.parts.is_none());
+    orphans
+}
+
+/// Removes `orphan` from disk, or restores its original content. What is there now is saved in
+/// `backup_run` first.
+pub fn prune_orphan(
+    orphan: &Orphan,
+    backups: &BackupStore,
+    backup_run: &BackupRun,
+) -> Result<(), RestoreError> {
+    if let Some(parts) = &orphan.parts {
+        return prune_parts(orphan, parts, backup_run);
+    }
+    let existing = read_existing_nofollow(&orphan.path)?;
+    backup_run.save(&orphan.path, existing.as_ref())?;
+    let original = match &orphan.original_backup {
+        Some(id) => {
+            let run = backups.run(id)?;
+            let entry = run
+                .entries()
+                .into_iter()
+                .find(|entry| entry.path == orphan.path);
+            entry.map(|entry| (run, entry))
+        }
+        None => None,
+    };
+    match original {
+        Some((run, entry)) => run.restore(&entry),
+        None => {
+            if existing.is_some() {
+                std::fs::remove_file(&orphan.path)?;
+            }
+            Ok(())
+        }
+    }
+}
+
+/// Takes `parts` out of the shared file of `orphan`, writing through a symlink like applying it
+/// did.
+fn prune_parts(orphan: &Orphan, parts: &Parts, backup_run: &BackupRun) -> Result<(), RestoreError> {
+    let path = resolve_symlinks(&orphan.path)?;
+    let Some(existing) = read_existing(&path)? else {
+        return Ok(());
+    };
+    let Some(content) = parts.remove_from(&existing.content)? else {
+        return Ok(());
+    };
+    backup_run.save(&path, Some(&existing))?;
+    write_atomic(
+        &path,
+        &content,
+        fs::Permissions::from_mode(existing.mode),
+        existing.ownership(),
+    )?;
+    Ok(())
+}
+
+impl Display for Orphan {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(parts) = &self.parts {
+            return write!(f, "{} (remove {})", self.path.display(), parts);
+        }
+        match &self.original_backup {
+            Some(id) => write!(
+                f,
+                "{} (restore original from backup run {})",
+                self.path.display(),
+                id
+            ),
+            None => write!(f, "{} (remove)", self.path.display()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dotfile::{nix_conf::NixConf, Dotfile, ExistingFile};
+    use std::fs;
+
+    #[test]
+    fn prune_restores_original_or_removes() {
+        let dir = tempfile::tempdir().unwrap();
+        let backups = BackupStore::new(dir.path().join("backups"));
+        let hook = dir.path().join("post-build-hook");
+        let key = dir.path().join("secret-key");
+        let other = dir.path().join("gitconfig");
+
+        let first_run = backups.begin_run();
+        let original = ExistingFile {
+            content: b"original hook".to_vec(),
+            mode: 0o755,
+            uid: 0,
+            gid: 0,
+            link_target: None,
+        };
+        first_run.save(&hook, Some(&original)).unwrap();
+        first_run.save(&key, None).unwrap();
+        let mut state = State::default();
+        for path in [&hook, &key, &other] {
+            fs::write(path, "managed").unwrap();
+        }
+        state
+            .record(
+                &hook,
+                vec![Target::PersonalNixCache],
+                Some(first_run.id()),
+                None,
+            )
+            .unwrap();
+        state
+            .record(&key, vec![Target::PersonalNixCache], None, None)
+            .unwrap();
+        state.record(&other, vec![Target::Git], None, None).unwrap();
+
+        let produced = HashMap::new();
+        let orphans = find_orphans(&state, &[Target::PersonalNixCache], &produced);
+        assert_eq!(
+            orphans.iter().map(|o| &o.path).collect::<Vec<_>>(),
+            vec![&hook, &key]
+        );
+
+        let prune_run = backups.begin_run();
+        for orphan in orphans.iter() {
+            prune_orphan(orphan, &backups, &prune_run).unwrap();
+        }
+        assert_eq!(fs::read_to_string(&hook).unwrap(), "original hook");
+        assert!(!key.exists());
+        assert_eq!(fs::read_to_string(&other).unwrap(), "managed");
+        assert_eq!(prune_run.entries().len(), 2);
+    }
+
+    #[test]
+    fn prune_only_owned_parts_of_shared_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let backups = BackupStore::new(dir.path().join("backups"));
+        let nix_conf = dir.path().join("nix.conf");
+        let hook = dir.path().join("post-build-hook");
+        fs::write(&hook, "managed").unwrap();
+        let managed = NixConf::new()
+            .with_substituters(vec!["s3://cache".to_string()])
+            .with_post_build_hook(hook.to_string_lossy().into_owned());
+        fs::write(
+            &nix_conf,
+            "# by hand\nsubstituters = https://cache.nixos.org\nmax-jobs = 8\n",
+        )
+        .unwrap();
+        let old_content = fs::read(&nix_conf).unwrap();
+        fs::write(&nix_conf, managed.apply(&old_content).unwrap().unwrap()).unwrap();
+        let mut state = State::default();
+        state
+            .record(
+                &nix_conf,
+                vec![Target::PersonalNixCache],
+                None,
+                managed.parts(),
+            )
+            .unwrap();
+        state
+            .record(&hook, vec![Target::PersonalNixCache], None, None)
+            .unwrap();
+
+        let orphans = find_orphans(&state, &[Target::PersonalNixCache], &HashMap::new());
+        assert_eq!(
+            orphans.iter().map(|o| &o.path).collect::<Vec<_>>(),
+            vec![&nix_conf, &hook],
+            "the setting pointing at the hook goes before the hook"
+        );
+        let prune_run = backups.begin_run();
+        for orphan in orphans.iter() {
+            prune_orphan(orphan, &backups, &prune_run).unwrap();
+        }
+        assert_eq!(
+            fs::read_to_string(&nix_conf).unwrap(),
+            "# by hand\nsubstituters = https://cache.nixos.org\nmax-jobs = 8\n"
+        );
+        assert!(!hook.exists());
+
+        // a target that still produces the file keeps its parts
+        let produced = HashMap::from([(nix_conf.clone(), managed.parts())]);
+        assert!(find_orphans(&state, &[Target::PersonalNixCache], &produced)
+            .iter()
+            .all(|orphan| orphan.path != nix_conf));
+    }
+}
@@ -5,7 +5,31 @@ use std::iter;
 use thiserror::Error;
 
 pub trait Decrpytor {
-    fn decrypt(&self, bytes: &[u8]) -> Result<Vec<u8>, Box<dyn Error + Send + Sync>>;
+    fn decrypt(&self, bytes: &[u8]) -> Result<Secret, Box<dyn Error + Send + Sync>>;
+}
+
+/// Decrypted bytes. Dotfiles built from a `Secret` are written with owner-only permissions and
+/// never show their content in diffs or logs.
+pub struct Secret(Vec<u8>);
+
+impl Secret {
+    pub fn new(bytes: Vec<u8>) -> Self {
+        Self(bytes)
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.0
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl std::fmt::Debug for Secret {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Secret(<{} bytes redacted>)", self.0.len())
+    }
 }
 
 pub struct AgeDecryptor {
@@ -35,7 +59,7 @@ enum AgeDecryptionError {
 }
 
 impl Decrpytor for AgeDecryptor {
-    fn decrypt(&self, bytes: &[u8]) -> Result<Vec<u8>, Box<dyn Error + Send + Sync>> {
+    fn decrypt(&self, bytes: &[u8]) -> Result<Secret, Box<dyn Error + Send + Sync>> {
         let encrypted_data = Cursor::new(bytes);
         let decryptor = match age::Decryptor::new_buffered(encrypted_data)
             .map_err(AgeDecryptionError::AgeError)?
@@ -52,6 +76,6 @@ impl Decrpytor for AgeDecryptor {
         reader
             .read_to_end(&mut decrypted)
             .map_err(AgeDecryptionError::IOError)?;
-        Ok(decrypted)
+        Ok(Secret::new(decrypted))
     }
 }
@@ -0,0 +1,258 @@
+use crate::{
+    dotfile::{
+        parts::Parts, read_existing_nofollow, write_atomic, ApplyError, ExistingFile, Ownership,
+    },
+    Target,
+};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{
+    collections::{BTreeMap, HashSet},
+    fmt::{self, Display},
+    fs, io,
+    os::unix::fs::{DirBuilderExt, PermissionsExt},
+    path::{Path, PathBuf},
+};
+use thiserror::Error;
+
+/// Where the tool keeps everything it needs to remember between runs.
+pub fn state_dir(home_dir: &Path) -> PathBuf {
+    home_dir.join(".local/state/dotfiles")
+}
+
+/// What the last `bootstrap` runs wrote to each path they manage.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct State {
+    files: BTreeMap<PathBuf, ManagedFile>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ManagedFile {
+    pub sha256: String,
+    pub mode: u32,
+    pub targets: Vec<Target>,
+    pub version: String,
+    /// The backup run that saved the file as it was before it was first managed.
+    #[serde(default)]
+    pub original_backup: Option<String>,
+    /// What the targets put in a file they share with others. `None` if they own all of it.
+    #[serde(default)]
+    pub parts: Option<Parts>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileStatus {
+    Clean,
+    Drifted,
+    Missing,
+}
+
+#[derive(Debug, Error)]
+pub enum StateError {
+    #[error("failed to access state file {path:?}: {err}")]
+    IO { path: PathBuf, err: io::Error },
+    #[error("corrupted state file: {0}")]
+    Json(#[from] serde_json::Error),
+    #[error("failed to write state file: {0}")]
+    Apply(#[from] ApplyError),
+}
+
+impl State {
+    pub fn path(home_dir: &Path) -> PathBuf {
+        state_dir(home_dir).join("state.json")
+    }
+
+    pub fn load(path: &Path) -> Result<Self, StateError> {
+        match fs::read(path) {
+            Ok(bytes) => Ok(serde_json::from_slice(&bytes)?),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(err) => Err(StateError::IO {
+                path: path.to_path_buf(),
+                err,
+            }),
+        }
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), StateError> {
+        if let Some(parent) = path.parent() {
+            fs::DirBuilder::new()
+                .recursive(true)
+                .mode(0o700)
+                .create(parent)
+                .map_err(|err| StateError::IO {
+                    path: parent.to_path_buf(),
+                    err,
+                })?;
+        }
+        let json = serde_json::to_vec_pretty(self)?;
+        write_atomic(
+            path,
+            &json,
+            fs::Permissions::from_mode(0o600),
+            Ownership::default(),
+        )?;
+        Ok(())
+    }
+
+    pub fn files(&self) -> &BTreeMap<PathBuf, ManagedFile> {
+        &self.files
+    }
+
+    /// Records the current content and mode of `path`, as just written for `targets`.
+    /// `backup_run` is the run that saved its previous content, if any. `parts` are added to
+    /// those recorded before, which stay until they are pruned.
+    pub fn record(
+        &mut self,
+        path: &Path,
+        targets: Vec<Target>,
+        backup_run: Option<&str>,
+        parts: Option<Parts>,
+    ) -> Result<(), ApplyError> {
+        match read_existing_nofollow(path)? {
+            Some(existing) => {
+                let managed = self.files.get(path);
+                let original_backup = match managed {
+                    Some(managed) => managed.original_backup.clone(),
+                    None => backup_run.map(String::from),
+                };
+                let parts = match (managed.and_then(|managed| managed.parts.clone()), parts) {
+                    (Some(recorded), Some(parts)) => Some(recorded.union(parts)),
+                    (_, parts) => parts,
+                };
+                self.files.insert(
+                    path.to_path_buf(),
+                    ManagedFile {
+                        sha256: fingerprint(&existing),
+                        mode: existing.mode,
+                        targets,
+                        version: env!("CARGO_PKG_VERSION").to_string(),
+                        original_backup,
+                        parts,
+                    },
+                );
+            }
+            None => {
+                self.files.remove(path);
+            }
+        }
+        Ok(())
+    }
+
+    pub fn forget(&mut self, path: &Path) {
+        self.files.remove(path);
+    }
+
+    /// Forgets `parts` of `path` after they were pruned, and the whole file once none are left.
+    pub fn forget_parts(&mut self, path: &Path, parts: &Parts) {
+        let Some(managed) = self.files.get_mut(path) else {
+            return;
+        };
+        let left = managed
+            .parts
+            .as_ref()
+            .map(|recorded| recorded.minus(parts))
+            .unwrap_or_default();
+        if left.is_empty() {
+            self.files.remove(path);
+        } else {
+            managed.parts = Some(left);
+        }
+    }
+
+    /// Backup runs that are still needed to restore the original of a managed file.
+    pub fn referenced_backups(&self) -> HashSet<String> {
+        self.files
+            .values()
+            .filter_map(|managed| managed.original_backup.clone())
+            .collect()
+    }
+
+    /// Compares every recorded file with what is on disk now.
+    pub fn status(&self) -> Vec<(PathBuf, Result<FileStatus, ApplyError>)> {
+        self.files
+            .iter()
+            .map(|(path, managed)| (path.clone(), managed.status(path)))
+            .collect()
+    }
+}
+
+impl ManagedFile {
+    fn status(&self, path: &Path) -> Result<FileStatus, ApplyError> {
+        Ok(match read_existing_nofollow(path)? {
+            None => FileStatus::Missing,
+            Some(existing)
+                if existing.mode != self.mode || fingerprint(&existing) != self.sha256 =>
+            {
+                FileStatus::Drifted
+            }
+            Some(_) => FileStatus::Clean,
+        })
+    }
+}
+
+/// Hash of the content, or of the target path for a symlink. A file written through a symlink
+/// is tracked by its link.
+fn fingerprint(existing: &ExistingFile) -> String {
+    match &existing.link_target {
+        Some(target) => sha256_hex(target.as_os_str().as_encoded_bytes()),
+        None => sha256_hex(&existing.content),
+    }
+}
+
+fn sha256_hex(content: &[u8]) -> String {
+    Sha256::digest(content)
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+impl Display for FileStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = match self {
+            FileStatus::Clean => "clean",
+            FileStatus::Drifted => "drifted since last apply",
+            FileStatus::Missing => "missing",
+        };
+        f.write_str(s)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn record_and_status() {
+        let dir = tempfile::tempdir().unwrap();
+        let state_path = dir.path().join("state/state.json");
+        let clean = dir.path().join("clean");
+        let drifted = dir.path().join("drifted");
+        let missing = dir.path().join("missing");
+        for path in [&clean, &drifted, &missing] {
+            fs::write(path, "managed").unwrap();
+        }
+
+        let mut state = State::load(&state_path).unwrap();
+        for path in [&clean, &drifted, &missing] {
+            state.record(path, vec![Target::Git], None, None).unwrap();
+        }
+        state.save(&state_path).unwrap();
+
+        fs::set_permissions(&drifted, fs::Permissions::from_mode(0o666)).unwrap();
+        fs::remove_file(&missing).unwrap();
+        let status: BTreeMap<PathBuf, FileStatus> = State::load(&state_path)
+            .unwrap()
+            .status()
+            .into_iter()
+            .map(|(path, status)| (path, status.unwrap()))
+            .collect();
+        assert_eq!(
+            status,
+            BTreeMap::from([
+                (clean, FileStatus::Clean),
+                (drifted, FileStatus::Drifted),
+                (missing, FileStatus::Missing),
+            ])
+        );
+    }
+}
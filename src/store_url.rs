@@ -0,0 +1,338 @@
+use std::{fmt, str::FromStr};
+use thiserror::Error;
+
+/// The URL of a Nix store used as a substituter or copied to, like
+/// `s3://bucket?region=us-east-2&priority=0`: https://nix.dev/manual/nix/stable/store/types/
+///
+/// Parameters keep the order and spelling they were parsed with, so rendering gives back the
+/// same string. The ones with a typed accessor are checked when parsed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StoreUrl {
+    scheme: Scheme,
+    /// Everything between `://` and `?`: a bucket, a host and path, or a path.
+    location: String,
+    params: Vec<(String, String)>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Scheme {
+    S3,
+    Http,
+    Https,
+    File,
+    SshNg,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    None,
+    Xz,
+    Bzip2,
+    Gzip,
+    Brotli,
+    Zstd,
+}
+
+#[derive(Debug, PartialEq, Eq, Error)]
+pub enum StoreUrlError {
+    #[error("{url:?} has no scheme")]
+    NoScheme { url: String },
+    #[error("{url:?} has an unsupported scheme {scheme:?}")]
+    UnsupportedScheme { url: String, scheme: String },
+    #[error("{url:?} has no location after the scheme")]
+    NoLocation { url: String },
+    #[error("{url:?} has an invalid {param}: {value:?}")]
+    InvalidParam {
+        url: String,
+        param: &'static str,
+        value: String,
+    },
+}
+
+impl Scheme {
+    fn as_str(self) -> &'static str {
+        match self {
+            Scheme::S3 => "s3",
+            Scheme::Http => "http",
+            Scheme::Https => "https",
+            Scheme::File => "file",
+            Scheme::SshNg => "ssh-ng",
+        }
+    }
+}
+
+impl Compression {
+    fn as_str(self) -> &'static str {
+        match self {
+            Compression::None => "none",
+            Compression::Xz => "xz",
+            Compression::Bzip2 => "bzip2",
+            Compression::Gzip => "gzip",
+            Compression::Brotli => "br",
+            Compression::Zstd => "zstd",
+        }
+    }
+
+    fn parse(value: &str) -> Option<Self> {
+        [
+            Compression::None,
+            Compression::Xz,
+            Compression::Bzip2,
+            Compression::Gzip,
+            Compression::Brotli,
+            Compression::Zstd,
+        ]
+        .into_iter()
+        .find(|compression| compression.as_str() == value)
+    }
+}
+
+impl StoreUrl {
+    pub fn new(scheme: Scheme, location: &str) -> Self {
+        Self {
+            scheme,
+            location: location.to_string(),
+            params: Vec::new(),
+        }
+    }
+
+    pub fn s3(bucket: &str) -> Self {
+        Self::new(Scheme::S3, bucket)
+    }
+
+    pub fn scheme(&self) -> Scheme {
+        self.scheme
+    }
+
+    /// Whether both are the same store, whatever their parameters.
+    pub fn same_store(&self, other: &StoreUrl) -> bool {
+        store_identity(&self.to_string()) == store_identity(&other.to_string())
+    }
+
+    pub fn param(&self, name: &str) -> Option<&str> {
+        self.params
+            .iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    pub fn compression(&self) -> Option<Compression> {
+        self.param("compression").and_then(Compression::parse)
+    }
+
+    /// Lower is queried first.
+    pub fn priority(&self) -> Option<u32> {
+        self.param("priority").and_then(|value| value.parse().ok())
+    }
+
+    pub fn trusted(&self) -> Option<bool> {
+        self.param("trusted").and_then(parse_bool)
+    }
+
+    pub fn endpoint(&self) -> Option<&str> {
+        self.param("endpoint")
+    }
+
+    pub fn profile(&self) -> Option<&str> {
+        self.param("profile")
+    }
+
+    pub fn region(&self) -> Option<&str> {
+        self.param("region")
+    }
+
+    /// Sets `name`, in place if it is already there.
+    pub fn with_param(mut self, name: &str, value: &str) -> Self {
+        match self.params.iter_mut().find(|(key, _)| key == name) {
+            Some((_, existing)) => *existing = value.to_string(),
+            None => self.params.push((name.to_string(), value.to_string())),
+        }
+        self
+    }
+
+    pub fn with_compression(self, compression: Compression) -> Self {
+        self.with_param("compression", compression.as_str())
+    }
+
+    pub fn with_priority(self, priority: u32) -> Self {
+        self.with_param("priority", &priority.to_string())
+    }
+
+    pub fn with_trusted(self, trusted: bool) -> Self {
+        self.with_param("trusted", if trusted { "true" } else { "false" })
+    }
+
+    pub fn with_endpoint(self, endpoint: &str) -> Self {
+        self.with_param("endpoint", endpoint)
+    }
+
+    pub fn with_profile(self, profile: &str) -> Self {
+        self.with_param("profile", profile)
+    }
+
+    pub fn with_region(self, region: &str) -> Self {
+        self.with_param("region", region)
+    }
+}
+
+/// Nix takes `1` and `0` as well.
+fn parse_bool(value: &str) -> Option<bool> {
+    match value {
+        "true" | "1" => Some(true),
+        "false" | "0" => Some(false),
+        _ => None,
+    }
+}
+
+/// What identifies the store of a URL: the URL without its parameters or a trailing `/`.
+pub(crate) fn store_identity(url: &str) -> &str {
+    url.split_once('?')
+        .map_or(url, |(store, _)| store)
+        .trim_end_matches('/')
+}
+
+impl FromStr for StoreUrl {
+    type Err = StoreUrlError;
+
+    fn from_str(url: &str) -> Result<Self, StoreUrlError> {
+        let (scheme, rest) = url
+            .split_once("://")
+            .ok_or_else(|| StoreUrlError::NoScheme {
+                url: url.to_string(),
+            })?;
+        let scheme = [
+            Scheme::S3,
+            Scheme::Http,
+            Scheme::Https,
+            Scheme::File,
+            Scheme::SshNg,
+        ]
+        .into_iter()
+        .find(|known| known.as_str() == scheme)
+        .ok_or_else(|| StoreUrlError::UnsupportedScheme {
+            url: url.to_string(),
+            scheme: scheme.to_string(),
+        })?;
+        let (location, query) = rest.split_once('?').unwrap_or((rest, ""));
+        if location.is_empty() {
+            return Err(StoreUrlError::NoLocation {
+                url: url.to_string(),
+            });
+        }
+        let params: Vec<(String, String)> = query
+            .split('&')
+            .filter(|param| !param.is_empty())
+            .map(|param| {
+                let (key, value) = param.split_once('=').unwrap_or((param, ""));
+                (key.to_string(), value.to_string())
+            })
+            .collect();
+        let store_url = Self {
+            scheme,
+            location: location.to_string(),
+            params,
+        };
+        for (param, valid) in [
+            ("compression", store_url.compression().is_some()),
+            ("priority", store_url.priority().is_some()),
+            ("trusted", store_url.trusted().is_some()),
+        ] {
+            if let Some(value) = store_url.param(param).filter(|_| !valid) {
+                return Err(StoreUrlError::InvalidParam {
+                    url: url.to_string(),
+                    param,
+                    value: value.to_string(),
+                });
+            }
+        }
+        Ok(store_url)
+    }
+}
+
+impl fmt::Display for StoreUrl {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}://{}", self.scheme.as_str(), self.location)?;
+        for (index, (key, value)) in self.params.iter().enumerate() {
+            let separator = if index == 0 { '?' } else { '&' };
+            if value.is_empty() {
+                write!(f, "{}{}", separator, key)?;
+            } else {
+                write!(f, "{}{}={}", separator, key, value)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_and_render() {
+        for url in [
+            "s3://nix-cache?endpoint=http://192.168.31.2:9091&profile=minio&compression=zstd&priority=0&trusted=true&want-mass-query=true",
+            "https://cache.nixos.org",
+            "https://cache.example.com/nix/?priority=40",
+            "file:///var/cache/nix?compression=none",
+            "ssh-ng://builder@10.0.0.5?trusted=1",
+        ] {
+            assert_eq!(url.parse::<StoreUrl>().unwrap().to_string(), url);
+        }
+        let url: StoreUrl = "s3://kokobd-nix-cache-ohio?profile=default&region=us-east-2&compression=zstd&priority=0&trusted=true"
+            .parse()
+            .unwrap();
+        assert_eq!(url.scheme(), Scheme::S3);
+        assert_eq!(url.profile(), Some("default"));
+        assert_eq!(url.region(), Some("us-east-2"));
+        assert_eq!(url.compression(), Some(Compression::Zstd));
+        assert_eq!(url.priority(), Some(0));
+        assert_eq!(url.trusted(), Some(true));
+        assert_eq!(url.endpoint(), None);
+    }
+
+    #[test]
+    fn build() {
+        let url = StoreUrl::s3("nix-cache")
+            .with_endpoint("http://192.168.31.2:9091")
+            .with_profile("minio")
+            .with_compression(Compression::Zstd)
+            .with_priority(10)
+            .with_trusted(true)
+            .with_priority(0);
+        assert_eq!(
+            url.to_string(),
+            "s3://nix-cache?endpoint=http://192.168.31.2:9091&profile=minio&compression=zstd&priority=0&trusted=true"
+        );
+        assert!(url.same_store(&"s3://nix-cache?priority=50".parse().unwrap()));
+        assert!(!url.same_store(&"s3://other-cache".parse().unwrap()));
+        assert!(StoreUrl::new(Scheme::Https, "cache.nixos.org/")
+            .same_store(&"https://cache.nixos.org".parse().unwrap()));
+    }
+
+    #[test]
+    fn reject_invalid() {
+        assert!(matches!(
+            "cache.nixos.org".parse::<StoreUrl>(),
+            Err(StoreUrlError::NoScheme { .. })
+        ));
+        assert!(matches!(
+            "gs://bucket".parse::<StoreUrl>(),
+            Err(StoreUrlError::UnsupportedScheme { .. })
+        ));
+        assert!(matches!(
+            "s3://bucket?priority=high".parse::<StoreUrl>(),
+            Err(StoreUrlError::InvalidParam {
+                param: "priority",
+                ..
+            })
+        ));
+        assert!(matches!(
+            "s3://bucket?compression=lz4".parse::<StoreUrl>(),
+            Err(StoreUrlError::InvalidParam {
+                param: "compression",
+                ..
+            })
+        ));
+    }
+}
@@ -1,3 +1,3 @@
-pub mod personal_nix_cache;
-pub mod git;
 pub mod aws;
+pub mod git;
+pub mod personal_nix_cache;
@@ -1,30 +1,40 @@
 use std::{collections::HashMap, path::PathBuf};
 
 use crate::{
-    dotfile::{unstructured::Unstructured, Dotfile},
+    dotfile::{
+        ini::{Ini, GITCONFIG_SCHEMA},
+        line_set::LineSet,
+        unstructured::Unstructured,
+        Dotfile,
+    },
     secret::Decrpytor,
     Config, DecryptError,
 };
 
 pub fn dotfiles(config: &Config) -> Result<HashMap<PathBuf, Box<dyn Dotfile>>, DecryptError> {
     let git_config_path = config.home_dir.join(".gitconfig");
-    let git_config: Box<dyn Dotfile> = Box::new(Unstructured::new(
-        include_bytes!("../../config/.gitconfig").to_vec(),
-    ));
+    let git_config: Box<dyn Dotfile> = Box::new(
+        Ini::parse(include_str!("../../config/.gitconfig"), &GITCONFIG_SCHEMA)
+            .with_ownership(config.home_owner),
+    );
     let gpg_key_path = config.home_dir.join(".gpg/private.gpg");
-    let gpg_key: Box<dyn Dotfile> = Box::new(Unstructured::new(
-        config
-            .decryptor
-            .decrypt(include_bytes!("../../config/private.gpg.rage"))
-            .map_err(|err| DecryptError {
-                path: "config/private.gpg.rage".to_string(),
-                error: err,
-            })?,
-    ));
+    let gpg_key: Box<dyn Dotfile> = Box::new(
+        Unstructured::from_secret(
+            config
+                .decryptor
+                .decrypt(include_bytes!("../../config/private.gpg.rage"))
+                .map_err(|err| DecryptError {
+                    path: "config/private.gpg.rage".to_string(),
+                    error: err,
+                })?,
+        )
+        .with_ownership(config.home_owner),
+    );
     let global_gitignore_path = config.home_dir.join(".gitignore");
-    let global_gitignore: Box<dyn Dotfile> = Box::new(Unstructured::new(
-        include_bytes!("../../config/global_gitignore").to_vec(),
-    ));
+    let global_gitignore: Box<dyn Dotfile> = Box::new(
+        LineSet::from_lines(include_str!("../../config/global_gitignore"))
+            .with_ownership(config.home_owner),
+    );
     Ok(HashMap::from([
         (git_config_path, git_config),
         (gpg_key_path, gpg_key),
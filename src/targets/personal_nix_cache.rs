@@ -1,63 +1,97 @@
 use crate::{
-    dotfile::{nix_conf::NixConf, unstructured::Unstructured, Dotfile},
+    dotfile::{nix_conf::NixConf, unstructured::Unstructured, Dotfile, Ownership},
+    nix_key::{NixKeyError, NixSecretKey},
     secret::Decrpytor,
-    Config, DecryptError, Region,
+    store_url::{Compression, StoreUrl},
+    upload_queue::DEFAULT_SPOOL_DIR,
+    Config, DecryptError, Region, TargetError,
 };
 use pathbuf::pathbuf;
-use std::{collections::HashMap, os::unix::fs::PermissionsExt, path::PathBuf};
+use std::{
+    collections::HashMap,
+    fs, io,
+    os::unix::fs::PermissionsExt,
+    path::{Path, PathBuf},
+    process,
+};
+
+/// Where the hook runs this binary from. Nix runs the hook as root, so it must not run a copy
+/// that anyone but root can replace, like the one in the user's home.
+const HOOK_EXE: &str = "/usr/local/libexec/dotfiles-bootstrap";
 
-pub fn dotfiles(config: &Config) -> Result<HashMap<PathBuf, Box<dyn Dotfile>>, DecryptError> {
-    const MY_NIX_CACHE_PARAMS: &'static str =
-        "compression=zstd&priority=0&trusted=true";
-    let nix_substituter: Option<String> = match &config.region {
-        Region::Home => Some(format!(
-            "s3://nix-cache?endpoint=http://192.168.31.2:9091&profile=minio&{MY_NIX_CACHE_PARAMS}"
-        )),
+pub fn dotfiles(config: &Config) -> Result<HashMap<PathBuf, Box<dyn Dotfile>>, TargetError> {
+    let nix_substituter: Option<StoreUrl> = match &config.region {
+        Region::Home => Some(
+            StoreUrl::s3("nix-cache")
+                .with_endpoint("http://192.168.31.2:9091")
+                .with_profile("minio"),
+        ),
         Region::AWS { region } => {
             if region == "us-east-2" {
-                Some(format!("s3://kokobd-nix-cache-ohio?profile=default&region=us-east-2&{MY_NIX_CACHE_PARAMS}"))
+                Some(
+                    StoreUrl::s3("kokobd-nix-cache-ohio")
+                        .with_profile("default")
+                        .with_region("us-east-2"),
+                )
             } else {
                 None
             }
         }
-    };
+    }
+    .map(|url| {
+        url.with_compression(Compression::Zstd)
+            .with_priority(0)
+            .with_trusted(true)
+    });
     if let Some(nix_substituter) = nix_substituter {
+        let trusted_public_keys =
+            vec!["cache.zelinf.net:poESahuRAXqYC2QPevSId+pTtoq0P1XfTxaSHRgfvVI=".to_string()];
+        let secret_key = config
+            .decryptor
+            .decrypt(include_bytes!("../../config/nix-secret-key.rage"))
+            .map_err(|err| DecryptError {
+                path: "config/nix-secret-key.rage".to_string(),
+                error: err,
+            })?;
+        // paths signed with a key no client trusts would be uploaded for nothing
+        std::str::from_utf8(secret_key.as_bytes())
+            .map_err(|_| NixKeyError::Malformed {
+                kind: "secret",
+                reason: "not UTF-8".to_string(),
+            })?
+            .parse::<NixSecretKey>()?
+            .check_trusted(&trusted_public_keys)?;
         let nix_conf: Box<dyn Dotfile> = Box::new(
             NixConf::new()
                 .with_secret_key_files(vec!["/etc/nix/secret-key".to_string()])
                 .with_post_build_hook("/etc/nix/post-build-hook".to_string())
-                .with_substituters(vec![nix_substituter.clone()])
-                .with_trusted_public_keys(vec![
-                    "cache.zelinf.net:poESahuRAXqYC2QPevSId+pTtoq0P1XfTxaSHRgfvVI=".to_string(),
-                ]),
+                .with_substituters(vec![nix_substituter.to_string()])
+                .with_trusted_public_keys(trusted_public_keys),
         );
         let nix_post_build_hook: Box<dyn Dotfile> = Box::new(
             Unstructured::new_utf8(format!(
                 r#"#!/usr/bin/env bash
 set -eu
-set -f # disable globbing
-export IFS=' '
 
-echo "Signing paths" $OUT_PATHS
-nix store sign --recursive --key-file /etc/nix/secret-key $OUT_PATHS
-echo "Uploading paths" $OUT_PATHS
-exec nix copy --to '{}' $OUT_PATHS
+mkdir -p -m 0700 '{spool_dir}'
+# queue the paths rather than upload them here, which would hold up every build
+'{exe}' post-build-hook --spool-dir '{spool_dir}'
+# upload in the background, without holding the output Nix waits on
+nohup '{exe}' flush-uploads --spool-dir '{spool_dir}' --to '{to}' \
+    >>'{spool_dir}/flush.log' 2>&1 </dev/null &
 "#,
-                nix_substituter
+                exe = HOOK_EXE,
+                spool_dir = DEFAULT_SPOOL_DIR,
+                to = nix_substituter,
             ))
-            .with_permissions(std::fs::Permissions::from_mode(0o777)),
+            .with_permissions(fs::Permissions::from_mode(0o755))
+            .with_ownership(Ownership::root()),
         );
         let secret_key_file: Box<dyn Dotfile> = Box::new(
-            Unstructured::new(
-                config
-                    .decryptor
-                    .decrypt(include_bytes!("../../config/nix-secret-key.rage"))
-                    .map_err(|err| DecryptError {
-                        path: "config/nix-secret-key.rage".to_string(),
-                        error: err,
-                    })?,
-            )
-            .with_permissions(std::fs::Permissions::from_mode(0o600)),
+            Unstructured::from_secret(secret_key)
+                // Nix reads nix.conf from the same directory as every user
+                .with_directory_permission(None)
+                .with_ownership(Ownership::root()),
         );
         Ok(HashMap::from([
             (pathbuf!["/etc", "nix", "nix.conf"], nix_conf),
@@ -71,3 +105,25 @@ exec nix copy --to '{}' $OUT_PATHS
         Ok(HashMap::new())
     }
 }
+
+/// Copies this binary to `HOOK_EXE` for the post-build hook, owned by root and 0755. The copy is
+/// renamed into place, so the hook never runs half of it. It isn't a dotfile, which keeps the
+/// binary out of plans, backups and the state.
+pub fn install_hook_exe(config: &Config) -> Result<(), TargetError> {
+    let hook_exe = Path::new(HOOK_EXE);
+    let install_err = |err: io::Error| TargetError::InstallExe {
+        path: hook_exe.to_path_buf(),
+        err,
+    };
+    let dir = hook_exe.parent().unwrap_or(Path::new("/"));
+    fs::create_dir_all(dir).map_err(install_err)?;
+    let temporary = dir.join(format!(".dotfiles-bootstrap.{}.tmp", process::id()));
+    let installed = fs::copy(&config.bootstrap_exe, &temporary)
+        .and_then(|_| std::os::unix::fs::chown(&temporary, Some(0), Some(0)))
+        .and_then(|()| fs::set_permissions(&temporary, fs::Permissions::from_mode(0o755)))
+        .and_then(|()| fs::rename(&temporary, hook_exe));
+    if installed.is_err() {
+        let _ = fs::remove_file(&temporary);
+    }
+    installed.map_err(install_err)
+}
@@ -0,0 +1,425 @@
+use crate::store_url::StoreUrl;
+use std::{
+    collections::HashSet,
+    error::Error,
+    fs, io,
+    os::unix::fs::DirBuilderExt,
+    path::{Path, PathBuf},
+    process, thread,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use thiserror::Error;
+
+/// Where the Nix post-build hook queues paths by default. The hook runs as root.
+pub const DEFAULT_SPOOL_DIR: &str = "/var/spool/dotfiles/nix-uploads";
+
+/// Store paths built by Nix and waiting to be signed and uploaded to a binary cache. The
+/// post-build hook only adds a file with its paths to the spool directory, so builds neither wait
+/// for the upload nor lose it when the cache is unreachable; `flush` uploads them later. Files
+/// that keep failing are moved to `failed/` in the spool directory, and moving them back queues
+/// them again.
+pub struct UploadQueue {
+    dir: PathBuf,
+}
+
+/// Signs store paths and copies them to a binary cache. Swapped out in tests.
+pub trait Uploader {
+    fn sign(&self, paths: &[String]) -> Result<(), Box<dyn Error + Send + Sync>>;
+    fn copy(&self, paths: &[String]) -> Result<(), Box<dyn Error + Send + Sync>>;
+}
+
+/// Runs `nix store sign` and `nix copy`.
+pub struct NixUploader {
+    key_file: PathBuf,
+    to: StoreUrl,
+}
+
+#[derive(Debug, Clone)]
+pub struct FlushOptions {
+    retries: usize,
+    retry_delay: Duration,
+    max_failures: usize,
+}
+
+#[derive(Debug, Error)]
+pub enum QueueError {
+    #[error("failed to access upload queue {path:?}: {err}")]
+    IO { path: PathBuf, err: io::Error },
+}
+
+/// What a flush did.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct FlushReport {
+    pub uploaded: Vec<String>,
+    /// Queued paths that are gone from the store, usually garbage collected.
+    pub missing: Vec<String>,
+    /// Queued files that failed to upload on every attempt, with the last error. They stay
+    /// queued for the next flush, unless they are also `given_up`.
+    pub failed: Vec<(PathBuf, String)>,
+    /// Files that failed in `max_failures` flushes, now in `failed/`.
+    pub given_up: Vec<PathBuf>,
+    /// Another flush was already running, so this one did nothing.
+    pub busy: bool,
+}
+
+fn io_err(path: &Path) -> impl FnOnce(io::Error) -> QueueError {
+    let path = path.to_path_buf();
+    |err| QueueError::IO { path, err }
+}
+
+impl Default for FlushOptions {
+    fn default() -> Self {
+        Self {
+            retries: 3,
+            retry_delay: Duration::from_secs(5),
+            max_failures: 3,
+        }
+    }
+}
+
+impl FlushOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// How many times to try again after a failed upload.
+    pub fn with_retries(mut self, retries: usize) -> Self {
+        self.retries = retries;
+        self
+    }
+
+    /// How long to wait before the first retry; the wait doubles after each one.
+    pub fn with_retry_delay(mut self, retry_delay: Duration) -> Self {
+        self.retry_delay = retry_delay;
+        self
+    }
+
+    /// How many flushes may fail to upload a file before it is moved to `failed/`.
+    pub fn with_max_failures(mut self, max_failures: usize) -> Self {
+        self.max_failures = max_failures;
+        self
+    }
+}
+
+impl NixUploader {
+    pub fn new(key_file: PathBuf, to: StoreUrl) -> Self {
+        Self { key_file, to }
+    }
+}
+
+fn run_nix(args: &[&str], paths: &[String]) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let status = process::Command::new("nix")
+        .args(args)
+        .args(paths)
+        .status()?;
+    if status.success() {
+        Ok(())
+    } else {
+        Err(format!("`nix {}` failed with {}", args.join(" "), status).into())
+    }
+}
+
+impl Uploader for NixUploader {
+    fn sign(&self, paths: &[String]) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let key_file = self.key_file.to_string_lossy();
+        run_nix(
+            &["store", "sign", "--recursive", "--key-file", &key_file],
+            paths,
+        )
+    }
+
+    fn copy(&self, paths: &[String]) -> Result<(), Box<dyn Error + Send + Sync>> {
+        run_nix(&["copy", "--to", &self.to.to_string()], paths)
+    }
+}
+
+impl UploadQueue {
+    pub fn new(dir: PathBuf) -> Self {
+        Self { dir }
+    }
+
+    /// Adds `paths` as one file, written under a temporary name and then renamed so that a flush
+    /// never reads half of it.
+    pub fn enqueue(&self, paths: &[String]) -> Result<(), QueueError> {
+        if paths.is_empty() {
+            return Ok(());
+        }
+        fs::DirBuilder::new()
+            .recursive(true)
+            .mode(0o700)
+            .create(&self.dir)
+            .map_err(io_err(&self.dir))?;
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos();
+        let name = format!("{}-{}.paths", nanos, process::id());
+        let temporary = self.dir.join(format!(".{}.tmp", name));
+        fs::write(&temporary, paths.join("\n") + "\n").map_err(io_err(&temporary))?;
+        let queued = self.dir.join(name);
+        fs::rename(&temporary, &queued).map_err(io_err(&queued))
+    }
+
+    /// The queued files, oldest first.
+    fn files(&self) -> Result<Vec<PathBuf>, QueueError> {
+        let entries = match fs::read_dir(&self.dir) {
+            Ok(entries) => entries,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(err) => return Err(io_err(&self.dir)(err)),
+        };
+        let mut files = Vec::new();
+        for entry in entries {
+            let path = entry.map_err(io_err(&self.dir))?.path();
+            if path
+                .extension()
+                .is_some_and(|extension| extension == "paths")
+            {
+                files.push(path);
+            }
+        }
+        files.sort();
+        Ok(files)
+    }
+
+    /// Uploads the paths of each queued file, trying again with a growing delay if the upload
+    /// fails. A file is removed once its paths are uploaded; paths no longer in the store are
+    /// dropped, and so are those an earlier file of this flush already uploaded. Only one flush
+    /// runs at a time.
+    pub fn flush(
+        &self,
+        uploader: &dyn Uploader,
+        options: &FlushOptions,
+    ) -> Result<FlushReport, QueueError> {
+        let files = self.files()?;
+        if files.is_empty() {
+            return Ok(FlushReport::default());
+        }
+        let lock_path = self.dir.join("flush.lock");
+        let lock = fs::File::create(&lock_path).map_err(io_err(&lock_path))?;
+        match lock.try_lock() {
+            Ok(()) => {}
+            Err(fs::TryLockError::WouldBlock) => {
+                return Ok(FlushReport {
+                    busy: true,
+                    ..Default::default()
+                })
+            }
+            Err(fs::TryLockError::Error(err)) => return Err(io_err(&lock_path)(err)),
+        }
+        // another flush may have finished between listing the files and locking
+        let files = self.files()?;
+
+        let mut report = FlushReport::default();
+        let mut seen = HashSet::new();
+        for file in files.iter() {
+            let content = fs::read_to_string(file).map_err(io_err(file))?;
+            let mut paths = Vec::new();
+            for path in content.split_whitespace() {
+                if !seen.insert(path.to_string()) {
+                    continue;
+                }
+                if Path::new(path).exists() {
+                    paths.push(path.to_string());
+                } else {
+                    report.missing.push(path.to_string());
+                }
+            }
+            match upload(uploader, &paths, options) {
+                Ok(()) => {
+                    fs::remove_file(file).map_err(io_err(file))?;
+                    report.uploaded.extend(paths);
+                }
+                Err(err) => {
+                    report.failed.push((file.clone(), err.to_string()));
+                    if let Some(given_up) = self.count_failure(file, options)? {
+                        report.given_up.push(given_up);
+                    }
+                }
+            }
+        }
+        Ok(report)
+    }
+
+    /// Records that a flush failed to upload `file`, in its name, or moves it to `failed/` if
+    /// that was its last chance. Returns where it was moved to.
+    fn count_failure(
+        &self,
+        file: &Path,
+        options: &FlushOptions,
+    ) -> Result<Option<PathBuf>, QueueError> {
+        let (name, failures) = queued_name(file);
+        let failures = failures + 1;
+        if failures < options.max_failures {
+            let renamed = self.dir.join(format!("{}.{}.paths", name, failures));
+            fs::rename(file, &renamed).map_err(io_err(&renamed))?;
+            return Ok(None);
+        }
+        let failed_dir = self.dir.join("failed");
+        fs::DirBuilder::new()
+            .recursive(true)
+            .mode(0o700)
+            .create(&failed_dir)
+            .map_err(io_err(&failed_dir))?;
+        let failed = failed_dir.join(format!("{}.paths", name));
+        fs::rename(file, &failed).map_err(io_err(&failed))?;
+        Ok(Some(failed))
+    }
+}
+
+/// The name a queued file was enqueued with, and how many flushes failed to upload it, which
+/// is kept as in `<name>.<failures>.paths`.
+fn queued_name(file: &Path) -> (String, usize) {
+    let stem = file
+        .file_stem()
+        .unwrap_or_default()
+        .to_string_lossy()
+        .into_owned();
+    match stem.rsplit_once('.') {
+        Some((name, failures)) => match failures.parse() {
+            Ok(failures) => (name.to_string(), failures),
+            Err(_) => (stem, 0),
+        },
+        None => (stem, 0),
+    }
+}
+
+/// Signs and copies `paths`, trying again after a failure as `options` allow.
+fn upload(
+    uploader: &dyn Uploader,
+    paths: &[String],
+    options: &FlushOptions,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    if paths.is_empty() {
+        return Ok(());
+    }
+    let mut delay = options.retry_delay;
+    let mut attempt = 0;
+    loop {
+        attempt += 1;
+        match uploader.sign(paths).and_then(|()| uploader.copy(paths)) {
+            Ok(()) => return Ok(()),
+            Err(err) if attempt > options.retries => return Err(err),
+            Err(_) => {
+                thread::sleep(delay);
+                delay *= 2;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::{Cell, RefCell};
+
+    /// Fails the first `failures` copies, and every copy of an `unreachable` path, and records
+    /// the paths of the others.
+    #[derive(Default)]
+    struct FakeUploader {
+        failures: Cell<usize>,
+        unreachable: Vec<String>,
+        copied: RefCell<Vec<Vec<String>>>,
+    }
+
+    impl Uploader for FakeUploader {
+        fn sign(&self, _paths: &[String]) -> Result<(), Box<dyn Error + Send + Sync>> {
+            Ok(())
+        }
+
+        fn copy(&self, paths: &[String]) -> Result<(), Box<dyn Error + Send + Sync>> {
+            if self.failures.get() > 0 {
+                self.failures.set(self.failures.get() - 1);
+                return Err("cache unreachable".into());
+            }
+            if paths.iter().any(|path| self.unreachable.contains(path)) {
+                return Err("cache unreachable".into());
+            }
+            self.copied.borrow_mut().push(paths.to_vec());
+            Ok(())
+        }
+    }
+
+    fn options(retries: usize) -> FlushOptions {
+        FlushOptions::new()
+            .with_retries(retries)
+            .with_retry_delay(Duration::ZERO)
+    }
+
+    #[test]
+    fn enqueue_and_flush() {
+        let dir = tempfile::tempdir().unwrap();
+        let store_path = |name: &str| {
+            let path = dir.path().join(name);
+            fs::write(&path, "").unwrap();
+            path.to_string_lossy().into_owned()
+        };
+        let (a, b) = (store_path("a"), store_path("b"));
+        let gone = dir.path().join("gone").to_string_lossy().into_owned();
+        let queue = UploadQueue::new(dir.path().join("spool"));
+        queue.enqueue(&[a.clone(), b.clone()]).unwrap();
+        queue.enqueue(&[b.clone(), gone.clone()]).unwrap();
+
+        let uploader = FakeUploader::default();
+        uploader.failures.set(1);
+        let report = queue.flush(&uploader, &options(1)).unwrap();
+        assert_eq!(report.uploaded, vec![a.clone(), b.clone()]);
+        assert_eq!(report.missing, vec![gone]);
+        assert_eq!(*uploader.copied.borrow(), vec![vec![a, b]]);
+        assert!(queue.files().unwrap().is_empty());
+        assert_eq!(
+            queue.flush(&uploader, &options(1)).unwrap(),
+            FlushReport::default()
+        );
+    }
+
+    #[test]
+    fn keep_queue_when_upload_fails() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().to_string_lossy().into_owned();
+        let queue = UploadQueue::new(dir.path().join("spool"));
+        queue.enqueue(std::slice::from_ref(&path)).unwrap();
+        let uploader = FakeUploader::default();
+        uploader.failures.set(3);
+        let report = queue.flush(&uploader, &options(2)).unwrap();
+        assert_eq!(report.failed.len(), 1);
+        assert!(report.given_up.is_empty());
+        assert_eq!(queue.files().unwrap().len(), 1);
+        let report = queue.flush(&uploader, &options(2)).unwrap();
+        assert_eq!(report.uploaded, vec![path]);
+        assert!(queue.files().unwrap().is_empty());
+    }
+
+    #[test]
+    fn remove_each_file_once_uploaded() {
+        let dir = tempfile::tempdir().unwrap();
+        let store_path = |name: &str| {
+            let path = dir.path().join(name);
+            fs::write(&path, "").unwrap();
+            path.to_string_lossy().into_owned()
+        };
+        let (good, bad) = (store_path("good"), store_path("bad"));
+        let queue = UploadQueue::new(dir.path().join("spool"));
+        queue.enqueue(std::slice::from_ref(&bad)).unwrap();
+        queue.enqueue(std::slice::from_ref(&good)).unwrap();
+        let uploader = FakeUploader {
+            unreachable: vec![bad.clone()],
+            ..Default::default()
+        };
+        let options = options(0).with_max_failures(2);
+
+        let report = queue.flush(&uploader, &options).unwrap();
+        assert_eq!(report.uploaded, vec![good]);
+        assert_eq!(report.failed.len(), 1);
+        assert_eq!(
+            queue.files().unwrap().len(),
+            1,
+            "only the failed file is left"
+        );
+
+        let report = queue.flush(&uploader, &options).unwrap();
+        assert_eq!(report.given_up.len(), 1);
+        assert!(queue.files().unwrap().is_empty());
+        assert_eq!(fs::read_to_string(&report.given_up[0]).unwrap(), bad + "\n");
+        assert!(report.given_up[0].starts_with(dir.path().join("spool/failed")));
+    }
+}